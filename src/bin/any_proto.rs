@@ -1,43 +1,57 @@
-use prost::Message;
 use prost_types::Any;
+use rust_hpc_communication_test::communicator::{
+    ChannelSimCommunicator, CommError, TestCommunicator,
+};
 use rust_hpc_communication_test::proto::events::{Event, LoginEvent, LogoutEvent};
+use std::thread;
 
-fn main() {
-    let event = create_login_event("test", 1);
-    handle_event(event);
-}
+fn main() -> Result<(), CommError> {
+    let mut comms = ChannelSimCommunicator::create_n_2_n(2);
+    let receiver = comms.pop().unwrap();
+    let sender = comms.pop().unwrap();
 
-fn create_login_event(user_id: &str, timestamp: i64) -> Event {
-    let login = LoginEvent {
-        user_id: user_id.to_string(),
-        timestamp,
-    };
+    let handle = thread::spawn(move || -> Result<(), CommError> {
+        let event: Event = receiver.recv_msg(0)?;
+        handle_event(event.payload.unwrap())?;
+        handle_event(receiver.recv_any(0)?)
+    });
 
-    let mut buf = Vec::new();
-    login.encode(&mut buf).unwrap();
+    sender.send_msg(&create_login_event("test", 1), 1)?;
+    sender.send_any(
+        &LogoutEvent {
+            user_id: "test".to_string(),
+            timestamp: 2,
+        },
+        1,
+    )?;
+    handle.join().expect("Failed to join thread.")
+}
 
+fn create_login_event(user_id: &str, timestamp: i64) -> Event {
     let result = Any::from_msg(&LoginEvent {
         user_id: user_id.to_string(),
         timestamp,
-    }).unwrap();
+    })
+    .unwrap();
 
     Event {
         payload: Some(result),
     }
 }
 
-fn handle_event(event: Event) {
-    match event.payload.as_ref().unwrap().type_url.as_str() {
+fn handle_event(payload: Any) -> Result<(), CommError> {
+    match payload.type_url.as_str() {
         "/events.LoginEvent" => {
-                let login = event.payload.unwrap().to_msg::<LoginEvent>().unwrap();
-                println!("Login: {} at {}", login.user_id, login.timestamp);
-            }
+            let login = payload.to_msg::<LoginEvent>()?;
+            println!("Login: {} at {}", login.user_id, login.timestamp);
+        }
         "/events.LogoutEvent" => {
-                let logout = event.payload.unwrap().to_msg::<LogoutEvent>().unwrap();
-        println!("Logout: {} at {}", logout.user_id, logout.timestamp);
-            }
+            let logout = payload.to_msg::<LogoutEvent>()?;
+            println!("Logout: {} at {}", logout.user_id, logout.timestamp);
+        }
         _ => {
-            println!("Unknown event type: {}", event.payload.as_ref().unwrap().type_url);
+            println!("Unknown event type: {}", payload.type_url);
         }
     }
-}
\ No newline at end of file
+    Ok(())
+}
@@ -0,0 +1,137 @@
+use clap::{Parser, ValueEnum};
+use mpi::Threading;
+use rust_hpc_communication_test::communicator::{
+    AddressArguments, AddressBook, CommError, CommProxy, MpiCommunicator, ProxyHandle,
+    TestCommunicator, TokioCommunicator,
+};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    /// MPI with `MPI_THREAD_FUNNELED`, driven by the main thread. Run with `mpirun -n 2`.
+    Mpi,
+    /// A `TokioCommunicator` on its own progress thread. Start one process per `--rank`.
+    Tokio,
+}
+
+#[derive(Parser, Debug, Clone)]
+struct Arguments {
+    #[arg(long, value_enum, default_value_t = Backend::Mpi)]
+    backend: Backend,
+    /// Rank of this process with the tokio backend.
+    #[arg(long, default_value_t = 0)]
+    rank: u32,
+    /// Worker threads per rank, each exchanging messages with the other rank.
+    #[arg(short, long, default_value_t = 3)]
+    workers: u32,
+    #[arg(short, long, default_value_t = 1_000)]
+    iterations: u32,
+    #[arg(short, long, default_value_t = 1024)]
+    message_len: usize,
+    /// Busy work of a worker between two tests of its exchange.
+    #[arg(long, default_value_t = 10)]
+    compute_micros: u64,
+    #[command(flatten)]
+    addresses: AddressArguments,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Arguments::parse();
+    match args.backend {
+        Backend::Mpi => {
+            let (universe, threading) =
+                mpi::initialize_with_threading(Threading::Funneled).unwrap();
+            if threading < Threading::Funneled {
+                return Err("MPI does not support MPI_THREAD_FUNNELED".into());
+            }
+            let comm = MpiCommunicator::create(universe.world());
+            let (proxy, progress) = CommProxy::new(&comm);
+            let workers = spawn_workers(proxy, args);
+            progress.run(&comm)?;
+            workers.join().expect("Failed to join thread.")?;
+        }
+        Backend::Tokio => {
+            let addresses = AddressBook::from_arguments(&args.addresses, 2)?;
+            let rank = args.rank;
+            let proxy = CommProxy::spawn(move || {
+                TokioCommunicator::create_with_addresses(&addresses, rank)
+            })?;
+            spawn_workers(proxy, args)
+                .join()
+                .expect("Failed to join thread.")?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs the workers and shuts the proxy down once they are done, on a thread of its own, as the
+/// main thread may be the progress thread.
+fn spawn_workers<C: TestCommunicator + 'static>(
+    proxy: CommProxy<C>,
+    args: Arguments,
+) -> JoinHandle<Result<(), CommError>> {
+    thread::spawn(move || {
+        let handle = proxy.handle();
+        handle.barrier()?;
+        let start = Instant::now();
+        let workers: Vec<JoinHandle<Result<u64, CommError>>> = (0..args.workers)
+            .map(|worker| {
+                let handle = proxy.handle();
+                let args = args.clone();
+                thread::Builder::new()
+                    .name(worker.to_string())
+                    .spawn(move || exchange(handle, worker, &args))
+                    .expect("Failed to spawn thread.")
+            })
+            .collect();
+        for (worker, thread) in workers.into_iter().enumerate() {
+            let tests = thread.join().expect("Failed to join thread.")?;
+            println!("Worker {} tested its exchanges {} times", worker, tests);
+        }
+        handle.barrier()?;
+        println!("Elapsed time: {:?}", start.elapsed());
+        proxy.shutdown()
+    })
+}
+
+/// Exchanges a message with the other rank in every iteration and works until it has arrived.
+/// The messages of all workers share the communicator, so a worker may get the message of
+/// another worker of the other rank.
+fn exchange<C: TestCommunicator + 'static>(
+    handle: ProxyHandle<C>,
+    worker: u32,
+    args: &Arguments,
+) -> Result<u64, CommError> {
+    if handle.size() != 2 {
+        return Err(CommError::Unsupported(
+            "exchanges on a communicator without exactly 2 ranks",
+        ));
+    }
+    let other = 1 - handle.rank();
+    let message = vec![worker as u8; args.message_len];
+    let compute = Duration::from_micros(args.compute_micros);
+
+    let mut tests = 0;
+    for _ in 0..args.iterations {
+        let mut recv = handle.irecv(vec![0; message.len()], other)?;
+        let send = handle.isend(message.clone(), other)?;
+        loop {
+            tests += 1;
+            if recv.test()? {
+                break;
+            }
+            busy_wait(compute);
+        }
+        send.wait()?;
+        recv.wait()?;
+    }
+    Ok(tests)
+}
+
+fn busy_wait(duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        std::hint::spin_loop();
+    }
+}
@@ -0,0 +1,30 @@
+use clap::Parser;
+use rust_hpc_communication_test::communicator::Coordinator;
+
+#[derive(Parser, Debug)]
+struct Arguments {
+    /// Address the ranks register at.
+    #[arg(long, default_value = "0.0.0.0:7000")]
+    listen: String,
+    /// Number of ranks per job.
+    #[arg(short, long)]
+    world_size: u32,
+    /// Keep serving further jobs instead of exiting after the first one.
+    #[arg(long)]
+    repeat: bool,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Arguments::parse();
+    let coordinator = Coordinator::bind(&args.listen)?;
+    println!("Coordinator listening on {}", coordinator.local_addr()?);
+    loop {
+        let addresses = coordinator.serve(args.world_size)?;
+        for (rank, address) in addresses.addresses().iter().enumerate() {
+            println!("Rank {}: {}", rank, address);
+        }
+        if !args.repeat {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use clap::Parser;
+use rust_hpc_communication_test::communicator::{
+    ChannelSimCommunicator, CommError, CommMatrix, InstrumentedCommunicator, NetworkArguments,
+};
+use rust_hpc_communication_test::test_execution::{BasicArguments, TestExecution};
+use std::fs::File;
+use std::io::stdout;
+use std::thread;
+use std::thread::JoinHandle;
+use tracing::info;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer};
+
+#[derive(Parser, Debug)]
+struct Arguments {
+    #[command(flatten)]
+    basic: BasicArguments,
+    #[command(flatten)]
+    network: NetworkArguments,
+    /// Where to write the communication matrix as CSV. Printed to stdout if not given.
+    #[arg(long)]
+    matrix_file: Option<String>,
+}
+
+fn main() -> Result<(), CommError> {
+    let Arguments {
+        basic: args,
+        network,
+        matrix_file,
+    } = Arguments::parse();
+
+    // the spans of the communicator are at debug level, e.g. RUST_LOG=debug logs every operation
+    let console_layer = fmt::layer()
+        .with_writer(stdout)
+        .with_span_events(fmt::format::FmtSpan::CLOSE)
+        .with_filter(EnvFilter::from_default_env());
+    let subscriber = tracing_subscriber::registry().with(console_layer);
+    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+
+    let comms = ChannelSimCommunicator::create_with_network_model(2, &network.model(2))?;
+
+    let handles: Vec<JoinHandle<Result<Option<CommMatrix>, CommError>>> = comms
+        .into_iter()
+        .enumerate()
+        .map(|(i, comm)| {
+            thread::Builder::new()
+                .name(i.to_string())
+                .spawn({
+                    let a = args.clone();
+                    move || {
+                        let execution = TestExecution::new(InstrumentedCommunicator::new(comm), a);
+                        if i == 0 {
+                            execution.run_client()?;
+                        } else {
+                            execution.run_server()?;
+                        }
+                        let comm = execution.communicator();
+                        let stats = comm.stats();
+                        info!(
+                            rank = stats.rank,
+                            send_time = ?stats.send_time,
+                            recv_time = ?stats.recv_time,
+                            barrier_time = ?stats.barrier_time,
+                            "finished"
+                        );
+                        comm.gather_matrix(0)
+                    }
+                })
+                .expect("Failed to spawn thread.")
+        })
+        .collect();
+
+    for handle in handles {
+        if let Some(matrix) = handle.join().expect("Failed to join thread.")? {
+            let written = match &matrix_file {
+                Some(path) => File::create(path)
+                    .map_err(csv::Error::from)
+                    .and_then(|file| matrix.write_csv(file)),
+                None => matrix.write_csv(stdout()),
+            };
+            written.map_err(|e| CommError::Io(e.into()))?;
+        }
+    }
+    Ok(())
+}
@@ -1,20 +1,141 @@
-use clap::Parser;
-use rust_hpc_communication_test::communicator::{MpiCommunicator, TestCommunicator};
+use clap::{Parser, ValueEnum};
+use mpi::Threading;
+use rust_hpc_communication_test::communicator::{CommError, MpiCommunicator, TestCommunicator};
 use rust_hpc_communication_test::test_execution::{BasicArguments, TestExecution};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::thread::{self, JoinHandle};
 
-fn main() {
-    let args = BasicArguments::parse();
-    let universe = mpi::initialize().unwrap();
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Pairing {
+    /// Ranks 0 and 1 of the world run the benchmark.
+    #[default]
+    World,
+    /// Ranks 0 and 1, 2 and 3, ... run the benchmark at the same time.
+    Neighbours,
+    /// The ranks with the same index on their node run the benchmark together, i.e. the first
+    /// ranks of two nodes, the second ranks of the two nodes, ... at the same time.
+    CrossNode,
+}
+
+/// Thread support requested from MPI.
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum ThreadLevel {
+    #[default]
+    Single,
+    Funneled,
+    Serialized,
+    Multiple,
+}
+
+impl ThreadLevel {
+    fn to_mpi(self) -> Threading {
+        match self {
+            ThreadLevel::Single => Threading::Single,
+            ThreadLevel::Funneled => Threading::Funneled,
+            ThreadLevel::Serialized => Threading::Serialized,
+            ThreadLevel::Multiple => Threading::Multiple,
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+struct Arguments {
+    #[command(flatten)]
+    basic: BasicArguments,
+    #[arg(long, value_enum, default_value_t = Pairing::World)]
+    pairing: Pairing,
+    #[arg(long, value_enum, default_value_t = ThreadLevel::Single)]
+    threading: ThreadLevel,
+    /// Threads per rank, each running the benchmark on its own duplicate of the communicator
+    /// at the same time. More than one needs `--threading multiple`.
+    #[arg(long, default_value_t = 1)]
+    threads: u32,
+}
+
+/// Same for all ranks on a node. MPI colors must not be negative.
+fn node_color() -> Result<u32, Box<dyn std::error::Error>> {
+    let mut hasher = DefaultHasher::new();
+    mpi::environment::processor_name()?.hash(&mut hasher);
+    Ok(hasher.finish() as u32 & i32::MAX as u32)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let Arguments {
+        basic: mut args,
+        pairing,
+        threading,
+        threads,
+    } = Arguments::parse();
+    let (universe, granted) = mpi::initialize_with_threading(threading.to_mpi()).unwrap();
     let comm = universe.world();
 
-    let communicator = MpiCommunicator::create(comm);
+    let world = MpiCommunicator::create(comm);
+    if world.rank() == 0 {
+        println!(
+            "Requested thread support {:?}, MPI granted {:?}",
+            threading.to_mpi(),
+            granted
+        );
+    }
+    let color = match pairing {
+        Pairing::World => 0,
+        Pairing::Neighbours => world.rank() / 2,
+        Pairing::CrossNode => {
+            // ranks of the same node, numbered from 0 on every node
+            let node = world.split(Some(node_color()?), world.rank())?;
+            node.expect("every rank has a color").rank()
+        }
+    };
+    let communicator = world
+        .split(Some(color), world.rank())?
+        .expect("every rank has a color");
+    if pairing != Pairing::World {
+        args.reporting_file =
+            (args.reporting_file.take()).map(|file| format!("{}.{}", file, color));
+    }
+    let has_partner = pairing == Pairing::World || communicator.size() == 2;
+    let mut duplicates = Vec::new();
+    if has_partner && threads > 1 {
+        for _ in 0..threads {
+            duplicates.push(communicator.duplicate().into_movable()?);
+        }
+    }
+
+    // all pairs start at the same time
+    world.barrier()?;
+    if !has_partner {
+        println!("Rank {} has no partner", world.rank());
+        return Ok(());
+    }
+    if threads == 1 {
+        return Ok(run(communicator, args)?);
+    }
+    let handles: Vec<JoinHandle<Result<(), CommError>>> = duplicates
+        .into_iter()
+        .enumerate()
+        .map(|(i, duplicate)| {
+            let mut args = args.clone();
+            args.reporting_file =
+                (args.reporting_file.take()).map(|file| format!("{}.t{}", file, i));
+            thread::Builder::new()
+                .name(i.to_string())
+                .spawn(move || run(duplicate.into_inner(), args))
+                .expect("Failed to spawn thread.")
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("Failed to join thread.")?;
+    }
+    Ok(())
+}
+
+fn run(communicator: MpiCommunicator, args: BasicArguments) -> Result<(), CommError> {
     let rank = communicator.rank();
     let test_execution = TestExecution::new(communicator, args);
-
-    test_execution.barrier();
     if rank == 0 {
-        test_execution.ping_pong_client();
+        test_execution.run_client()
     } else {
-        test_execution.ping_pong_server();
+        test_execution.run_server()
     }
 }
@@ -0,0 +1,28 @@
+use clap::Parser;
+use rust_hpc_communication_test::communicator::{MpiCommunicator, TestCommunicator};
+use rust_hpc_communication_test::test_execution::{BasicArguments, RmaSync, TestExecution};
+
+#[derive(Parser, Debug)]
+struct Arguments {
+    #[command(flatten)]
+    basic: BasicArguments,
+    #[arg(long, value_enum, default_value_t = RmaSync::Fence)]
+    sync: RmaSync,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let Arguments { basic: args, sync } = Arguments::parse();
+    let universe = mpi::initialize().unwrap();
+    let comm = universe.world();
+
+    let communicator = MpiCommunicator::create(comm);
+    let rank = communicator.rank();
+    let test_execution = TestExecution::new(communicator, args);
+    test_execution.barrier()?;
+    if rank == 0 {
+        test_execution.run_rma_client(sync)?;
+    } else {
+        test_execution.run_rma_server(sync)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,21 @@
+use clap::Parser;
+use rust_hpc_communication_test::communicator::{ShmArguments, ShmCommunicator};
+use rust_hpc_communication_test::test_execution::{BasicArguments, TestExecution};
+
+#[derive(Parser, Debug)]
+struct Arguments {
+    #[command(flatten)]
+    basic: BasicArguments,
+    #[command(flatten)]
+    shm: ShmArguments,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Arguments::parse();
+    let communicator = ShmCommunicator::create_n_2_n_for_job(&args.shm.job_id, 2, 0)?
+        .with_max_message_len(args.shm.max_message_len);
+    let test_execution = TestExecution::new(communicator, args.basic);
+    test_execution.barrier()?;
+    test_execution.run_client()?;
+    Ok(())
+}
@@ -1,10 +1,28 @@
 use clap::Parser;
-use rust_hpc_communication_test::communicator::StdCommunicator;
+use rust_hpc_communication_test::communicator::{
+    AddressArguments, AddressBook, DatagramOptions, StdCommunicator,
+};
 use rust_hpc_communication_test::test_execution::{BasicArguments, TestExecution};
 
-fn main() {
-    let args = BasicArguments::parse();
-    let communicator = StdCommunicator::create_n_2_n(2, 0);
-    let test_execution = TestExecution::new(communicator, args);
-    test_execution.ping_pong_client();
+#[derive(Parser, Debug)]
+struct Arguments {
+    #[command(flatten)]
+    basic: BasicArguments,
+    #[command(flatten)]
+    datagram: DatagramOptions,
+    #[command(flatten)]
+    addresses: AddressArguments,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Arguments::parse();
+    let addresses = AddressBook::from_arguments(&args.addresses, 2)?;
+    let communicator = StdCommunicator::create_with_addresses(&addresses, 0)?
+        .with_max_datagram_len(args.datagram.max_datagram_len)
+        .with_max_message_len(args.datagram.max_message_len)
+        .with_reliable_delivery(args.datagram.reliable);
+    let test_execution = TestExecution::new(communicator, args.basic);
+    test_execution.barrier()?;
+    test_execution.run_client()?;
+    Ok(())
 }
@@ -0,0 +1,25 @@
+use clap::Parser;
+use rust_hpc_communication_test::communicator::{
+    AddressArguments, AddressBook, TcpCommunicator, TcpOptions,
+};
+use rust_hpc_communication_test::test_execution::{BasicArguments, TestExecution};
+
+#[derive(Parser, Debug)]
+struct Arguments {
+    #[command(flatten)]
+    basic: BasicArguments,
+    #[command(flatten)]
+    tcp: TcpOptions,
+    #[command(flatten)]
+    addresses: AddressArguments,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Arguments::parse();
+    let addresses = AddressBook::from_arguments(&args.addresses, 2)?;
+    let communicator = TcpCommunicator::create_with_addresses(&addresses, 1, &args.tcp)?;
+    let test_execution = TestExecution::new(communicator, args.basic);
+    test_execution.barrier()?;
+    test_execution.run_server()?;
+    Ok(())
+}
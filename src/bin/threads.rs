@@ -1,15 +1,28 @@
 use clap::Parser;
-use rust_hpc_communication_test::communicator::ChannelSimCommunicator;
+use rust_hpc_communication_test::communicator::{
+    ChannelSimCommunicator, CommError, NetworkArguments,
+};
 use rust_hpc_communication_test::test_execution::{BasicArguments, TestExecution};
 use std::thread;
 use std::thread::JoinHandle;
 
-fn main() {
-    let args = BasicArguments::parse();
+#[derive(Parser, Debug)]
+struct Arguments {
+    #[command(flatten)]
+    basic: BasicArguments,
+    #[command(flatten)]
+    network: NetworkArguments,
+}
+
+fn main() -> Result<(), CommError> {
+    let Arguments {
+        basic: args,
+        network,
+    } = Arguments::parse();
 
-    let comms = ChannelSimCommunicator::create_n_2_n(2);
+    let comms = ChannelSimCommunicator::create_with_network_model(2, &network.model(2))?;
 
-    let handles: Vec<JoinHandle<()>> = comms
+    let handles: Vec<JoinHandle<Result<(), CommError>>> = comms
         .into_iter()
         .enumerate()
         .map(|(i, comm)| {
@@ -18,9 +31,7 @@ fn main() {
                     .name(i.to_string())
                     .spawn({
                         let a = args.clone();
-                        move || {
-                            TestExecution::new(comm, a).ping_pong_client();
-                        }
+                        move || TestExecution::new(comm, a).run_client()
                     })
                     .expect("Failed to spawn thread.")
             } else {
@@ -28,9 +39,7 @@ fn main() {
                     .name(i.to_string())
                     .spawn({
                         let a = args.clone();
-                        move || {
-                            TestExecution::new(comm, a).ping_pong_server();
-                        }
+                        move || TestExecution::new(comm, a).run_server()
                     })
                     .expect("Failed to spawn thread.")
             }
@@ -38,6 +47,7 @@ fn main() {
         .collect();
 
     for handle in handles {
-        handle.join().expect("Failed to join thread.");
+        handle.join().expect("Failed to join thread.")?;
     }
+    Ok(())
 }
@@ -0,0 +1,30 @@
+use clap::Parser;
+use rust_hpc_communication_test::communicator::{
+    AddressArguments, AddressBook, DatagramOptions, TokioCommunicator,
+};
+use rust_hpc_communication_test::test_execution::{AsyncTestExecution, BasicArguments};
+
+#[derive(Parser, Debug)]
+struct Arguments {
+    #[command(flatten)]
+    basic: BasicArguments,
+    #[command(flatten)]
+    datagram: DatagramOptions,
+    #[command(flatten)]
+    addresses: AddressArguments,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Arguments::parse();
+    let addresses = AddressBook::from_arguments(&args.addresses, 2)?;
+    let communicator = TokioCommunicator::create_with_addresses_async(&addresses, 0)
+        .await?
+        .with_max_datagram_len(args.datagram.max_datagram_len)
+        .with_max_message_len(args.datagram.max_message_len)
+        .with_reliable_delivery(args.datagram.reliable);
+    let test_execution = AsyncTestExecution::new(communicator, args.basic);
+    test_execution.barrier().await?;
+    test_execution.run_client().await?;
+    Ok(())
+}
@@ -0,0 +1,40 @@
+use clap::Parser;
+use rust_hpc_communication_test::communicator::{
+    TestCommunicator, UdsArguments, UdsCommunicator, UdsFlavour, UdsStreamCommunicator,
+};
+use rust_hpc_communication_test::test_execution::{BasicArguments, TestExecution};
+
+#[derive(Parser, Debug)]
+struct Arguments {
+    #[command(flatten)]
+    basic: BasicArguments,
+    #[command(flatten)]
+    uds: UdsArguments,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Arguments::parse();
+    let dir = &args.uds.socket_dir;
+    match args.uds.flavour {
+        UdsFlavour::Datagram => run(
+            UdsCommunicator::create_n_2_n_in(dir, 2, 1)?
+                .with_max_message_len(args.uds.max_message_len),
+            args.basic,
+        ),
+        UdsFlavour::Stream => run(
+            UdsStreamCommunicator::create_n_2_n_in(dir, 2, 1)?
+                .with_max_message_len(args.uds.max_message_len),
+            args.basic,
+        ),
+    }
+}
+
+fn run(
+    communicator: impl TestCommunicator,
+    args: BasicArguments,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let test_execution = TestExecution::new(communicator, args);
+    test_execution.barrier()?;
+    test_execution.run_server()?;
+    Ok(())
+}
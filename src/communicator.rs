@@ -1,29 +1,294 @@
-use clap::Parser;
-use mpi::collective::CommunicatorCollectives;
-use mpi::point_to_point::{Destination, Source};
-use mpi::topology::{Communicator, SimpleCommunicator};
-use mpi::Rank;
-use std::net::{IpAddr, SocketAddr, UdpSocket};
-use std::sync::mpsc::{Receiver, Sender};
-use std::sync::{Arc, Barrier};
-use tokio::runtime::Runtime;
-
-#[derive(Parser, Debug, Clone, Default)]
-pub struct UdpArguments {
-    #[arg(short, long)]
-    pub server_address: String,
-}
+use mpi::collective::{CommunicatorCollectives, Root};
+use mpi::datatype::{Equivalence, Partition, PartitionMut};
+use mpi::point_to_point::{Destination, MatchedReceiveVec, Source};
+use mpi::raw::AsRaw;
+use mpi::request::{Request, StaticScope};
+use mpi::topology::{Color, Communicator, SimpleCommunicator};
+use mpi::{ffi, Count, Rank, Tag, Threading};
+use std::collections::HashMap;
+use std::ffi::{c_int, c_void};
+use std::future::Future;
+use std::mem::MaybeUninit;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Barrier, Mutex};
+use std::time::{Duration, Instant};
+
+mod address;
+mod barrier;
+mod collective;
+mod datagram;
+mod error;
+mod faulty;
+mod fragment;
+mod grpc;
+mod instrumented;
+mod matching;
+mod message;
+mod network;
+mod proxy;
+mod reliable;
+mod rendezvous;
+mod rma;
+mod runtime;
+mod shm;
+mod split;
+mod stream;
+mod sync_adapter;
+mod tcp;
+mod uds;
+
+pub use address::{AddressArguments, AddressBook};
+use barrier::{CentralBarrier, Control, ControlChannel, RELEASE_TAG};
+pub use collective::{ReduceOp, Reducible};
+pub use datagram::{DatagramCommunicator, DatagramOptions, DatagramSocket};
+pub use error::CommError;
+pub use faulty::{FaultConfig, FaultyCommunicator};
+use fragment::{Fragment, Reassembler, MIN_DATAGRAM_LEN};
+pub use grpc::GrpcCommunicator;
+pub use instrumented::{
+    CollectiveStats, CommMatrix, CommStats, InstrumentedCommunicator, OperationStats, Traffic,
+};
+pub use matching::Status;
+use matching::{
+    Header, Mailbox, RecvTimeout, Wait, DEFAULT_DATAGRAM_LEN, DEFAULT_TAG, MAX_DATAGRAM_LEN,
+};
+use network::{InFlight, InTransit, Network};
+pub use network::{Jitter, JitterKind, NetworkArguments, NetworkModel, Topology, TopologyKind};
+pub use proxy::{CommProxy, Posted, Progress, ProxyHandle};
+use reliable::{Outstanding, Reliability, ACK_TAG, LINGER};
+pub use rendezvous::Coordinator;
+pub use rma::{Epoch, RmaWindow};
+use runtime::CommRuntime;
+pub use shm::{ShmArguments, ShmCommunicator};
+pub use stream::{StreamCommunicator, StreamSocket};
+pub use sync_adapter::SyncAdapter;
+pub use tcp::{TcpCommunicator, TcpOptions, TokioTcpCommunicator};
+pub use uds::{UdsArguments, UdsCommunicator, UdsFlavour, UdsStreamCommunicator};
 
 pub trait TestCommunicator {
     fn rank(&self) -> u32;
     fn size(&self) -> u32;
-    fn send(&self, buffer: &[u8], dest: u32);
-    fn recv(&self, buffer: &mut [u8], source: u32);
-    fn barrier(&self);
+    fn send(&self, buffer: &[u8], dest: u32) -> Result<(), CommError>;
+    /// Receives the next message from `source` into the start of `buffer` and returns its
+    /// length, which may be shorter than the buffer.
+    fn recv(&self, buffer: &mut [u8], source: u32) -> Result<usize, CommError>;
+    fn barrier(&self) -> Result<(), CommError>;
+    /// Starts sending `buffer` to `dest` and returns immediately. The buffer is handed back by
+    /// [`CommRequest::wait`] once the send has completed.
+    fn isend(&self, buffer: Vec<u8>, dest: u32) -> Result<CommRequest<'_>, CommError>;
+    /// Starts receiving a message from `source` into `buffer` and returns immediately. The
+    /// filled buffer, truncated to the received length, is handed back by [`CommRequest::wait`].
+    fn irecv(&self, buffer: Vec<u8>, source: u32) -> Result<CommRequest<'_>, CommError>;
+    /// Like `send`, but with a tag the receiver can select the message by. The topmost tags
+    /// are reserved for control messages and the collective operations.
+    fn send_tagged(&self, buffer: &[u8], dest: u32, tag: u32) -> Result<(), CommError>;
+    /// Receives the next message from `source` carrying `tag`. Messages with other tags stay
+    /// queued for later receives. A plain `recv` accepts any tag, like `MPI_ANY_TAG`.
+    fn recv_tagged(&self, buffer: &mut [u8], source: u32, tag: u32) -> Result<usize, CommError>;
+    /// Waits for the next message from `source`, with any tag, and returns its envelope without
+    /// receiving it. The next `recv` or `recv_vec` from `source` gets that message.
+    fn probe(&self, source: u32) -> Result<Status, CommError>;
+    /// Receives the next message from `source` into a buffer of its length.
+    fn recv_vec(&self, source: u32) -> Result<Vec<u8>, CommError>;
+    /// Like `recv`, but fails with [`CommError::Timeout`] if no message from `source` arrived
+    /// within `timeout`. A message arriving later is left to the next receive.
+    fn recv_timeout(
+        &self,
+        buffer: &mut [u8],
+        source: u32,
+        timeout: Duration,
+    ) -> Result<usize, CommError>;
+    /// Sets a deadline for all blocking receives: `recv`, `recv_tagged`, `probe`, `recv_vec`
+    /// and the wait of an `irecv`. They fail with [`CommError::Timeout`] once it has passed
+    /// instead of waiting forever for a missing peer. `None`, the default, waits forever.
+    fn set_recv_timeout(&self, timeout: Option<Duration>) -> Result<(), CommError>;
+
+    /// Sends a protobuf message to `dest`, to be received with `recv_msg`.
+    fn send_msg<M: prost::Message>(&self, message: &M, dest: u32) -> Result<(), CommError> {
+        message::send_msg(self, message, dest)
+    }
+    /// Receives the next message from `source` as an `M`. Fails with [`CommError::Decode`] if
+    /// it is not one.
+    fn recv_msg<M: prost::Message + Default>(&self, source: u32) -> Result<M, CommError> {
+        message::recv_msg(self, source)
+    }
+    /// Sends a protobuf message to `dest` wrapped into an [`Any`](prost_types::Any), so that
+    /// the receiver can tell its type from the type URL.
+    fn send_any<M: prost::Name>(&self, message: &M, dest: u32) -> Result<(), CommError> {
+        message::send_any(self, message, dest)
+    }
+    /// Receives the next message from `source` sent with `send_any`. Its content is decoded
+    /// with [`Any::to_msg`](prost_types::Any::to_msg).
+    fn recv_any(&self, source: u32) -> Result<prost_types::Any, CommError> {
+        message::recv_msg(self, source)
+    }
+
+    // Collective operations have to be called by all ranks in the same order. The default
+    // implementations are built on `send_tagged` and `recv_tagged` with a reserved tag, so
+    // point-to-point messages in flight between the ranks do not interfere with them.
+
+    /// Copies `buffer` of `root` into `buffer` of all other ranks.
+    fn broadcast(&self, buffer: &mut [u8], root: u32) -> Result<(), CommError> {
+        collective::broadcast(self, buffer, root)
+    }
+    /// Combines `send` of all ranks element-wise with `op` into `recv` of `root`. `recv` is
+    /// only used on `root`.
+    fn reduce<T: Reducible>(
+        &self,
+        send: &[T],
+        recv: &mut [T],
+        op: ReduceOp,
+        root: u32,
+    ) -> Result<(), CommError> {
+        collective::reduce(self, send, recv, op, root)
+    }
+    /// Like `reduce`, with the result in `recv` of all ranks.
+    fn allreduce<T: Reducible>(
+        &self,
+        send: &[T],
+        recv: &mut [T],
+        op: ReduceOp,
+    ) -> Result<(), CommError> {
+        collective::allreduce(self, send, recv, op)
+    }
+    /// Concatenates `send` of all ranks in rank order into `recv` of `root`. `recv` is only
+    /// used on `root`.
+    fn gather(&self, send: &[u8], recv: &mut [u8], root: u32) -> Result<(), CommError> {
+        collective::gather(self, send, recv, root)
+    }
+    /// Like `gather`, with the result in `recv` of all ranks.
+    fn allgather(&self, send: &[u8], recv: &mut [u8]) -> Result<(), CommError> {
+        collective::allgather(self, send, recv)
+    }
+    /// Splits `send` of `root` into one block per rank and copies block `i` into `recv` of
+    /// rank `i`. `send` is only used on `root`.
+    fn scatter(&self, send: &[u8], recv: &mut [u8], root: u32) -> Result<(), CommError> {
+        collective::scatter(self, send, recv, root)
+    }
+    /// Splits `send` into one block per rank and copies block `i` into block `rank` of `recv` of
+    /// rank `i`.
+    fn alltoall(&self, send: &[u8], recv: &mut [u8]) -> Result<(), CommError> {
+        collective::alltoall(self, send, recv)
+    }
+    /// Like `alltoall`, with blocks of `send_counts` and `recv_counts` bytes laid out back to
+    /// back.
+    fn alltoallv(
+        &self,
+        send: &[u8],
+        send_counts: &[usize],
+        recv: &mut [u8],
+        recv_counts: &[usize],
+    ) -> Result<(), CommError> {
+        collective::alltoallv(self, send, send_counts, recv, recv_counts)
+    }
+    /// Splits the ranks into one new communicator per `color`, like `MPI_Comm_split`. The ranks
+    /// of a new communicator are numbered by `key`, ties broken by their rank in this one. Ranks
+    /// with a `color` of `None` get no communicator. The new communicators do not share any
+    /// messages with this one, which stays usable.
+    ///
+    /// Backends that cannot create new communicators fail with [`CommError::Unsupported`].
+    fn split(&self, color: Option<u32>, key: u32) -> Result<Option<Self>, CommError>
+    where
+        Self: Sized,
+    {
+        let _ = (color, key);
+        Err(CommError::Unsupported("splitting this communicator"))
+    }
+}
+
+/// Async counterpart of [`TestCommunicator`] for use inside a tokio runtime. The operations only
+/// borrow the communicator, so several of them can run concurrently, e.g. with `tokio::join!`.
+///
+/// The tokio based communicators implement it natively if created with their `_async`
+/// constructors. Blocking communicators get it through a [`SyncAdapter`].
+pub trait AsyncTestCommunicator {
+    fn rank(&self) -> u32;
+    fn size(&self) -> u32;
+    fn send(&self, buffer: &[u8], dest: u32) -> impl Future<Output = Result<(), CommError>> + Send;
+    fn recv(
+        &self,
+        buffer: &mut [u8],
+        source: u32,
+    ) -> impl Future<Output = Result<usize, CommError>> + Send;
+    fn barrier(&self) -> impl Future<Output = Result<(), CommError>> + Send;
+    /// See [`TestCommunicator::send_tagged`].
+    fn send_tagged(
+        &self,
+        buffer: &[u8],
+        dest: u32,
+        tag: u32,
+    ) -> impl Future<Output = Result<(), CommError>> + Send;
+    /// See [`TestCommunicator::recv_tagged`].
+    fn recv_tagged(
+        &self,
+        buffer: &mut [u8],
+        source: u32,
+        tag: u32,
+    ) -> impl Future<Output = Result<usize, CommError>> + Send;
+}
+
+/// Backend specific state of a non-blocking operation.
+pub trait PendingRequest {
+    /// Tries to make progress without blocking. Returns true once the operation has completed.
+    fn test(&mut self) -> Result<bool, CommError>;
+    /// Blocks until the operation has completed and returns its buffer.
+    fn wait(self: Box<Self>) -> Result<Vec<u8>, CommError>;
+}
+
+/// Handle of a non-blocking operation started with [`TestCommunicator::isend`] or
+/// [`TestCommunicator::irecv`].
+pub struct CommRequest<'a> {
+    inner: Box<dyn PendingRequest + 'a>,
+}
+
+impl<'a> CommRequest<'a> {
+    pub fn new(pending: impl PendingRequest + 'a) -> Self {
+        CommRequest {
+            inner: Box::new(pending),
+        }
+    }
+
+    /// A request that has already completed, e.g. an eager send on a datagram socket.
+    pub fn completed(buffer: Vec<u8>) -> Self {
+        Self::new(CompletedRequest { buffer })
+    }
+
+    pub fn test(&mut self) -> Result<bool, CommError> {
+        self.inner.test()
+    }
+
+    pub fn wait(self) -> Result<Vec<u8>, CommError> {
+        self.inner.wait()
+    }
+
+    /// Waits for all requests and returns their buffers in the order of `requests`. Stops at the
+    /// first failed request; the remaining ones are completed or cancelled as they are dropped.
+    pub fn wait_all(requests: Vec<CommRequest<'a>>) -> Result<Vec<Vec<u8>>, CommError> {
+        requests.into_iter().map(|r| r.wait()).collect()
+    }
+}
+
+struct CompletedRequest {
+    buffer: Vec<u8>,
+}
+
+impl PendingRequest for CompletedRequest {
+    fn test(&mut self) -> Result<bool, CommError> {
+        Ok(true)
+    }
+
+    fn wait(self: Box<Self>) -> Result<Vec<u8>, CommError> {
+        Ok(self.buffer)
+    }
 }
 
 pub struct MpiCommunicator {
     comm: SimpleCommunicator,
+    recv_timeout: RecvTimeout,
+    /// Largest tag the MPI library supports, `MPI_TAG_UB`.
+    tag_ub: u32,
 }
 
 impl TestCommunicator for MpiCommunicator {
@@ -35,24 +300,442 @@ impl TestCommunicator for MpiCommunicator {
         self.comm.size() as u32
     }
 
-    fn send(&self, buffer: &[u8], dest: u32) {
+    // MPI aborts on errors with its default error handler, so these never return an error.
+
+    fn send(&self, buffer: &[u8], dest: u32) -> Result<(), CommError> {
         self.comm.process_at_rank(dest as Rank).send(buffer);
+        Ok(())
     }
 
-    fn recv(&self, buffer: &mut [u8], source: u32) {
-        self.comm
+    fn recv(&self, buffer: &mut [u8], source: u32) -> Result<usize, CommError> {
+        if let Some(timeout) = self.recv_timeout.get() {
+            return self.recv_timeout(buffer, source, timeout);
+        }
+        let status = self
+            .comm
             .process_at_rank(source as Rank)
             .receive_into(buffer);
+        Ok(received_len(&status))
     }
 
-    fn barrier(&self) {
+    fn barrier(&self) -> Result<(), CommError> {
         self.comm.barrier();
+        Ok(())
+    }
+
+    fn send_tagged(&self, buffer: &[u8], dest: u32, tag: u32) -> Result<(), CommError> {
+        let tag = self.to_mpi_tag(tag)?;
+        self.comm
+            .process_at_rank(dest as Rank)
+            .send_with_tag(buffer, tag);
+        Ok(())
+    }
+
+    fn recv_tagged(&self, buffer: &mut [u8], source: u32, tag: u32) -> Result<usize, CommError> {
+        let tag = self.to_mpi_tag(tag)?;
+        let process = self.comm.process_at_rank(source as Rank);
+        let status = match self.recv_timeout.get() {
+            Some(timeout) => {
+                let (message, _) =
+                    test_until(timeout, || process.immediate_matched_probe_with_tag(tag))?;
+                message.matched_receive_into(buffer)
+            }
+            None => process.receive_into_with_tag(buffer, tag),
+        };
+        Ok(received_len(&status))
+    }
+
+    fn probe(&self, source: u32) -> Result<Status, CommError> {
+        let process = self.comm.process_at_rank(source as Rank);
+        let status = match self.recv_timeout.get() {
+            Some(timeout) => test_until(timeout, || process.immediate_probe())?,
+            None => process.probe(),
+        };
+        Ok(Status {
+            source: status.source_rank() as u32,
+            tag: status.tag() as u32,
+            len: received_len(&status),
+        })
+    }
+
+    fn recv_vec(&self, source: u32) -> Result<Vec<u8>, CommError> {
+        // a matched probe takes the message off the queue, so no other thread can receive it
+        // between the probe and the receive
+        let process = self.comm.process_at_rank(source as Rank);
+        let probed = match self.recv_timeout.get() {
+            Some(timeout) => test_until(timeout, || process.immediate_matched_probe())?,
+            None => process.matched_probe(),
+        };
+        let (payload, _) = probed.matched_receive_vec();
+        Ok(payload)
+    }
+
+    fn recv_timeout(
+        &self,
+        buffer: &mut [u8],
+        source: u32,
+        timeout: Duration,
+    ) -> Result<usize, CommError> {
+        let process = self.comm.process_at_rank(source as Rank);
+        let (message, _) = test_until(timeout, || process.immediate_matched_probe())?;
+        Ok(received_len(&message.matched_receive_into(buffer)))
+    }
+
+    fn set_recv_timeout(&self, timeout: Option<Duration>) -> Result<(), CommError> {
+        self.recv_timeout.set(timeout);
+        Ok(())
+    }
+
+    fn isend(&self, buffer: Vec<u8>, dest: u32) -> Result<CommRequest<'_>, CommError> {
+        let buffer = Box::into_raw(buffer.into_boxed_slice());
+        // SAFETY: the allocation is only released by `MpiRequest::finish` after the request has
+        // completed, so it outlives the operation even though MPI sees it as 'static.
+        let request = self
+            .comm
+            .process_at_rank(dest as Rank)
+            .immediate_send(StaticScope, unsafe { &*buffer });
+        Ok(CommRequest::new(MpiRequest {
+            request: Some(request),
+            buffer: Some(buffer),
+            is_receive: false,
+            received: 0,
+            timeout: None,
+        }))
+    }
+
+    fn irecv(&self, buffer: Vec<u8>, source: u32) -> Result<CommRequest<'_>, CommError> {
+        let buffer = Box::into_raw(buffer.into_boxed_slice());
+        // SAFETY: see `isend`.
+        let request = self
+            .comm
+            .process_at_rank(source as Rank)
+            .immediate_receive_into(StaticScope, unsafe { &mut *buffer });
+        Ok(CommRequest::new(MpiRequest {
+            request: Some(request),
+            buffer: Some(buffer),
+            is_receive: true,
+            received: 0,
+            timeout: self.recv_timeout.get(),
+        }))
+    }
+
+    fn broadcast(&self, buffer: &mut [u8], root: u32) -> Result<(), CommError> {
+        collective::check_root(root, self.size())?;
+        self.comm
+            .process_at_rank(root as Rank)
+            .broadcast_into(buffer);
+        Ok(())
+    }
+
+    fn reduce<T: Reducible>(
+        &self,
+        send: &[T],
+        recv: &mut [T],
+        op: ReduceOp,
+        root: u32,
+    ) -> Result<(), CommError> {
+        collective::check_root(root, self.size())?;
+        let root_process = self.comm.process_at_rank(root as Rank);
+        if self.rank() == root {
+            collective::check_len("reduce receive buffer", recv.len(), send.len())?;
+            root_process.reduce_into_root(send, recv, op.to_mpi());
+        } else {
+            root_process.reduce_into(send, op.to_mpi());
+        }
+        Ok(())
+    }
+
+    fn allreduce<T: Reducible>(
+        &self,
+        send: &[T],
+        recv: &mut [T],
+        op: ReduceOp,
+    ) -> Result<(), CommError> {
+        collective::check_len("allreduce receive buffer", recv.len(), send.len())?;
+        self.comm.all_reduce_into(send, recv, op.to_mpi());
+        Ok(())
+    }
+
+    fn gather(&self, send: &[u8], recv: &mut [u8], root: u32) -> Result<(), CommError> {
+        collective::check_root(root, self.size())?;
+        let root_process = self.comm.process_at_rank(root as Rank);
+        if self.rank() == root {
+            let expected = send.len() * self.size() as usize;
+            collective::check_len("gather receive buffer", recv.len(), expected)?;
+            root_process.gather_into_root(send, recv);
+        } else {
+            root_process.gather_into(send);
+        }
+        Ok(())
+    }
+
+    fn allgather(&self, send: &[u8], recv: &mut [u8]) -> Result<(), CommError> {
+        let expected = send.len() * self.size() as usize;
+        collective::check_len("allgather receive buffer", recv.len(), expected)?;
+        self.comm.all_gather_into(send, recv);
+        Ok(())
+    }
+
+    fn scatter(&self, send: &[u8], recv: &mut [u8], root: u32) -> Result<(), CommError> {
+        collective::check_root(root, self.size())?;
+        let root_process = self.comm.process_at_rank(root as Rank);
+        if self.rank() == root {
+            let expected = recv.len() * self.size() as usize;
+            collective::check_len("scatter send buffer", send.len(), expected)?;
+            root_process.scatter_into_root(send, recv);
+        } else {
+            root_process.scatter_into(recv);
+        }
+        Ok(())
+    }
+
+    fn alltoall(&self, send: &[u8], recv: &mut [u8]) -> Result<(), CommError> {
+        collective::check_blocks("alltoall send buffer", send.len(), self.size())?;
+        collective::check_len("alltoall receive buffer", recv.len(), send.len())?;
+        self.comm.all_to_all_into(send, recv);
+        Ok(())
+    }
+
+    fn alltoallv(
+        &self,
+        send: &[u8],
+        send_counts: &[usize],
+        recv: &mut [u8],
+        recv_counts: &[usize],
+    ) -> Result<(), CommError> {
+        let size = self.size();
+        collective::check_counts("alltoallv send buffer", send_counts, send.len(), size)?;
+        collective::check_counts("alltoallv receive buffer", recv_counts, recv.len(), size)?;
+        let to_mpi = |values: Vec<usize>| -> Vec<Count> {
+            values.into_iter().map(|value| value as Count).collect()
+        };
+        let send = Partition::new(
+            send,
+            to_mpi(send_counts.to_vec()),
+            to_mpi(collective::displacements(send_counts)),
+        );
+        let mut recv = PartitionMut::new(
+            recv,
+            to_mpi(recv_counts.to_vec()),
+            to_mpi(collective::displacements(recv_counts)),
+        );
+        self.comm.all_to_all_varcount_into(&send, &mut recv);
+        Ok(())
+    }
+
+    fn split(&self, color: Option<u32>, key: u32) -> Result<Option<Self>, CommError> {
+        let to_mpi = |what: &str, value: u32| {
+            i32::try_from(value).map_err(|_| {
+                CommError::Io(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("{} {} does not fit into an MPI {}", what, value, what),
+                ))
+            })
+        };
+        let color = match color {
+            Some(color) => Color::with_value(to_mpi("color", color)?),
+            None => Color::undefined(),
+        };
+        let comm = self
+            .comm
+            .split_by_color_with_key(color, to_mpi("key", key)?);
+        Ok(comm.map(MpiCommunicator::create))
     }
 }
 
 impl MpiCommunicator {
     pub fn create(comm: SimpleCommunicator) -> MpiCommunicator {
-        MpiCommunicator { comm }
+        let tag_ub = tag_upper_bound(&comm);
+        MpiCommunicator {
+            comm,
+            recv_timeout: RecvTimeout::default(),
+            tag_ub,
+        }
+    }
+
+    /// Rejects the reserved tags and those beyond the tags MPI supports.
+    fn to_mpi_tag(&self, tag: u32) -> Result<Tag, CommError> {
+        matching::check_tag(tag)?;
+        match Tag::try_from(tag) {
+            Ok(mpi_tag) if tag <= self.tag_ub => Ok(mpi_tag),
+            _ => Err(CommError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("tag {} exceeds MPI_TAG_UB of {}", tag, self.tag_ub),
+            ))),
+        }
+    }
+
+    /// A new communicator with the same ranks, like `MPI_Comm_dup`, whose messages do not mix
+    /// with the ones of this one. Has to be called by all ranks.
+    pub fn duplicate(&self) -> MpiCommunicator {
+        MpiCommunicator::create(self.comm.duplicate())
+    }
+
+    /// Creates a window of `len` bytes on every rank for one-sided communication, like
+    /// `MPI_Win_create`. Has to be called by all ranks with the same `len`.
+    pub fn create_window(&self, len: usize) -> Result<RmaWindow<'_>, CommError> {
+        RmaWindow::create(self, len)
+    }
+
+    /// Allows moving the communicator to another thread, which needs MPI initialized with
+    /// `MPI_THREAD_MULTIPLE`.
+    pub fn into_movable(self) -> Result<MovableMpiCommunicator, CommError> {
+        if mpi::environment::threading_support() != Threading::Multiple {
+            return Err(CommError::Unsupported(
+                "moving an MPI communicator to another thread without MPI_THREAD_MULTIPLE",
+            ));
+        }
+        Ok(MovableMpiCommunicator { comm: self })
+    }
+}
+
+/// An [`MpiCommunicator`] on its way to another thread, created by
+/// [`MpiCommunicator::into_movable`].
+pub struct MovableMpiCommunicator {
+    comm: MpiCommunicator,
+}
+
+// SAFETY: MPI may be called from any thread with MPI_THREAD_MULTIPLE, which `into_movable`
+// checks, and the communicator is only used by the thread it was moved to.
+unsafe impl Send for MovableMpiCommunicator {}
+
+impl MovableMpiCommunicator {
+    pub fn into_inner(self) -> MpiCommunicator {
+        self.comm
+    }
+}
+
+/// MPI immediate operation on a heap buffer that is leaked for the duration of the request.
+struct MpiRequest {
+    request: Option<Request<'static, [u8]>>,
+    buffer: Option<*mut [u8]>,
+    is_receive: bool,
+    received: usize,
+    /// The default deadline of a receive when it was started.
+    timeout: Option<Duration>,
+}
+
+impl MpiRequest {
+    fn complete(&mut self, status: mpi::point_to_point::Status) {
+        if self.is_receive {
+            self.received = received_len(&status);
+        }
+    }
+
+    /// Cancels the operation and waits for it. Returns false if it completed before the cancel
+    /// took effect, so that its result is valid.
+    fn cancel(&mut self) -> bool {
+        let Some(request) = self.request.take() else {
+            return false;
+        };
+        request.cancel();
+        // SAFETY: the raw request is waited for right away, which completes and frees it. The
+        // buffer is only released afterwards.
+        let (mut raw, _, _) = unsafe { request.into_raw() };
+        let mut status = MaybeUninit::uninit();
+        let mut cancelled: c_int = 0;
+        let status = unsafe {
+            ffi::MPI_Wait(&mut raw, status.as_mut_ptr());
+            ffi::MPI_Test_cancelled(status.as_ptr(), &mut cancelled);
+            status.assume_init()
+        };
+        if cancelled == 0 {
+            self.complete(mpi::point_to_point::Status::from_raw(status));
+        }
+        cancelled != 0
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        if let Some(request) = self.request.take() {
+            let status = request.wait();
+            self.complete(status);
+        }
+        let Some(buffer) = self.buffer.take() else {
+            return Vec::new();
+        };
+        // SAFETY: the request has completed, so MPI no longer accesses the buffer.
+        let mut buffer = unsafe { Box::from_raw(buffer) }.into_vec();
+        if self.is_receive {
+            buffer.truncate(self.received);
+        }
+        buffer
+    }
+}
+
+impl PendingRequest for MpiRequest {
+    fn test(&mut self) -> Result<bool, CommError> {
+        let Some(request) = self.request.take() else {
+            return Ok(true);
+        };
+        match request.test() {
+            Ok(status) => {
+                self.complete(status);
+                Ok(true)
+            }
+            Err(request) => {
+                self.request = Some(request);
+                Ok(false)
+            }
+        }
+    }
+
+    fn wait(mut self: Box<Self>) -> Result<Vec<u8>, CommError> {
+        if let Some(timeout) = self.timeout {
+            let expired =
+                test_until(timeout, || matches!(self.test(), Ok(true)).then_some(())).is_err();
+            // a receive that matched a message before the cancel took effect has its data
+            if expired && self.cancel() {
+                self.finish();
+                return Err(CommError::Timeout);
+            }
+        }
+        Ok(self.finish())
+    }
+}
+
+impl Drop for MpiRequest {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// `MPI_TAG_UB` of `comm`. The standard guarantees at least 32767.
+fn tag_upper_bound(comm: &SimpleCommunicator) -> u32 {
+    let mut value: *const c_int = std::ptr::null();
+    let mut found: c_int = 0;
+    // SAFETY: the value of the predefined attribute MPI_TAG_UB is a pointer to an int owned by
+    // MPI, which is only read if the attribute is set.
+    unsafe {
+        ffi::MPI_Comm_get_attr(
+            comm.as_raw(),
+            ffi::MPI_TAG_UB as c_int,
+            &mut value as *mut *const c_int as *mut c_void,
+            &mut found,
+        );
+        if found != 0 && !value.is_null() {
+            (*value).max(32767) as u32
+        } else {
+            32767
+        }
+    }
+}
+
+fn received_len(status: &mpi::point_to_point::Status) -> usize {
+    status.count(u8::equivalent_datatype()) as usize
+}
+
+/// Calls `test` until it returns something, or fails with [`CommError::Timeout`] once `timeout`
+/// has passed. MPI has no blocking operations with a timeout, so deadlines are kept this way.
+fn test_until<T>(timeout: Duration, mut test: impl FnMut() -> Option<T>) -> Result<T, CommError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(done) = test() {
+            return Ok(done);
+        }
+        if Instant::now() >= deadline {
+            return Err(CommError::Timeout);
+        }
+        std::thread::yield_now();
     }
 }
 
@@ -60,7 +743,15 @@ pub struct TokioCommunicator {
     rank: u32,
     socket: tokio::net::UdpSocket,
     receiver: Vec<SocketAddr>,
-    runtime: Runtime,
+    runtime: CommRuntime,
+    unexpected: Mailbox,
+    recv_timeout: RecvTimeout,
+    scratch: tokio::sync::Mutex<Vec<u8>>,
+    barrier: CentralBarrier,
+    max_datagram_len: usize,
+    next_message: AtomicU32,
+    reassembler: Reassembler,
+    reliability: Option<Reliability>,
 }
 
 impl TestCommunicator for TokioCommunicator {
@@ -72,120 +763,499 @@ impl TestCommunicator for TokioCommunicator {
         self.receiver.len() as u32
     }
 
-    fn send(&self, buffer: &[u8], dest: u32) {
-        self.runtime.block_on(self.send(buffer, dest));
+    fn send(&self, buffer: &[u8], dest: u32) -> Result<(), CommError> {
+        self.runtime.block_on(self.send(buffer, dest, DEFAULT_TAG))
+    }
+
+    fn recv(&self, buffer: &mut [u8], source: u32) -> Result<usize, CommError> {
+        self.runtime.block_on(self.recv(buffer, source, None))
     }
 
-    fn recv(&self, buffer: &mut [u8], _source: u32) {
-        self.runtime.block_on(self.recv(buffer, _source));
+    fn barrier(&self) -> Result<(), CommError> {
+        self.runtime.block_on(
+            self.barrier
+                .wait(self, self.rank, TestCommunicator::size(self)),
+        )
     }
 
-    fn barrier(&self) {
-        unimplemented!()
+    fn isend(&self, buffer: Vec<u8>, dest: u32) -> Result<CommRequest<'_>, CommError> {
+        // datagrams are handed to the kernel right away, so the send completes eagerly
+        TestCommunicator::send(self, &buffer, dest)?;
+        Ok(CommRequest::completed(buffer))
+    }
+
+    fn irecv(&self, buffer: Vec<u8>, source: u32) -> Result<CommRequest<'_>, CommError> {
+        Ok(CommRequest::new(TokioRecvRequest {
+            comm: self,
+            buffer,
+            source,
+            payload: None,
+        }))
+    }
+
+    fn send_tagged(&self, buffer: &[u8], dest: u32, tag: u32) -> Result<(), CommError> {
+        matching::check_tag(tag)?;
+        self.runtime.block_on(self.send(buffer, dest, tag))
+    }
+
+    fn recv_tagged(&self, buffer: &mut [u8], source: u32, tag: u32) -> Result<usize, CommError> {
+        self.runtime.block_on(self.recv(buffer, source, Some(tag)))
+    }
+
+    fn probe(&self, source: u32) -> Result<Status, CommError> {
+        self.runtime.block_on(async {
+            let receive = self.receive_message(source, None);
+            let (header, payload) = runtime::with_timeout(self.recv_timeout.get(), receive).await?;
+            Ok(self.unexpected.put_back(header, payload))
+        })
+    }
+
+    fn recv_vec(&self, source: u32) -> Result<Vec<u8>, CommError> {
+        let receive = self.receive_matching(source, None);
+        (self.runtime).block_on(runtime::with_timeout(self.recv_timeout.get(), receive))
+    }
+
+    fn recv_timeout(
+        &self,
+        buffer: &mut [u8],
+        source: u32,
+        timeout: Duration,
+    ) -> Result<usize, CommError> {
+        self.runtime.block_on(async {
+            let receive = self.receive_matching(source, None);
+            let payload = runtime::with_timeout(Some(timeout), receive).await?;
+            matching::copy_payload(&payload, buffer)
+        })
+    }
+
+    fn set_recv_timeout(&self, timeout: Option<Duration>) -> Result<(), CommError> {
+        self.recv_timeout.set(timeout);
+        Ok(())
+    }
+
+    fn split(&self, color: Option<u32>, key: u32) -> Result<Option<Self>, CommError> {
+        let Some(color) = color else {
+            split::abstain(self)?;
+            return Ok(None);
+        };
+        // a socket next to the own one, which the others reach at the same host
+        let runtime = CommRuntime::current_thread()?;
+        let local = SocketAddr::new(self.socket.local_addr()?.ip(), 0);
+        let socket = runtime.block_on(async { Ok(tokio::net::UdpSocket::bind(local).await?) })?;
+        let address = SocketAddr::new(
+            self.receiver[self.rank as usize].ip(),
+            socket.local_addr()?.port(),
+        );
+        let group = split::join(self, color, key, &split::encode_socket_address(address))?;
+        let addresses = (group.addresses.iter())
+            .map(|address| split::decode_socket_address(address))
+            .collect::<Result<_, _>>()?;
+        let comm = Self::from_socket(runtime, socket, group.rank, &AddressBook::new(addresses))?;
+        Ok(Some(
+            comm.with_max_datagram_len(self.max_datagram_len)
+                .with_max_message_len(self.reassembler.max_message_len())
+                .with_reliable_delivery(self.reliability.is_some()),
+        ))
+    }
+}
+
+impl AsyncTestCommunicator for TokioCommunicator {
+    fn rank(&self) -> u32 {
+        self.rank
+    }
+
+    fn size(&self) -> u32 {
+        self.receiver.len() as u32
+    }
+
+    async fn send(&self, buffer: &[u8], dest: u32) -> Result<(), CommError> {
+        self.runtime.check_ambient()?;
+        self.send(buffer, dest, DEFAULT_TAG).await
+    }
+
+    async fn recv(&self, buffer: &mut [u8], source: u32) -> Result<usize, CommError> {
+        self.runtime.check_ambient()?;
+        self.recv(buffer, source, None).await
+    }
+
+    async fn barrier(&self) -> Result<(), CommError> {
+        self.runtime.check_ambient()?;
+        let size = AsyncTestCommunicator::size(self);
+        self.barrier.wait(self, self.rank, size).await
+    }
+
+    async fn send_tagged(&self, buffer: &[u8], dest: u32, tag: u32) -> Result<(), CommError> {
+        self.runtime.check_ambient()?;
+        matching::check_tag(tag)?;
+        self.send(buffer, dest, tag).await
+    }
+
+    async fn recv_tagged(
+        &self,
+        buffer: &mut [u8],
+        source: u32,
+        tag: u32,
+    ) -> Result<usize, CommError> {
+        self.runtime.check_ambient()?;
+        self.recv(buffer, source, Some(tag)).await
     }
 }
 
 impl TokioCommunicator {
-    pub fn create_n_2_n(n: u32, rank: u32) -> TokioCommunicator {
-        let runtime = tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()
-            .unwrap();
-        let socket = runtime
-            .block_on(tokio::net::UdpSocket::bind(SocketAddr::new(
-                IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)),
-                (8080 + rank) as u16,
-            )))
-            .unwrap();
+    pub fn create_n_2_n(n: u32, rank: u32) -> Result<TokioCommunicator, CommError> {
+        Self::create_with_addresses(&AddressBook::localhost(n)?, rank)
+    }
 
-        let receiver = (0..n)
-            .map(|i| {
-                SocketAddr::new(
-                    IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)),
-                    (8080 + i) as u16,
-                )
-            })
-            .collect();
+    /// Like `create_n_2_n`, with the ranks at the addresses of `addresses`.
+    pub fn create_with_addresses(
+        addresses: &AddressBook,
+        rank: u32,
+    ) -> Result<TokioCommunicator, CommError> {
+        let runtime = CommRuntime::current_thread()?;
+        let socket = runtime.block_on(async {
+            Ok(tokio::net::UdpSocket::bind(addresses.bind_address(rank)).await?)
+        })?;
+        Self::from_socket(runtime, socket, rank, addresses)
+    }
+
+    /// Like `create_n_2_n`, for the [`AsyncTestCommunicator`] API in the runtime of the caller.
+    pub async fn create_n_2_n_async(n: u32, rank: u32) -> Result<TokioCommunicator, CommError> {
+        Self::create_with_addresses_async(&AddressBook::localhost(n)?, rank).await
+    }
+
+    /// Like `create_with_addresses`, for the [`AsyncTestCommunicator`] API in the runtime of the
+    /// caller.
+    pub async fn create_with_addresses_async(
+        addresses: &AddressBook,
+        rank: u32,
+    ) -> Result<TokioCommunicator, CommError> {
+        let socket = tokio::net::UdpSocket::bind(addresses.bind_address(rank)).await?;
+        Self::from_socket(CommRuntime::ambient(), socket, rank, addresses)
+    }
+
+    /// Registers with the [`Coordinator`] at `coordinator` and waits for the other ranks of a
+    /// job of `world_size` ranks. The rank is assigned by the coordinator.
+    pub fn connect(
+        coordinator: SocketAddr,
+        world_size: u32,
+    ) -> Result<TokioCommunicator, CommError> {
+        let runtime = CommRuntime::current_thread()?;
+        let address = rendezvous::any_address(coordinator);
+        let socket = runtime.block_on(async { Ok(tokio::net::UdpSocket::bind(address).await?) })?;
+        let port = socket.local_addr()?.port();
+        let (rank, addresses) = rendezvous::register(coordinator, world_size, port)?;
+        Self::from_socket(runtime, socket, rank, &addresses)
+    }
+
+    fn from_socket(
+        runtime: CommRuntime,
+        socket: tokio::net::UdpSocket,
+        rank: u32,
+        addresses: &AddressBook,
+    ) -> Result<TokioCommunicator, CommError> {
+        fragment::enlarge_recv_buffer(&socket)?;
+        let receiver = addresses.addresses().to_vec();
 
-        TokioCommunicator {
+        Ok(TokioCommunicator {
             rank,
             socket,
             receiver,
             runtime,
+            unexpected: Mailbox::default(),
+            recv_timeout: RecvTimeout::default(),
+            scratch: tokio::sync::Mutex::new(vec![0; MAX_DATAGRAM_LEN]),
+            barrier: CentralBarrier::default(),
+            max_datagram_len: DEFAULT_DATAGRAM_LEN,
+            next_message: AtomicU32::new(0),
+            reassembler: Reassembler::default(),
+            reliability: None,
+        })
+    }
+
+    /// Limits the datagrams this rank sends, e.g. to the MTU of the network to avoid IP
+    /// fragmentation. Defaults to 1472 bytes, which fit into an Ethernet MTU of 1500.
+    pub fn with_max_datagram_len(mut self, len: usize) -> Self {
+        self.max_datagram_len = len.clamp(MIN_DATAGRAM_LEN, MAX_DATAGRAM_LEN);
+        self
+    }
+
+    /// Limits the messages this rank receives, like
+    /// [`DatagramCommunicator::with_max_message_len`].
+    pub fn with_max_message_len(mut self, len: usize) -> Self {
+        self.reassembler = Reassembler::new(len);
+        self
+    }
+
+    /// Turns on acknowledgements, retransmissions and duplicate suppression, like
+    /// [`DatagramCommunicator::with_reliable_delivery`].
+    pub fn with_reliable_delivery(mut self, reliable: bool) -> Self {
+        self.reliability = reliable.then(|| Reliability::new(self.receiver.len() as u32));
+        self
+    }
+
+    async fn recv(
+        &self,
+        buffer: &mut [u8],
+        source: u32,
+        tag: Option<u32>,
+    ) -> Result<usize, CommError> {
+        let receive = self.receive_matching(source, tag);
+        let payload = runtime::with_timeout(self.recv_timeout.get(), receive).await?;
+        matching::copy_payload(&payload, buffer)
+    }
+
+    async fn send(&self, buffer: &[u8], dest: u32, tag: u32) -> Result<(), CommError> {
+        match &self.reliability {
+            Some(reliability) => self.send_reliable(reliability, buffer, dest, tag).await,
+            None => self.send_datagrams(buffer, dest, tag).await,
         }
     }
 
-    async fn recv(&self, buffer: &mut [u8], _source: u32) {
-        self.socket.recv_from(buffer).await.unwrap();
+    async fn send_reliable(
+        &self,
+        reliability: &Reliability,
+        buffer: &[u8],
+        dest: u32,
+        tag: u32,
+    ) -> Result<(), CommError> {
+        let message = reliability.next_message(dest);
+        let datagrams = fragment::split(self.rank, tag, message, buffer, self.max_datagram_len)?;
+        let mut outstanding = Outstanding::new(message, datagrams)?;
+        let mut retransmit = false;
+        while !outstanding.is_complete() {
+            for datagram in outstanding.window(retransmit) {
+                self.socket
+                    .send_to(datagram, self.receiver[dest as usize])
+                    .await?;
+            }
+            let ack = self.receive_matching(dest, Some(ACK_TAG));
+            match tokio::time::timeout(outstanding.rto(), ack).await {
+                Ok(ack) => {
+                    outstanding.acknowledge(&ack?);
+                    retransmit = false;
+                }
+                Err(_elapsed) => {
+                    outstanding.back_off()?;
+                    retransmit = true;
+                }
+            }
+        }
+        Ok(())
     }
 
-    async fn send(&self, buffer: &[u8], dest: u32) {
-        self.socket
-            .send_to(buffer, self.receiver[dest as usize])
-            .await
-            .unwrap();
+    async fn send_datagrams(&self, buffer: &[u8], dest: u32, tag: u32) -> Result<(), CommError> {
+        let message = self.next_message.fetch_add(1, Ordering::Relaxed);
+        for datagram in fragment::split(self.rank, tag, message, buffer, self.max_datagram_len)? {
+            self.socket
+                .send_to(&datagram, self.receiver[dest as usize])
+                .await?;
+        }
+        Ok(())
     }
-}
 
-pub struct StdCommunicator {
-    rank: u32,
-    socket: UdpSocket,
-    receiver: Vec<SocketAddr>,
+    fn send_ack(&self, fragment: &Fragment) -> Result<(), CommError> {
+        let ack = reliable::encode_ack(fragment);
+        let message = self.next_message.fetch_add(1, Ordering::Relaxed);
+        let dest = self.receiver[fragment.header.source as usize];
+        for datagram in fragment::split(self.rank, ACK_TAG, message, &ack, self.max_datagram_len)? {
+            match self.socket.try_send_to(&datagram, dest) {
+                // a lost acknowledgement only delays the retransmission
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                result => {
+                    result?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the payload of the next message from `source` with `tag`. Other messages that
+    /// arrive in the meantime are queued as unexpected.
+    async fn receive_matching(&self, source: u32, tag: Option<u32>) -> Result<Vec<u8>, CommError> {
+        let (_, payload) = self.receive_message(source, tag).await?;
+        Ok(payload)
+    }
+
+    /// Like [`Self::receive_matching`], but also returns the header of the message.
+    async fn receive_message(
+        &self,
+        source: u32,
+        tag: Option<u32>,
+    ) -> Result<(Header, Vec<u8>), CommError> {
+        let mut scratch = self.scratch.lock().await;
+        if let Some(message) = self.unexpected.take(source, tag) {
+            return Ok(message);
+        }
+        if let Some(ack) = (self.reliability.as_ref()).and_then(|r| r.take_ack(source, tag)) {
+            return Ok(ack);
+        }
+        loop {
+            let (len, _) = self.socket.recv_from(&mut scratch).await?;
+            let fragment = Fragment::decode(&scratch[..len])?;
+            let reliability =
+                (self.reliability.as_ref()).filter(|_| reliable::is_covered(fragment.header.tag));
+            if let Some(reliability) = reliability {
+                self.send_ack(&fragment)?;
+                if reliability.is_duplicate(&fragment) {
+                    continue;
+                }
+            }
+            let Some((header, payload)) = self.reassembler.add(&fragment)? else {
+                continue;
+            };
+            if let Some(reliability) = reliability {
+                reliability.mark_delivered(&header, fragment.message);
+            }
+            if header.matches(source, tag) {
+                return Ok((header, payload));
+            }
+            if header.tag == ACK_TAG {
+                // for a concurrent send, or a late one of a retransmitted fragment
+                if let Some(reliability) = &self.reliability {
+                    reliability.queue_ack(header.source, payload);
+                }
+                continue;
+            }
+            match self.barrier.intercept(self.rank, &header, &payload) {
+                Control::Queue => self.unexpected.push(header, payload),
+                Control::Discard => {}
+                Control::Release { dest, epoch } => {
+                    self.send_datagrams(&barrier::encode_epoch(epoch), dest, RELEASE_TAG)
+                        .await?
+                }
+            }
+        }
+    }
 }
 
-impl TestCommunicator for StdCommunicator {
-    fn rank(&self) -> u32 {
-        self.rank
+impl ControlChannel for TokioCommunicator {
+    async fn send_control(&self, dest: u32, tag: u32, epoch: u64) -> Result<(), CommError> {
+        self.send_datagrams(&barrier::encode_epoch(epoch), dest, tag)
+            .await
     }
 
-    fn size(&self) -> u32 {
-        self.receiver.len() as u32
+    async fn recv_control(
+        &self,
+        source: u32,
+        tag: u32,
+        timeout: Duration,
+    ) -> Result<Option<u64>, CommError> {
+        let received =
+            tokio::time::timeout(timeout, self.receive_matching(source, Some(tag))).await;
+        match received {
+            Ok(payload) => Ok(barrier::decode_epoch(&payload?)),
+            Err(_elapsed) => Ok(None),
+        }
     }
+}
 
-    fn send(&self, buffer: &[u8], dest: u32) {
-        self.socket
-            .send_to(buffer, self.receiver[dest as usize])
-            .unwrap();
+impl Drop for TokioCommunicator {
+    fn drop(&mut self) {
+        if self.reliability.is_some() {
+            // acknowledge retransmissions for a while, in case our last acknowledgements were lost
+            self.runtime.block_on_drop(async {
+                let _ =
+                    tokio::time::timeout(LINGER, self.receive_matching(self.rank, Some(ACK_TAG)))
+                        .await;
+            });
+        }
     }
+}
+
+struct TokioRecvRequest<'a> {
+    comm: &'a TokioCommunicator,
+    buffer: Vec<u8>,
+    source: u32,
+    payload: Option<Vec<u8>>,
+}
 
-    fn recv(&self, buffer: &mut [u8], _source: u32) {
-        let (_, _addr) = self.socket.recv_from(buffer).unwrap();
+impl PendingRequest for TokioRecvRequest<'_> {
+    fn test(&mut self) -> Result<bool, CommError> {
+        if self.payload.is_some() {
+            return Ok(true);
+        }
+        // Poll the receive once and yield, which lets the runtime turn its IO driver without
+        // blocking. `try_recv_from` alone would never see new readiness.
+        let comm = self.comm;
+        let source = self.source;
+        self.payload = comm.runtime.block_on(async {
+            tokio::select! {
+                biased;
+                payload = comm.receive_matching(source, None) => payload.map(Some),
+                _ = tokio::task::yield_now() => Ok(None),
+            }
+        })?;
+        Ok(self.payload.is_some())
     }
 
-    fn barrier(&self) {
-        unimplemented!()
+    fn wait(mut self: Box<Self>) -> Result<Vec<u8>, CommError> {
+        let payload = match self.payload.take() {
+            Some(payload) => payload,
+            None => {
+                let receive = self.comm.receive_matching(self.source, None);
+                let timeout = self.comm.recv_timeout.get();
+                (self.comm.runtime).block_on(runtime::with_timeout(timeout, receive))?
+            }
+        };
+        matching::fill_buffer(&mut self.buffer, &payload)?;
+        Ok(self.buffer)
     }
 }
 
+pub type StdCommunicator = DatagramCommunicator<UdpSocket>;
+
 impl StdCommunicator {
-    pub fn create_n_2_n(n: u32, rank: u32) -> StdCommunicator {
-        let socket = UdpSocket::bind(SocketAddr::new(
-            IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)),
-            (8080 + rank) as u16,
-        ))
-        .unwrap();
-        let receiver = (0..n)
-            .map(|i| {
-                SocketAddr::new(
-                    IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)),
-                    (8080 + i) as u16,
-                )
-            })
-            .collect();
+    pub fn create_n_2_n(n: u32, rank: u32) -> Result<StdCommunicator, CommError> {
+        Self::create_with_addresses(&AddressBook::localhost(n)?, rank)
+    }
 
-        StdCommunicator {
+    /// Like `create_n_2_n`, with the ranks at the addresses of `addresses`.
+    pub fn create_with_addresses(
+        addresses: &AddressBook,
+        rank: u32,
+    ) -> Result<StdCommunicator, CommError> {
+        let socket = UdpSocket::bind(addresses.bind_address(rank))?;
+        fragment::enlarge_recv_buffer(&socket)?;
+        let receiver = addresses.addresses().to_vec();
+
+        Ok(DatagramCommunicator::new(rank, socket, receiver))
+    }
+
+    /// Registers with the [`Coordinator`] at `coordinator` and waits for the other ranks of a
+    /// job of `world_size` ranks. The rank is assigned by the coordinator.
+    pub fn connect(coordinator: SocketAddr, world_size: u32) -> Result<StdCommunicator, CommError> {
+        let socket = UdpSocket::bind(rendezvous::any_address(coordinator))?;
+        fragment::enlarge_recv_buffer(&socket)?;
+        let port = socket.local_addr()?.port();
+        let (rank, addresses) = rendezvous::register(coordinator, world_size, port)?;
+
+        Ok(DatagramCommunicator::new(
             rank,
             socket,
-            receiver,
-        }
+            addresses.addresses().to_vec(),
+        ))
     }
 }
 
+/// In-process communicator for tests. Messages travel over one channel per receiving rank and
+/// are matched on source and tag like in MPI, with messages that arrive before a matching
+/// receive kept as unexpected.
 pub struct ChannelSimCommunicator {
     rank: u32,
-    senders: Vec<Sender<Vec<u8>>>,
-    receivers: Receiver<Vec<u8>>,
+    senders: Vec<Sender<InFlight>>,
+    incoming: Mutex<ChannelIncoming>,
+    unexpected: Mailbox,
+    recv_timeout: RecvTimeout,
+    /// Delays messages to `dest` if a network is modeled.
+    network: Option<Network>,
     barrier: Arc<Barrier>,
+    /// Communicators created by `split`, by the rank that picks them up.
+    split_off: Arc<Mutex<HashMap<u32, ChannelSimCommunicator>>>,
+}
+
+struct ChannelIncoming {
+    receiver: Receiver<InFlight>,
+    in_transit: InTransit,
 }
 
 impl TestCommunicator for ChannelSimCommunicator {
@@ -197,34 +1267,251 @@ impl TestCommunicator for ChannelSimCommunicator {
         self.senders.len() as u32
     }
 
-    fn send(&self, buffer: &[u8], dest: u32) {
-        self.senders[dest as usize].send(buffer.to_vec()).unwrap();
+    fn send(&self, buffer: &[u8], dest: u32) -> Result<(), CommError> {
+        self.send_message(buffer, dest, DEFAULT_TAG)
     }
 
-    fn recv(&self, buffer: &mut [u8], _source: u32) {
-        buffer.copy_from_slice(&self.receivers.recv().unwrap());
+    fn recv(&self, buffer: &mut [u8], source: u32) -> Result<usize, CommError> {
+        let payload = self
+            .receive_matching(source, None, self.recv_timeout.wait())?
+            .ok_or(CommError::Timeout)?;
+        matching::copy_payload(&payload, buffer)
     }
 
-    fn barrier(&self) {
+    fn barrier(&self) -> Result<(), CommError> {
         self.barrier.wait();
+        Ok(())
+    }
+
+    fn isend(&self, buffer: Vec<u8>, dest: u32) -> Result<CommRequest<'_>, CommError> {
+        self.send(&buffer, dest)?;
+        Ok(CommRequest::completed(buffer))
+    }
+
+    fn irecv(&self, buffer: Vec<u8>, source: u32) -> Result<CommRequest<'_>, CommError> {
+        Ok(CommRequest::new(ChannelRecvRequest {
+            comm: self,
+            buffer,
+            source,
+            payload: None,
+        }))
+    }
+
+    fn send_tagged(&self, buffer: &[u8], dest: u32, tag: u32) -> Result<(), CommError> {
+        matching::check_tag(tag)?;
+        self.send_message(buffer, dest, tag)
+    }
+
+    fn recv_tagged(&self, buffer: &mut [u8], source: u32, tag: u32) -> Result<usize, CommError> {
+        let payload = self
+            .receive_matching(source, Some(tag), self.recv_timeout.wait())?
+            .ok_or(CommError::Timeout)?;
+        matching::copy_payload(&payload, buffer)
+    }
+
+    fn probe(&self, source: u32) -> Result<Status, CommError> {
+        let (header, payload) = self
+            .receive_message(source, None, self.recv_timeout.wait())?
+            .ok_or(CommError::Timeout)?;
+        Ok(self.unexpected.put_back(header, payload))
+    }
+
+    fn recv_vec(&self, source: u32) -> Result<Vec<u8>, CommError> {
+        self.receive_matching(source, None, self.recv_timeout.wait())?
+            .ok_or(CommError::Timeout)
+    }
+
+    fn recv_timeout(
+        &self,
+        buffer: &mut [u8],
+        source: u32,
+        timeout: Duration,
+    ) -> Result<usize, CommError> {
+        let payload = self
+            .receive_matching(source, None, Wait::For(timeout))?
+            .ok_or(CommError::Timeout)?;
+        matching::copy_payload(&payload, buffer)
+    }
+
+    fn set_recv_timeout(&self, timeout: Option<Duration>) -> Result<(), CommError> {
+        self.recv_timeout.set(timeout);
+        Ok(())
+    }
+
+    fn split(&self, color: Option<u32>, key: u32) -> Result<Option<Self>, CommError> {
+        let group = match color {
+            Some(color) => Some(split::join(self, color, key, &[])?),
+            None => {
+                split::abstain(self)?;
+                None
+            }
+        };
+        // The first rank of every group creates the channels for all of them. Their ranks
+        // pick them up after the barrier, before any rank can get to the next split.
+        if let Some(group) = group.as_ref().filter(|group| group.rank == 0) {
+            let model = self.network.as_ref().map(Network::model);
+            let comms = Self::create(group.size(), model);
+            let mut split_off = self.split_off.lock().unwrap();
+            for (&member, comm) in group.members.iter().zip(comms) {
+                split_off.insert(member, comm);
+            }
+        }
+        self.barrier()?;
+        Ok(group.map(|_| {
+            (self.split_off.lock().unwrap())
+                .remove(&self.rank)
+                .expect("the first rank of a group creates the communicators of all")
+        }))
+    }
+}
+
+struct ChannelRecvRequest<'a> {
+    comm: &'a ChannelSimCommunicator,
+    buffer: Vec<u8>,
+    source: u32,
+    payload: Option<Vec<u8>>,
+}
+
+impl PendingRequest for ChannelRecvRequest<'_> {
+    fn test(&mut self) -> Result<bool, CommError> {
+        if self.payload.is_none() {
+            self.payload = self.comm.receive_matching(self.source, None, Wait::Poll)?;
+        }
+        Ok(self.payload.is_some())
+    }
+
+    fn wait(mut self: Box<Self>) -> Result<Vec<u8>, CommError> {
+        let payload = match self.payload.take() {
+            Some(payload) => payload,
+            None => self
+                .comm
+                .receive_matching(self.source, None, self.comm.recv_timeout.wait())?
+                .ok_or(CommError::Timeout)?,
+        };
+        matching::fill_buffer(&mut self.buffer, &payload)?;
+        Ok(self.buffer)
     }
 }
 
 impl ChannelSimCommunicator {
+    fn send_message(&self, buffer: &[u8], dest: u32, tag: u32) -> Result<(), CommError> {
+        let header = Header {
+            source: self.rank,
+            tag,
+            len: buffer.len() as u32,
+        };
+        let arrival = match &self.network {
+            Some(network) => network.arrival(dest, buffer.len()),
+            None => Instant::now(),
+        };
+        let message = InFlight {
+            header,
+            payload: buffer.to_vec(),
+            arrival,
+        };
+        self.senders[dest as usize]
+            .send(message)
+            .map_err(|_| CommError::Disconnected { rank: dest })
+    }
+
+    /// Returns the payload of the next message from `source` with `tag`. Other messages that
+    /// arrive in the meantime are queued as unexpected. Returns `None` if `wait` does not allow
+    /// to wait any longer.
+    fn receive_matching(
+        &self,
+        source: u32,
+        tag: Option<u32>,
+        wait: Wait,
+    ) -> Result<Option<Vec<u8>>, CommError> {
+        let message = self.receive_message(source, tag, wait)?;
+        Ok(message.map(|(_, payload)| payload))
+    }
+
+    /// Like [`Self::receive_matching`], but also returns the header of the message.
+    fn receive_message(
+        &self,
+        source: u32,
+        tag: Option<u32>,
+        wait: Wait,
+    ) -> Result<Option<(Header, Vec<u8>)>, CommError> {
+        let mut incoming = self.incoming.lock().unwrap();
+        let ChannelIncoming {
+            receiver,
+            in_transit,
+        } = &mut *incoming;
+        let deadline = match wait {
+            Wait::Poll => Some(Instant::now()),
+            Wait::Block => None,
+            Wait::For(timeout) => Some(Instant::now() + timeout),
+        };
+        loop {
+            // Messages only become visible to the matching once they have arrived, which is
+            // right away without a network model.
+            while let Ok(message) = receiver.try_recv() {
+                in_transit.add(message);
+            }
+            let now = Instant::now();
+            in_transit.deliver(now, &self.unexpected);
+            if let Some(message) = self.unexpected.take(source, tag) {
+                return Ok(Some(message));
+            }
+            if deadline.is_some_and(|deadline| deadline <= now) {
+                return Ok(None);
+            }
+            let wake = match (deadline, in_transit.next_arrival()) {
+                (Some(deadline), Some(arrival)) => Some(deadline.min(arrival)),
+                (deadline, arrival) => deadline.or(arrival),
+            };
+            let received = match wake {
+                Some(wake) => receiver.recv_timeout(wake.saturating_duration_since(now)),
+                None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match received {
+                Ok(message) => in_transit.add(message),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(CommError::Disconnected { rank: source })
+                }
+            }
+        }
+    }
+
     pub fn create_n_2_n(n: u32) -> Vec<ChannelSimCommunicator> {
+        Self::create(n, None)
+    }
+
+    /// Like `create_n_2_n`, but messages are only delivered after the delay `model` gives for
+    /// them.
+    pub fn create_with_network_model(
+        n: u32,
+        model: &NetworkModel,
+    ) -> Result<Vec<ChannelSimCommunicator>, CommError> {
+        model.topology.check(n)?;
+        Ok(Self::create(n, Some(model)))
+    }
+
+    fn create(n: u32, model: Option<&NetworkModel>) -> Vec<ChannelSimCommunicator> {
         let mut senders = Vec::new();
         let mut comms = Vec::new();
         let barrier = Arc::new(Barrier::new(n as usize));
+        let split_off = Arc::new(Mutex::new(HashMap::new()));
 
         //create n communicators with a receiver. Temporarily store the senders in a vector.
-        for n in 0..n {
+        for rank in 0..n {
             let (sender, receiver) = std::sync::mpsc::channel();
             senders.push(sender);
             let comm = ChannelSimCommunicator {
-                rank: n,
+                rank,
                 senders: vec![],
-                receivers: receiver,
+                incoming: Mutex::new(ChannelIncoming {
+                    receiver,
+                    in_transit: InTransit::default(),
+                }),
+                unexpected: Mailbox::default(),
+                recv_timeout: RecvTimeout::default(),
+                network: model.map(|model| Network::new(model.clone(), rank, n)),
                 barrier: barrier.clone(),
+                split_off: split_off.clone(),
             };
             comms.push(comm);
         }
@@ -241,3 +1528,26 @@ impl ChannelSimCommunicator {
         comms
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn receives_of_the_channel_communicator_time_out() {
+        let comms = ChannelSimCommunicator::create(2, None);
+        let mut buffer = [0; 4];
+        let timed_out = comms[0].recv_timeout(&mut buffer, 1, Duration::from_millis(20));
+        assert!(matches!(timed_out, Err(CommError::Timeout)));
+
+        comms[0]
+            .set_recv_timeout(Some(Duration::from_millis(20)))
+            .unwrap();
+        assert!(matches!(comms[0].recv_vec(1), Err(CommError::Timeout)));
+
+        // a message sent after the timeouts goes to the next receive
+        comms[1].send(&[1, 2], 0).unwrap();
+        assert_eq!(comms[0].recv(&mut buffer, 1).unwrap(), 2);
+        assert_eq!(buffer[..2], [1, 2]);
+    }
+}
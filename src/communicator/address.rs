@@ -0,0 +1,271 @@
+use super::CommError;
+use clap::Parser;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+
+/// Port of rank 0 if none is configured, rank i listens on `DEFAULT_BASE_PORT + i`.
+pub const DEFAULT_BASE_PORT: u16 = 8080;
+
+/// Where the ranks of the socket based communicators listen, see [`AddressBook::from_arguments`].
+#[derive(Parser, Debug, Clone)]
+pub struct AddressArguments {
+    /// Host all ranks run on if neither a hostfile nor addresses are given.
+    #[arg(short, long, env = "HPC_SERVER_ADDRESS", default_value = "127.0.0.1")]
+    pub server_address: String,
+    /// Port of rank 0. Ranks without an explicit port use `base_port + rank`, so two jobs on
+    /// the same node need base ports far enough apart.
+    #[arg(long, env = "HPC_BASE_PORT", default_value_t = DEFAULT_BASE_PORT)]
+    pub base_port: u16,
+    /// CSV file with one `host[,port]` line per rank, in rank order. Lines starting with `#`
+    /// are skipped. Takes precedence over `--server-address`.
+    #[arg(long, env = "HPC_HOSTFILE")]
+    pub hostfile: Option<PathBuf>,
+    /// Comma separated `host[:port]` of all ranks in rank order, e.g.
+    /// `node1,node2:9000,[fe80::1]:8080`. Takes precedence over `--hostfile`.
+    #[arg(long, env = "HPC_ADDRESSES", value_delimiter = ',')]
+    pub addresses: Vec<String>,
+}
+
+impl Default for AddressArguments {
+    fn default() -> Self {
+        AddressArguments {
+            server_address: Ipv4Addr::LOCALHOST.to_string(),
+            base_port: DEFAULT_BASE_PORT,
+            hostfile: None,
+            addresses: Vec::new(),
+        }
+    }
+}
+
+/// Socket address of every rank of a communicator.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressBook {
+    addresses: Vec<SocketAddr>,
+}
+
+impl AddressBook {
+    pub fn new(addresses: Vec<SocketAddr>) -> Self {
+        AddressBook { addresses }
+    }
+
+    /// `n` ranks on the loopback interface with ports from [`DEFAULT_BASE_PORT`] on.
+    pub fn localhost(n: u32) -> Result<Self, CommError> {
+        Self::consecutive(IpAddr::V4(Ipv4Addr::LOCALHOST), DEFAULT_BASE_PORT, n)
+    }
+
+    /// `n` ranks on `host` with the ports `base_port..base_port + n`.
+    pub fn consecutive(host: IpAddr, base_port: u16, n: u32) -> Result<Self, CommError> {
+        let addresses = (0..n)
+            .map(|rank| Ok(SocketAddr::new(host, port(base_port, rank)?)))
+            .collect::<Result<_, CommError>>()?;
+        Ok(AddressBook { addresses })
+    }
+
+    /// Builds the address book of `n` ranks from the command line or environment, see
+    /// [`AddressArguments`].
+    pub fn from_arguments(arguments: &AddressArguments, n: u32) -> Result<Self, CommError> {
+        let book = if !arguments.addresses.is_empty() {
+            let addresses = (arguments.addresses.iter().zip(0..))
+                .map(|(entry, rank)| parse_entry(entry, port(arguments.base_port, rank)?))
+                .collect::<Result<_, CommError>>()?;
+            AddressBook { addresses }
+        } else if let Some(hostfile) = &arguments.hostfile {
+            Self::from_hostfile(hostfile, arguments.base_port)?
+        } else {
+            let host = parse_entry(&arguments.server_address, arguments.base_port)?.ip();
+            Self::consecutive(host, arguments.base_port, n)?
+        };
+        if book.len() != n {
+            return Err(invalid_input(format!(
+                "{} addresses configured for {} ranks",
+                book.len(),
+                n
+            )));
+        }
+        // a socket only reaches addresses of its own family
+        if book
+            .addresses
+            .iter()
+            .any(|a| a.is_ipv4() != book.addresses[0].is_ipv4())
+        {
+            return Err(invalid_input(
+                "Ranks mix IPv4 and IPv6 addresses".to_string(),
+            ));
+        }
+        Ok(book)
+    }
+
+    /// Reads a CSV file with one `host[,port]` record per rank. Records without a port use
+    /// `base_port + rank`. Hosts are IPv4 or IPv6 addresses or names to resolve.
+    pub fn from_hostfile(path: &Path, base_port: u16) -> Result<Self, CommError> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .comment(Some(b'#'))
+            .trim(csv::Trim::All)
+            .from_path(path)
+            .map_err(|e| CommError::Io(e.into()))?;
+        let mut addresses = Vec::new();
+        for (record, rank) in reader.records().zip(0..) {
+            let record = record.map_err(|e| CommError::Io(e.into()))?;
+            let port = match record.get(1) {
+                Some(value) => value.parse().map_err(|_| {
+                    invalid_input(format!("Invalid port {:?} of rank {}", value, rank))
+                })?,
+                None => port(base_port, rank)?,
+            };
+            addresses.push(resolve(&record[0], port)?);
+        }
+        Ok(AddressBook { addresses })
+    }
+
+    pub fn address(&self, rank: u32) -> SocketAddr {
+        self.addresses[rank as usize]
+    }
+
+    pub fn addresses(&self) -> &[SocketAddr] {
+        &self.addresses
+    }
+
+    pub fn len(&self) -> u32 {
+        self.addresses.len() as u32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    /// Address for `rank` to bind its socket to. Ranks on other interfaces than loopback listen
+    /// on all of them, as the configured address may belong to a host name or NAT.
+    pub(crate) fn bind_address(&self, rank: u32) -> SocketAddr {
+        let address = self.address(rank);
+        if address.ip().is_loopback() {
+            return address;
+        }
+        SocketAddr::new(unspecified(address), address.port())
+    }
+}
+
+/// The address of all interfaces in the family of `address`.
+pub(crate) fn unspecified(address: SocketAddr) -> IpAddr {
+    match address {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    }
+}
+
+fn port(base_port: u16, rank: u32) -> Result<u16, CommError> {
+    u16::try_from(base_port as u32 + rank)
+        .map_err(|_| invalid_input(format!("No port left for rank {} from {}", rank, base_port)))
+}
+
+/// Parses `host[:port]`, where an IPv6 host with a port is written in brackets.
+fn parse_entry(entry: &str, default_port: u16) -> Result<SocketAddr, CommError> {
+    let entry = entry.trim();
+    if let Ok(address) = entry.parse::<SocketAddr>() {
+        return Ok(address);
+    }
+    if let Ok(ip) = entry.trim_start_matches('[').trim_end_matches(']').parse() {
+        return Ok(SocketAddr::new(ip, default_port));
+    }
+    match entry.rsplit_once(':') {
+        Some((host, port)) => {
+            let port = port
+                .parse()
+                .map_err(|_| invalid_input(format!("Invalid port in {:?}", entry)))?;
+            resolve(host, port)
+        }
+        None => resolve(entry, default_port),
+    }
+}
+
+/// Resolves a host name or IP address, taking the first address the resolver returns.
+fn resolve(host: &str, port: u16) -> Result<SocketAddr, CommError> {
+    if let Ok(ip) = host.parse() {
+        return Ok(SocketAddr::new(ip, port));
+    }
+    (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| invalid_input(format!("Host {:?} has no address", host)))
+}
+
+fn invalid_input(message: String) -> CommError {
+    CommError::Io(io::Error::new(ErrorKind::InvalidInput, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn address(entry: &str) -> SocketAddr {
+        entry.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_entries() {
+        assert_eq!(parse_entry("10.0.0.1", 80).unwrap(), address("10.0.0.1:80"));
+        assert_eq!(
+            parse_entry(" 10.0.0.1:90 ", 80).unwrap(),
+            address("10.0.0.1:90")
+        );
+        assert_eq!(parse_entry("::1", 80).unwrap(), address("[::1]:80"));
+        assert_eq!(parse_entry("[::1]", 80).unwrap(), address("[::1]:80"));
+        assert_eq!(
+            parse_entry("[fe80::1]:90", 80).unwrap(),
+            address("[fe80::1]:90")
+        );
+        assert_eq!(parse_entry("localhost:90", 80).unwrap().port(), 90);
+        assert!(parse_entry("localhost:90", 80).unwrap().ip().is_loopback());
+        assert!(parse_entry("10.0.0.1:http", 80).is_err());
+    }
+
+    #[test]
+    fn reads_hostfiles() {
+        let path = std::env::temp_dir().join(format!("hostfile-{}.csv", std::process::id()));
+        fs::write(&path, "# rank 0 and 1\n10.0.0.1\n10.0.0.2, 9000\n\n::1\n").unwrap();
+        let book = AddressBook::from_hostfile(&path, 8000);
+        fs::write(&path, "10.0.0.1,port\n").unwrap();
+        let invalid = AddressBook::from_hostfile(&path, 8000);
+        fs::remove_file(&path).unwrap();
+
+        let expected = ["10.0.0.1:8000", "10.0.0.2:9000", "[::1]:8002"].map(address);
+        assert_eq!(book.unwrap().addresses(), expected);
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn builds_address_books_from_arguments() {
+        let arguments = AddressArguments {
+            server_address: "10.0.0.1".to_string(),
+            base_port: 9000,
+            ..AddressArguments::default()
+        };
+        let book = AddressBook::from_arguments(&arguments, 2).unwrap();
+        assert_eq!(
+            book.addresses(),
+            ["10.0.0.1:9000", "10.0.0.1:9001"].map(address)
+        );
+        assert_eq!(book.bind_address(1), address("0.0.0.0:9001"));
+
+        let arguments = AddressArguments {
+            addresses: vec!["10.0.0.1".to_string(), "10.0.0.2:7000".to_string()],
+            ..arguments
+        };
+        let book = AddressBook::from_arguments(&arguments, 2).unwrap();
+        assert_eq!(
+            book.addresses(),
+            ["10.0.0.1:9000", "10.0.0.2:7000"].map(address)
+        );
+        assert!(AddressBook::from_arguments(&arguments, 3).is_err());
+
+        let mixed = AddressArguments {
+            addresses: vec!["10.0.0.1".to_string(), "::1".to_string()],
+            ..arguments
+        };
+        assert!(AddressBook::from_arguments(&mixed, 2).is_err());
+        assert!(AddressBook::consecutive(Ipv4Addr::LOCALHOST.into(), u16::MAX, 2).is_err());
+    }
+}
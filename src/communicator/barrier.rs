@@ -0,0 +1,207 @@
+use super::matching::{Header, RESERVED_TAG_START};
+use super::CommError;
+use std::future::Future;
+use std::pin::pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+/// Sent by every rank to the root when it enters a barrier.
+pub(crate) const ARRIVE_TAG: u32 = RESERVED_TAG_START;
+/// Sent by the root to every rank once all ranks have arrived.
+pub(crate) const RELEASE_TAG: u32 = RESERVED_TAG_START + 1;
+
+const ROOT: u32 = 0;
+
+/// How long a rank waits for the release before it sends its arrival again.
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a barrier may take before it fails. Generous, as client and server processes are
+/// often started by hand.
+const BARRIER_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Point-to-point control messages a communicator offers to run the barrier protocol on.
+/// Communicators on blocking sockets implement it with futures that complete on the first poll
+/// and run the barrier with [`complete_now`].
+pub(crate) trait ControlChannel {
+    async fn send_control(&self, dest: u32, tag: u32, epoch: u64) -> Result<(), CommError>;
+    /// Waits up to `timeout` for a control message from `source` with `tag` and returns its
+    /// epoch, or `None` if nothing arrived in time.
+    async fn recv_control(
+        &self,
+        source: u32,
+        tag: u32,
+        timeout: Duration,
+    ) -> Result<Option<u64>, CommError>;
+}
+
+/// What to do with a control message that arrived while nobody was waiting for it.
+pub(crate) enum Control {
+    Queue,
+    Discard,
+    Release { dest: u32, epoch: u64 },
+}
+
+/// Central counter barrier on rank 0 for datagram transports that may lose messages.
+///
+/// Every rank sends ARRIVE(epoch) to the root until it receives RELEASE(epoch). The root waits
+/// for all arrivals and then releases everybody. A lost release makes the rank send its arrival
+/// again, which the root answers with another release, either in a later barrier or whenever
+/// it receives messages. Epochs tell duplicates of earlier barriers apart.
+#[derive(Default)]
+pub(crate) struct CentralBarrier {
+    /// Number of completed barriers, which is also the epoch of the next one.
+    epoch: AtomicU64,
+}
+
+impl CentralBarrier {
+    pub async fn wait(
+        &self,
+        channel: &impl ControlChannel,
+        rank: u32,
+        size: u32,
+    ) -> Result<(), CommError> {
+        let epoch = self.epoch.load(Ordering::Acquire);
+        let deadline = Instant::now() + BARRIER_TIMEOUT;
+        if rank == ROOT {
+            for rank in 1..size {
+                self.wait_for_arrival(channel, rank, epoch, deadline)
+                    .await?;
+            }
+            for rank in 1..size {
+                channel.send_control(rank, RELEASE_TAG, epoch).await?;
+            }
+        } else {
+            self.wait_for_release(channel, epoch, deadline).await?;
+        }
+        self.epoch.store(epoch + 1, Ordering::Release);
+        Ok(())
+    }
+
+    async fn wait_for_arrival(
+        &self,
+        channel: &impl ControlChannel,
+        rank: u32,
+        epoch: u64,
+        deadline: Instant,
+    ) -> Result<(), CommError> {
+        while Instant::now() < deadline {
+            match channel
+                .recv_control(rank, ARRIVE_TAG, RETRY_INTERVAL)
+                .await?
+            {
+                Some(arrived) if arrived == epoch => return Ok(()),
+                // the rank missed the release of an earlier barrier
+                Some(arrived) if arrived < epoch => {
+                    channel.send_control(rank, RELEASE_TAG, arrived).await?
+                }
+                _ => {}
+            }
+        }
+        Err(CommError::Timeout)
+    }
+
+    async fn wait_for_release(
+        &self,
+        channel: &impl ControlChannel,
+        epoch: u64,
+        deadline: Instant,
+    ) -> Result<(), CommError> {
+        while Instant::now() < deadline {
+            channel.send_control(ROOT, ARRIVE_TAG, epoch).await?;
+            let retry = Instant::now() + RETRY_INTERVAL;
+            // duplicate releases of earlier barriers are skipped until it is time to retry
+            while let Some(timeout) = retry.checked_duration_since(Instant::now()) {
+                match channel.recv_control(ROOT, RELEASE_TAG, timeout).await? {
+                    Some(released) if released == epoch => return Ok(()),
+                    Some(_) => {}
+                    None => break,
+                }
+            }
+        }
+        Err(CommError::Timeout)
+    }
+
+    /// Handles a control message received outside of a barrier: the root answers arrivals of
+    /// completed barriers with a release, and stale releases are dropped.
+    pub fn intercept(&self, rank: u32, header: &Header, payload: &[u8]) -> Control {
+        if header.tag < RESERVED_TAG_START {
+            return Control::Queue;
+        }
+        let Some(epoch) = decode_epoch(payload) else {
+            return Control::Discard;
+        };
+        let completed = epoch < self.epoch.load(Ordering::Acquire);
+        match header.tag {
+            ARRIVE_TAG if rank == ROOT && completed => Control::Release {
+                dest: header.source,
+                epoch,
+            },
+            RELEASE_TAG if completed => Control::Discard,
+            _ => Control::Queue,
+        }
+    }
+}
+
+/// Barrier for reliable, ordered transports: every rank reports to the root, which releases
+/// all ranks once everybody has arrived. Nothing is lost or duplicated, so no epochs are needed.
+pub(crate) fn linear_barrier(
+    rank: u32,
+    size: u32,
+    send: impl Fn(u32, u32) -> Result<(), CommError>,
+    recv: impl Fn(u32, u32) -> Result<(), CommError>,
+) -> Result<(), CommError> {
+    if rank == ROOT {
+        for rank in 1..size {
+            recv(rank, ARRIVE_TAG)?;
+        }
+        for rank in 1..size {
+            send(rank, RELEASE_TAG)?;
+        }
+    } else {
+        send(ROOT, ARRIVE_TAG)?;
+        recv(ROOT, RELEASE_TAG)?;
+    }
+    Ok(())
+}
+
+/// Like [`linear_barrier`], for the async communicators.
+pub(crate) async fn linear_barrier_async<S, R>(
+    rank: u32,
+    size: u32,
+    send: impl Fn(u32, u32) -> S,
+    recv: impl Fn(u32, u32) -> R,
+) -> Result<(), CommError>
+where
+    S: Future<Output = Result<(), CommError>>,
+    R: Future<Output = Result<(), CommError>>,
+{
+    if rank == ROOT {
+        for rank in 1..size {
+            recv(rank, ARRIVE_TAG).await?;
+        }
+        for rank in 1..size {
+            send(rank, RELEASE_TAG).await?;
+        }
+    } else {
+        send(ROOT, ARRIVE_TAG).await?;
+        recv(ROOT, RELEASE_TAG).await?;
+    }
+    Ok(())
+}
+
+/// Runs a future of a blocking [`ControlChannel`], which never has to wait for a wakeup.
+pub(crate) fn complete_now<T>(future: impl Future<Output = T>) -> T {
+    match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(output) => output,
+        Poll::Pending => unreachable!("blocking control channels complete on the first poll"),
+    }
+}
+
+pub(crate) fn encode_epoch(epoch: u64) -> [u8; 8] {
+    epoch.to_le_bytes()
+}
+
+pub(crate) fn decode_epoch(payload: &[u8]) -> Option<u64> {
+    Some(u64::from_le_bytes(payload.try_into().ok()?))
+}
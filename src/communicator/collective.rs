@@ -0,0 +1,625 @@
+use super::matching::{invalid_data, COLLECTIVE_TAG};
+use super::{CommError, TestCommunicator};
+use mpi::collective::SystemOperation;
+use mpi::datatype::Equivalence;
+use std::io;
+use std::ops::{Add, Mul};
+
+/// Operation that combines the contributions of the ranks in a reduction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReduceOp {
+    Sum,
+    Product,
+    Min,
+    Max,
+}
+
+impl ReduceOp {
+    pub(crate) fn to_mpi(self) -> SystemOperation {
+        match self {
+            ReduceOp::Sum => SystemOperation::sum(),
+            ReduceOp::Product => SystemOperation::product(),
+            ReduceOp::Min => SystemOperation::min(),
+            ReduceOp::Max => SystemOperation::max(),
+        }
+    }
+}
+
+/// Element type of a reduction. It has an MPI datatype for the native collectives and a
+/// little endian encoding for the ones built on point-to-point messages. Elements can be handed
+/// to other threads, e.g. the progress thread of a [`CommProxy`](super::CommProxy).
+pub trait Reducible: Equivalence + Copy + Send + 'static {
+    /// Size of the encoded element in bytes.
+    const SIZE: usize;
+    fn encode(self, out: &mut Vec<u8>);
+    /// Decodes an element from the first `SIZE` bytes of `bytes`.
+    fn decode(bytes: &[u8]) -> Self;
+    fn combine(self, other: Self, op: ReduceOp) -> Self;
+}
+
+/// Implements [`Reducible`] with `$add` and `$mul` for sums and products. The integer types
+/// wrap around on overflow like MPI does, instead of panicking in debug builds.
+macro_rules! reducible {
+    ($add:ident, $mul:ident: $($t:ty),*) => {
+        $(
+            impl Reducible for $t {
+                const SIZE: usize = std::mem::size_of::<$t>();
+
+                fn encode(self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_le_bytes());
+                }
+
+                fn decode(bytes: &[u8]) -> Self {
+                    <$t>::from_le_bytes(bytes[..Self::SIZE].try_into().unwrap())
+                }
+
+                fn combine(self, other: Self, op: ReduceOp) -> Self {
+                    match op {
+                        ReduceOp::Sum => self.$add(other),
+                        ReduceOp::Product => self.$mul(other),
+                        ReduceOp::Min => if other < self { other } else { self },
+                        ReduceOp::Max => if other > self { other } else { self },
+                    }
+                }
+            }
+        )*
+    };
+}
+
+reducible!(wrapping_add, wrapping_mul: i32, i64, u32, u64);
+reducible!(add, mul: f32, f64);
+
+fn encode<T: Reducible>(values: &[T]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(values.len() * T::SIZE);
+    for value in values {
+        value.encode(&mut out);
+    }
+    out
+}
+
+/// Combines the encoded elements of `bytes` into `acc`.
+fn combine_into<T: Reducible>(acc: &mut [T], bytes: &[u8], op: ReduceOp) {
+    for (value, chunk) in acc.iter_mut().zip(bytes.chunks_exact(T::SIZE)) {
+        *value = value.combine(T::decode(chunk), op);
+    }
+}
+
+pub(crate) fn check_len(what: &str, len: usize, expected: usize) -> Result<(), CommError> {
+    if len != expected {
+        return Err(CommError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} has length {}, expected {}", what, len, expected),
+        )));
+    }
+    Ok(())
+}
+
+pub(crate) fn check_root(root: u32, size: u32) -> Result<(), CommError> {
+    if root >= size {
+        return Err(CommError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Root {} is not a rank of the {} ranks", root, size),
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that `len` bytes split into one equal block per rank, and returns the block length.
+pub(crate) fn check_blocks(what: &str, len: usize, size: u32) -> Result<usize, CommError> {
+    if !len.is_multiple_of(size as usize) {
+        return Err(CommError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} has length {}, which does not split into {} equal blocks",
+                what, len, size
+            ),
+        )));
+    }
+    Ok(len / size as usize)
+}
+
+/// Checks that the counts of an all-to-all cover `len` elements, one count per rank.
+pub(crate) fn check_counts(
+    what: &str,
+    counts: &[usize],
+    len: usize,
+    size: u32,
+) -> Result<(), CommError> {
+    check_len(&format!("{} counts", what), counts.len(), size as usize)?;
+    check_len(what, len, counts.iter().sum())
+}
+
+/// Offsets of the blocks of `counts` in a contiguous buffer.
+pub(crate) fn displacements(counts: &[usize]) -> Vec<usize> {
+    counts
+        .iter()
+        .scan(0, |offset, &count| {
+            let displacement = *offset;
+            *offset += count;
+            Some(displacement)
+        })
+        .collect()
+}
+
+/// Sends `buffer` to `dest` as part of a collective operation. Collective messages carry a tag
+/// of their own, so that they do not get mixed up with point-to-point messages in flight.
+fn send_block<C: TestCommunicator + ?Sized>(
+    comm: &C,
+    buffer: &[u8],
+    dest: u32,
+) -> Result<(), CommError> {
+    comm.send_tagged(buffer, dest, COLLECTIVE_TAG)
+}
+
+/// Receives a message of a collective operation from `source`, which has to fill `buffer`.
+fn recv_block<C: TestCommunicator + ?Sized>(
+    comm: &C,
+    buffer: &mut [u8],
+    source: u32,
+) -> Result<(), CommError> {
+    let len = comm.recv_tagged(buffer, source, COLLECTIVE_TAG)?;
+    if len != buffer.len() {
+        return Err(invalid_data(format!(
+            "Rank {} contributed {} bytes to a collective operation, expected {}",
+            source,
+            len,
+            buffer.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Sends to `dest` and receives from `source` at the same time. One side of every pair receives
+/// first, otherwise two sends of messages larger than the socket buffers would wait on each
+/// other.
+fn exchange<C: TestCommunicator + ?Sized>(
+    comm: &C,
+    send: &[u8],
+    dest: u32,
+    recv: &mut [u8],
+    source: u32,
+) -> Result<(), CommError> {
+    if comm.rank() < dest {
+        send_block(comm, send, dest)?;
+        recv_block(comm, recv, source)
+    } else {
+        recv_block(comm, recv, source)?;
+        send_block(comm, send, dest)
+    }
+}
+
+/// Binomial tree rooted at `root`: every rank receives from its parent and forwards to the
+/// subtrees below it, so the data reaches all ranks in `log2(size)` rounds.
+pub(crate) fn broadcast<C: TestCommunicator + ?Sized>(
+    comm: &C,
+    buffer: &mut [u8],
+    root: u32,
+) -> Result<(), CommError> {
+    let size = comm.size();
+    check_root(root, size)?;
+    let relative = (comm.rank() + size - root) % size;
+    let mut mask = 1;
+    while mask < size {
+        if relative & mask != 0 {
+            recv_block(comm, buffer, (relative - mask + root) % size)?;
+            break;
+        }
+        mask <<= 1;
+    }
+    mask >>= 1;
+    while mask > 0 {
+        if relative + mask < size {
+            send_block(comm, buffer, (relative + mask + root) % size)?;
+        }
+        mask >>= 1;
+    }
+    Ok(())
+}
+
+/// Binomial tree rooted at `root`, the reverse of [`broadcast`]: every rank combines the
+/// partial results of its subtrees and passes them on to its parent.
+pub(crate) fn reduce<C: TestCommunicator + ?Sized, T: Reducible>(
+    comm: &C,
+    send: &[T],
+    recv: &mut [T],
+    op: ReduceOp,
+    root: u32,
+) -> Result<(), CommError> {
+    let size = comm.size();
+    check_root(root, size)?;
+    let relative = (comm.rank() + size - root) % size;
+    if relative == 0 {
+        check_len("reduce receive buffer", recv.len(), send.len())?;
+    }
+    let mut acc = send.to_vec();
+    let mut partial = vec![0; send.len() * T::SIZE];
+    let mut mask = 1;
+    while mask < size {
+        if relative & mask != 0 {
+            send_block(comm, &encode(&acc), (relative - mask + root) % size)?;
+            return Ok(());
+        }
+        if relative + mask < size {
+            recv_block(comm, &mut partial, (relative + mask + root) % size)?;
+            combine_into(&mut acc, &partial, op);
+        }
+        mask <<= 1;
+    }
+    recv.copy_from_slice(&acc);
+    Ok(())
+}
+
+/// Recursive doubling: in round `i` every rank exchanges its partial result with the rank
+/// `2^i` away, so all of them hold the result after `log2(size)` rounds. For sizes that are no
+/// power of two, the first ranks fold pairwise into one before and get the result after.
+pub(crate) fn allreduce<C: TestCommunicator + ?Sized, T: Reducible>(
+    comm: &C,
+    send: &[T],
+    recv: &mut [T],
+    op: ReduceOp,
+) -> Result<(), CommError> {
+    check_len("allreduce receive buffer", recv.len(), send.len())?;
+    let rank = comm.rank();
+    let size = comm.size();
+    let power = 1 << (31 - size.leading_zeros());
+    let folded = size - power;
+    recv.copy_from_slice(send);
+    let mut partial = vec![0; send.len() * T::SIZE];
+
+    // ranks below 2 * folded pair up, the even one of each pair sits out the doubling
+    let virtual_rank = if rank < 2 * folded {
+        if rank % 2 == 1 {
+            recv_block(comm, &mut partial, rank - 1)?;
+            combine_into(recv, &partial, op);
+            Some(rank / 2)
+        } else {
+            send_block(comm, &encode(recv), rank + 1)?;
+            None
+        }
+    } else {
+        Some(rank - folded)
+    };
+
+    if let Some(virtual_rank) = virtual_rank {
+        let mut mask = 1;
+        while mask < power {
+            let peer = virtual_rank ^ mask;
+            let peer = if peer < folded {
+                peer * 2 + 1
+            } else {
+                peer + folded
+            };
+            exchange(comm, &encode(recv), peer, &mut partial, peer)?;
+            combine_into(recv, &partial, op);
+            mask <<= 1;
+        }
+    }
+
+    if rank < 2 * folded {
+        if rank % 2 == 1 {
+            send_block(comm, &encode(recv), rank - 1)?;
+        } else {
+            recv_block(comm, &mut partial, rank + 1)?;
+            for (value, chunk) in recv.iter_mut().zip(partial.chunks_exact(T::SIZE)) {
+                *value = T::decode(chunk);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The root receives the blocks of all other ranks in turn.
+pub(crate) fn gather<C: TestCommunicator + ?Sized>(
+    comm: &C,
+    send: &[u8],
+    recv: &mut [u8],
+    root: u32,
+) -> Result<(), CommError> {
+    let rank = comm.rank();
+    check_root(root, comm.size())?;
+    if rank != root {
+        return send_block(comm, send, root);
+    }
+    let block = send.len();
+    check_len(
+        "gather receive buffer",
+        recv.len(),
+        block * comm.size() as usize,
+    )?;
+    for source in 0..comm.size() {
+        let chunk = &mut recv[source as usize * block..(source as usize + 1) * block];
+        if source == rank {
+            chunk.copy_from_slice(send);
+        } else {
+            recv_block(comm, chunk, source)?;
+        }
+    }
+    Ok(())
+}
+
+/// Ring: in every round each rank passes the block it received last on to its right
+/// neighbour, so all blocks have gone around after `size - 1` rounds.
+pub(crate) fn allgather<C: TestCommunicator + ?Sized>(
+    comm: &C,
+    send: &[u8],
+    recv: &mut [u8],
+) -> Result<(), CommError> {
+    let rank = comm.rank();
+    let size = comm.size();
+    let block = send.len();
+    check_len(
+        "allgather receive buffer",
+        recv.len(),
+        block * size as usize,
+    )?;
+    let right = (rank + 1) % size;
+    let left = (rank + size - 1) % size;
+    let offset = |i: u32| i as usize * block;
+
+    recv[offset(rank)..offset(rank + 1)].copy_from_slice(send);
+    let mut outgoing = send.to_vec();
+    for step in 0..size - 1 {
+        let incoming = (rank + size - step - 1) % size;
+        let chunk = &mut recv[offset(incoming)..offset(incoming + 1)];
+        exchange(comm, &outgoing, right, chunk, left)?;
+        outgoing.copy_from_slice(chunk);
+    }
+    Ok(())
+}
+
+/// The root sends every other rank its block in turn.
+pub(crate) fn scatter<C: TestCommunicator + ?Sized>(
+    comm: &C,
+    send: &[u8],
+    recv: &mut [u8],
+    root: u32,
+) -> Result<(), CommError> {
+    let rank = comm.rank();
+    check_root(root, comm.size())?;
+    if rank != root {
+        return recv_block(comm, recv, root);
+    }
+    let block = recv.len();
+    check_len(
+        "scatter send buffer",
+        send.len(),
+        block * comm.size() as usize,
+    )?;
+    for dest in 0..comm.size() {
+        let chunk = &send[dest as usize * block..(dest as usize + 1) * block];
+        if dest == rank {
+            recv.copy_from_slice(chunk);
+        } else {
+            send_block(comm, chunk, dest)?;
+        }
+    }
+    Ok(())
+}
+
+/// [`alltoallv`] with blocks of equal length.
+pub(crate) fn alltoall<C: TestCommunicator + ?Sized>(
+    comm: &C,
+    send: &[u8],
+    recv: &mut [u8],
+) -> Result<(), CommError> {
+    let size = comm.size();
+    let block = check_blocks("alltoall send buffer", send.len(), size)?;
+    check_len("alltoall receive buffer", recv.len(), send.len())?;
+    let counts = vec![block; size as usize];
+    alltoallv(comm, send, &counts, recv, &counts)
+}
+
+/// Pairwise exchange: in round `i` every rank sends to the rank `i` to its right and receives
+/// from the one `i` to its left, so no rank is the target of two others at once.
+pub(crate) fn alltoallv<C: TestCommunicator + ?Sized>(
+    comm: &C,
+    send: &[u8],
+    send_counts: &[usize],
+    recv: &mut [u8],
+    recv_counts: &[usize],
+) -> Result<(), CommError> {
+    let rank = comm.rank();
+    let size = comm.size();
+    check_counts("alltoallv send buffer", send_counts, send.len(), size)?;
+    check_counts("alltoallv receive buffer", recv_counts, recv.len(), size)?;
+    let send_displacements = displacements(send_counts);
+    let recv_displacements = displacements(recv_counts);
+    let send_block = |i: u32| {
+        let i = i as usize;
+        &send[send_displacements[i]..send_displacements[i] + send_counts[i]]
+    };
+    let recv_range = |i: u32| {
+        let i = i as usize;
+        recv_displacements[i]..recv_displacements[i] + recv_counts[i]
+    };
+
+    recv[recv_range(rank)].copy_from_slice(send_block(rank));
+    for step in 1..size {
+        let dest = (rank + step) % size;
+        let source = (rank + size - step) % size;
+        exchange(
+            comm,
+            send_block(dest),
+            dest,
+            &mut recv[recv_range(source)],
+            source,
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communicator::ChannelSimCommunicator;
+    use std::thread;
+
+    const SIZES: [u32; 4] = [1, 2, 3, 5];
+
+    /// Runs `f` on every rank of a fresh communicator of `n` ranks and returns the results in
+    /// rank order.
+    fn on_all_ranks<T: Send>(n: u32, f: impl Fn(&ChannelSimCommunicator) -> T + Sync) -> Vec<T> {
+        let comms = ChannelSimCommunicator::create(n, None);
+        thread::scope(|scope| {
+            let ranks: Vec<_> = comms.iter().map(|comm| scope.spawn(|| f(comm))).collect();
+            ranks.into_iter().map(|rank| rank.join().unwrap()).collect()
+        })
+    }
+
+    #[test]
+    fn integer_reductions_wrap_around() {
+        assert_eq!(u32::MAX.combine(1, ReduceOp::Sum), 0);
+        assert_eq!(i64::MAX.combine(2, ReduceOp::Product), -2);
+        assert_eq!(1.5f64.combine(2.0, ReduceOp::Product), 3.0);
+    }
+
+    #[test]
+    fn broadcast_from_every_root() {
+        for n in SIZES {
+            for root in 0..n {
+                let received = on_all_ranks(n, |comm| {
+                    let mut buffer = [comm.rank() as u8; 4];
+                    comm.broadcast(&mut buffer, root).unwrap();
+                    buffer
+                });
+                assert!(received.iter().all(|buffer| *buffer == [root as u8; 4]));
+            }
+        }
+    }
+
+    #[test]
+    fn reduce_and_allreduce() {
+        for n in SIZES {
+            let sum = (n * (n + 1) / 2) as i64;
+            let factorial = (1..=n as i64).product::<i64>();
+            for root in 0..n {
+                let reduced = on_all_ranks(n, |comm| {
+                    let mut recv = [0i64; 2];
+                    let send = [comm.rank() as i64 + 1, 2];
+                    comm.reduce(&send, &mut recv, ReduceOp::Sum, root).unwrap();
+                    recv
+                });
+                assert_eq!(reduced[root as usize], [sum, 2 * n as i64]);
+            }
+            let reduced = on_all_ranks(n, |comm| {
+                let mut recv = [0i64; 2];
+                let send = [comm.rank() as i64 + 1, 2];
+                comm.allreduce(&send, &mut recv, ReduceOp::Product).unwrap();
+                recv
+            });
+            assert!(reduced.iter().all(|recv| *recv == [factorial, 1 << n]));
+        }
+    }
+
+    #[test]
+    fn gather_scatter_and_allgather() {
+        for n in SIZES {
+            let ranks: Vec<u8> = (0..n as u8).flat_map(|rank| [rank, rank + 100]).collect();
+            for root in 0..n {
+                let gathered = on_all_ranks(n, |comm| {
+                    let rank = comm.rank() as u8;
+                    let mut recv = vec![0; 2 * n as usize];
+                    comm.gather(&[rank, rank + 100], &mut recv, root).unwrap();
+                    recv
+                });
+                assert_eq!(gathered[root as usize], ranks);
+
+                let scattered = on_all_ranks(n, |comm| {
+                    let send = if comm.rank() == root {
+                        ranks.clone()
+                    } else {
+                        vec![]
+                    };
+                    let mut recv = [0; 2];
+                    comm.scatter(&send, &mut recv, root).unwrap();
+                    recv
+                });
+                for (rank, recv) in (0..).zip(scattered) {
+                    assert_eq!(recv, [rank, rank + 100]);
+                }
+            }
+            let gathered = on_all_ranks(n, |comm| {
+                let rank = comm.rank() as u8;
+                let mut recv = vec![0; 2 * n as usize];
+                comm.allgather(&[rank, rank + 100], &mut recv).unwrap();
+                recv
+            });
+            assert!(gathered.iter().all(|recv| *recv == ranks));
+        }
+    }
+
+    #[test]
+    fn alltoall_and_alltoallv() {
+        for n in SIZES {
+            let exchanged = on_all_ranks(n, |comm| {
+                let send: Vec<u8> = (0..n).map(|dest| (10 * comm.rank() + dest) as u8).collect();
+                let mut recv = vec![0; n as usize];
+                comm.alltoall(&send, &mut recv).unwrap();
+                recv
+            });
+            for (rank, recv) in (0..n).zip(exchanged) {
+                let expected: Vec<u8> = (0..n).map(|source| (10 * source + rank) as u8).collect();
+                assert_eq!(recv, expected);
+            }
+
+            // rank `r` sends `d + 1` bytes of `r` to rank `d`
+            let exchanged = on_all_ranks(n, |comm| {
+                let rank = comm.rank() as usize;
+                let send_counts: Vec<usize> = (1..=n as usize).collect();
+                let recv_counts = vec![rank + 1; n as usize];
+                let send = vec![rank as u8; send_counts.iter().sum()];
+                let mut recv = vec![0; recv_counts.iter().sum()];
+                comm.alltoallv(&send, &send_counts, &mut recv, &recv_counts)
+                    .unwrap();
+                recv
+            });
+            for (rank, recv) in (0..n as usize).zip(exchanged) {
+                let expected: Vec<u8> = (0..n as u8)
+                    .flat_map(|source| vec![source; rank + 1])
+                    .collect();
+                assert_eq!(recv, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_arguments_are_rejected() {
+        for n in SIZES {
+            let results = on_all_ranks(n, |comm| {
+                let mut buffer = [0; 2];
+                let broadcast = comm.broadcast(&mut buffer, n).is_err();
+                let gather = comm.gather(&[0], &mut buffer, n).is_err();
+                let scatter = comm.scatter(&buffer, &mut [0], n).is_err();
+                let reduce = comm.reduce(&[0u32], &mut [0], ReduceOp::Sum, n).is_err();
+                let send = vec![0; n as usize + 1];
+                let alltoall = comm.alltoall(&send, &mut vec![0; n as usize + 1]);
+                [
+                    broadcast,
+                    gather,
+                    scatter,
+                    reduce,
+                    alltoall.is_err() || n == 1,
+                ]
+            });
+            assert!(results.iter().flatten().all(|&rejected| rejected));
+        }
+    }
+
+    #[test]
+    fn collective_messages_do_not_mix_with_point_to_point_ones() {
+        let received = on_all_ranks(2, |comm| {
+            let mut buffer = [comm.rank() as u8; 3];
+            if comm.rank() == 0 {
+                comm.send(&[7], 1).unwrap();
+                comm.broadcast(&mut buffer, 0).unwrap();
+            } else {
+                comm.broadcast(&mut buffer, 0).unwrap();
+                let mut message = [0];
+                assert_eq!(comm.recv(&mut message, 0).unwrap(), 1);
+                assert_eq!(message, [7]);
+            }
+            buffer
+        });
+        assert_eq!(received, [[0; 3], [0; 3]]);
+    }
+}
@@ -0,0 +1,572 @@
+use super::barrier::{self, CentralBarrier, Control, ControlChannel, RELEASE_TAG};
+use super::fragment::{self, Fragment, Reassembler, MIN_DATAGRAM_LEN};
+use super::matching::{
+    self, Header, Mailbox, RecvTimeout, Wait, DEFAULT_MAX_MESSAGE_LEN, DEFAULT_TAG,
+};
+use super::reliable::{self, Outstanding, Reliability, ACK_TAG, LINGER};
+use super::split;
+use super::{CommError, CommRequest, PendingRequest, Status, TestCommunicator};
+use clap::Parser;
+use socket2::{SockAddr, SockRef};
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, UdpSocket};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Parser, Debug, Clone)]
+pub struct DatagramOptions {
+    /// Largest UDP datagram sent, including headers. Longer messages are split into fragments.
+    /// The default of 1472 avoids IP fragmentation on an Ethernet MTU of 1500, at most 65507.
+    #[arg(long, default_value_t = matching::DEFAULT_DATAGRAM_LEN)]
+    pub max_datagram_len: usize,
+    /// Longest message received. Fragments of longer ones are rejected, so that a corrupt
+    /// header cannot make a rank allocate arbitrary amounts of memory.
+    #[arg(long, default_value_t = DEFAULT_MAX_MESSAGE_LEN)]
+    pub max_message_len: usize,
+    /// Acknowledge and retransmit datagrams, so that a lost one does not hang a receive. All
+    /// ranks need the same setting.
+    #[arg(long)]
+    pub reliable: bool,
+}
+
+impl Default for DatagramOptions {
+    fn default() -> Self {
+        DatagramOptions {
+            max_datagram_len: matching::DEFAULT_DATAGRAM_LEN,
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+            reliable: false,
+        }
+    }
+}
+
+/// Blocking socket that sends whole messages, such as UDP or Unix datagram sockets.
+pub trait DatagramSocket: Send + Sync + Sized {
+    type Address: Send + Sync;
+
+    /// Largest datagram the socket can send, including our headers.
+    const MAX_DATAGRAM_LEN: usize;
+    /// Largest datagram sent unless configured otherwise.
+    const DEFAULT_DATAGRAM_LEN: usize = Self::MAX_DATAGRAM_LEN;
+
+    fn send_to(&self, datagram: &[u8], address: &Self::Address) -> io::Result<usize>;
+    /// Like `send_to`, but fails with `WouldBlock` instead of waiting for buffer space.
+    fn try_send_to(&self, datagram: &[u8], address: &Self::Address) -> io::Result<usize>;
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<usize>;
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    /// Binds the socket of a communicator split off the one of this socket, which the other
+    /// ranks reach at `address`. Returns it with the address they reach it at.
+    fn bind_split(&self, address: &Self::Address) -> io::Result<(Self, Self::Address)>;
+    /// Cleans up after a socket of `bind_split` once its communicator is dropped.
+    fn unbind_split(&self) -> io::Result<()> {
+        Ok(())
+    }
+    fn encode_address(address: &Self::Address) -> Vec<u8>;
+    fn decode_address(bytes: &[u8]) -> io::Result<Self::Address>;
+}
+
+impl DatagramSocket for UdpSocket {
+    type Address = SocketAddr;
+
+    const MAX_DATAGRAM_LEN: usize = matching::MAX_DATAGRAM_LEN;
+    const DEFAULT_DATAGRAM_LEN: usize = matching::DEFAULT_DATAGRAM_LEN;
+
+    fn send_to(&self, datagram: &[u8], address: &SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, datagram, address)
+    }
+
+    fn try_send_to(&self, datagram: &[u8], address: &SocketAddr) -> io::Result<usize> {
+        SockRef::from(self).send_to_with_flags(
+            datagram,
+            &SockAddr::from(*address),
+            libc::MSG_DONTWAIT,
+        )
+    }
+
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        Ok(self.recv_from(buffer)?.0)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UdpSocket::set_nonblocking(self, nonblocking)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+
+    fn bind_split(&self, address: &SocketAddr) -> io::Result<(Self, SocketAddr)> {
+        // on the same interface with any free port
+        let socket = UdpSocket::bind(SocketAddr::new(self.local_addr()?.ip(), 0))?;
+        fragment::enlarge_recv_buffer(&socket)?;
+        let port = socket.local_addr()?.port();
+        Ok((socket, SocketAddr::new(address.ip(), port)))
+    }
+
+    fn encode_address(address: &SocketAddr) -> Vec<u8> {
+        split::encode_socket_address(*address)
+    }
+
+    fn decode_address(bytes: &[u8]) -> io::Result<SocketAddr> {
+        split::decode_socket_address(bytes)
+    }
+}
+
+impl DatagramSocket for UnixDatagram {
+    type Address = PathBuf;
+
+    // well below the default socket send buffer, net.core.wmem_default, which also has to
+    // hold the bookkeeping of the kernel
+    const MAX_DATAGRAM_LEN: usize = 128 << 10;
+
+    fn send_to(&self, datagram: &[u8], address: &PathBuf) -> io::Result<usize> {
+        UnixDatagram::send_to(self, datagram, address)
+    }
+
+    fn try_send_to(&self, datagram: &[u8], address: &PathBuf) -> io::Result<usize> {
+        SockRef::from(self).send_to_with_flags(
+            datagram,
+            &SockAddr::unix(address)?,
+            libc::MSG_DONTWAIT,
+        )
+    }
+
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        UnixDatagram::recv(self, buffer)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixDatagram::set_nonblocking(self, nonblocking)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixDatagram::set_read_timeout(self, timeout)
+    }
+
+    fn bind_split(&self, address: &PathBuf) -> io::Result<(Self, PathBuf)> {
+        let path = split::socket_path(address);
+        Ok((UnixDatagram::bind(&path)?, path))
+    }
+
+    fn unbind_split(&self) -> io::Result<()> {
+        // the names of split sockets are unique, so nobody would remove them otherwise
+        match self.local_addr()?.as_pathname() {
+            Some(path) => fs::remove_file(path),
+            None => Ok(()),
+        }
+    }
+
+    fn encode_address(address: &PathBuf) -> Vec<u8> {
+        address.as_os_str().as_bytes().to_vec()
+    }
+
+    fn decode_address(bytes: &[u8]) -> io::Result<PathBuf> {
+        Ok(PathBuf::from(OsStr::from_bytes(bytes)))
+    }
+}
+
+/// Communicator on a single blocking datagram socket per rank, see
+/// [`StdCommunicator`](super::StdCommunicator) and [`UdsCommunicator`](super::UdsCommunicator).
+/// Messages are split into datagrams with a header for matching and reassembled on receive.
+/// Lost datagrams are only recovered with [`with_reliable_delivery`](Self::with_reliable_delivery).
+pub struct DatagramCommunicator<S: DatagramSocket> {
+    rank: u32,
+    socket: S,
+    receiver: Vec<S::Address>,
+    unexpected: Mailbox,
+    recv_timeout: RecvTimeout,
+    scratch: Mutex<Vec<u8>>,
+    barrier: CentralBarrier,
+    max_datagram_len: usize,
+    next_message: AtomicU32,
+    reassembler: Reassembler,
+    reliability: Option<Reliability>,
+    /// Whether the socket was bound by `split`.
+    split_off: bool,
+}
+
+impl<S: DatagramSocket> TestCommunicator for DatagramCommunicator<S> {
+    fn rank(&self) -> u32 {
+        self.rank
+    }
+
+    fn size(&self) -> u32 {
+        self.receiver.len() as u32
+    }
+
+    fn send(&self, buffer: &[u8], dest: u32) -> Result<(), CommError> {
+        self.send_tagged(buffer, dest, DEFAULT_TAG)
+    }
+
+    fn recv(&self, buffer: &mut [u8], source: u32) -> Result<usize, CommError> {
+        let payload = self
+            .receive_matching(source, None, self.recv_timeout.wait())?
+            .ok_or(CommError::Timeout)?;
+        matching::copy_payload(&payload, buffer)
+    }
+
+    fn barrier(&self) -> Result<(), CommError> {
+        barrier::complete_now(self.barrier.wait(self, self.rank, self.size()))
+    }
+
+    fn isend(&self, buffer: Vec<u8>, dest: u32) -> Result<CommRequest<'_>, CommError> {
+        // datagrams are handed to the kernel right away, so the send completes eagerly
+        self.send(&buffer, dest)?;
+        Ok(CommRequest::completed(buffer))
+    }
+
+    fn irecv(&self, buffer: Vec<u8>, source: u32) -> Result<CommRequest<'_>, CommError> {
+        Ok(CommRequest::new(DatagramRecvRequest {
+            comm: self,
+            buffer,
+            source,
+            payload: None,
+        }))
+    }
+
+    fn send_tagged(&self, buffer: &[u8], dest: u32, tag: u32) -> Result<(), CommError> {
+        matching::check_tag(tag)?;
+        match &self.reliability {
+            Some(reliability) => self.send_reliable(reliability, buffer, dest, tag),
+            None => self.send_frame(buffer, dest, tag),
+        }
+    }
+
+    fn recv_tagged(&self, buffer: &mut [u8], source: u32, tag: u32) -> Result<usize, CommError> {
+        let payload = self
+            .receive_matching(source, Some(tag), self.recv_timeout.wait())?
+            .ok_or(CommError::Timeout)?;
+        matching::copy_payload(&payload, buffer)
+    }
+
+    fn probe(&self, source: u32) -> Result<Status, CommError> {
+        let (header, payload) = self
+            .receive_message(source, None, self.recv_timeout.wait())?
+            .ok_or(CommError::Timeout)?;
+        Ok(self.unexpected.put_back(header, payload))
+    }
+
+    fn recv_vec(&self, source: u32) -> Result<Vec<u8>, CommError> {
+        self.receive_matching(source, None, self.recv_timeout.wait())?
+            .ok_or(CommError::Timeout)
+    }
+
+    fn recv_timeout(
+        &self,
+        buffer: &mut [u8],
+        source: u32,
+        timeout: Duration,
+    ) -> Result<usize, CommError> {
+        let payload = self
+            .receive_matching(source, None, Wait::For(timeout))?
+            .ok_or(CommError::Timeout)?;
+        matching::copy_payload(&payload, buffer)
+    }
+
+    fn set_recv_timeout(&self, timeout: Option<Duration>) -> Result<(), CommError> {
+        self.recv_timeout.set(timeout);
+        Ok(())
+    }
+
+    fn split(&self, color: Option<u32>, key: u32) -> Result<Option<Self>, CommError> {
+        let Some(color) = color else {
+            split::abstain(self)?;
+            return Ok(None);
+        };
+        // bound before the exchange, so that it is there once the others send to it
+        let (socket, address) = self.socket.bind_split(&self.receiver[self.rank as usize])?;
+        let group = split::join(self, color, key, &S::encode_address(&address))?;
+        let receiver = (group.addresses.iter())
+            .map(|address| S::decode_address(address))
+            .collect::<io::Result<_>>()?;
+        let mut comm = DatagramCommunicator::new(group.rank, socket, receiver);
+        comm.split_off = true;
+        Ok(Some(
+            comm.with_max_datagram_len(self.max_datagram_len)
+                .with_max_message_len(self.reassembler.max_message_len())
+                .with_reliable_delivery(self.reliability.is_some()),
+        ))
+    }
+}
+
+struct DatagramRecvRequest<'a, S: DatagramSocket> {
+    comm: &'a DatagramCommunicator<S>,
+    buffer: Vec<u8>,
+    source: u32,
+    payload: Option<Vec<u8>>,
+}
+
+impl<S: DatagramSocket> PendingRequest for DatagramRecvRequest<'_, S> {
+    fn test(&mut self) -> Result<bool, CommError> {
+        if self.payload.is_none() {
+            self.payload = self.comm.receive_matching(self.source, None, Wait::Poll)?;
+        }
+        Ok(self.payload.is_some())
+    }
+
+    fn wait(mut self: Box<Self>) -> Result<Vec<u8>, CommError> {
+        let payload = match self.payload.take() {
+            Some(payload) => payload,
+            None => self
+                .comm
+                .receive_matching(self.source, None, self.comm.recv_timeout.wait())?
+                .ok_or(CommError::Timeout)?,
+        };
+        matching::fill_buffer(&mut self.buffer, &payload)?;
+        Ok(self.buffer)
+    }
+}
+
+impl<S: DatagramSocket> DatagramCommunicator<S> {
+    /// `receiver` holds the addresses of all ranks, including the one `socket` is bound to.
+    pub(crate) fn new(rank: u32, socket: S, receiver: Vec<S::Address>) -> Self {
+        DatagramCommunicator {
+            rank,
+            socket,
+            receiver,
+            unexpected: Mailbox::default(),
+            recv_timeout: RecvTimeout::default(),
+            scratch: Mutex::new(vec![0; S::MAX_DATAGRAM_LEN]),
+            barrier: CentralBarrier::default(),
+            max_datagram_len: S::DEFAULT_DATAGRAM_LEN,
+            next_message: AtomicU32::new(0),
+            reassembler: Reassembler::default(),
+            reliability: None,
+            split_off: false,
+        }
+    }
+
+    /// Limits the datagrams this rank sends, e.g. to the MTU of the network to avoid IP
+    /// fragmentation. Defaults to [`DatagramSocket::DEFAULT_DATAGRAM_LEN`].
+    pub fn with_max_datagram_len(mut self, len: usize) -> Self {
+        self.max_datagram_len = len.clamp(MIN_DATAGRAM_LEN, S::MAX_DATAGRAM_LEN);
+        self
+    }
+
+    /// Limits the messages this rank receives. Defaults to 1 GiB.
+    pub fn with_max_message_len(mut self, len: usize) -> Self {
+        self.reassembler = Reassembler::new(len);
+        self
+    }
+
+    /// Turns on acknowledgements, retransmissions and duplicate suppression, see
+    /// [`Reliability`]. Sends then return once the receiver has acknowledged all datagrams.
+    pub fn with_reliable_delivery(mut self, reliable: bool) -> Self {
+        self.reliability = reliable.then(|| Reliability::new(self.size()));
+        self
+    }
+
+    fn send_frame(&self, buffer: &[u8], dest: u32, tag: u32) -> Result<(), CommError> {
+        let message = self.next_message.fetch_add(1, Ordering::Relaxed);
+        for datagram in fragment::split(self.rank, tag, message, buffer, self.max_datagram_len)? {
+            self.socket
+                .send_to(&datagram, &self.receiver[dest as usize])?;
+        }
+        Ok(())
+    }
+
+    fn send_reliable(
+        &self,
+        reliability: &Reliability,
+        buffer: &[u8],
+        dest: u32,
+        tag: u32,
+    ) -> Result<(), CommError> {
+        let message = reliability.next_message(dest);
+        let datagrams = fragment::split(self.rank, tag, message, buffer, self.max_datagram_len)?;
+        let mut outstanding = Outstanding::new(message, datagrams)?;
+        let mut retransmit = false;
+        while !outstanding.is_complete() {
+            for datagram in outstanding.window(retransmit) {
+                self.socket
+                    .send_to(datagram, &self.receiver[dest as usize])?;
+            }
+            match self.receive_matching(dest, Some(ACK_TAG), Wait::For(outstanding.rto()))? {
+                Some(ack) => {
+                    outstanding.acknowledge(&ack);
+                    retransmit = false;
+                }
+                None => {
+                    outstanding.back_off()?;
+                    retransmit = true;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the payload of the next message from `source` with `tag`. Other messages that
+    /// arrive in the meantime are queued as unexpected. Returns `None` if `wait` does not allow
+    /// to wait any longer.
+    fn receive_matching(
+        &self,
+        source: u32,
+        tag: Option<u32>,
+        wait: Wait,
+    ) -> Result<Option<Vec<u8>>, CommError> {
+        let message = self.receive_message(source, tag, wait)?;
+        Ok(message.map(|(_, payload)| payload))
+    }
+
+    /// Like [`Self::receive_matching`], but also returns the header of the message.
+    fn receive_message(
+        &self,
+        source: u32,
+        tag: Option<u32>,
+        wait: Wait,
+    ) -> Result<Option<(Header, Vec<u8>)>, CommError> {
+        let mut scratch = self.scratch.lock().unwrap();
+        if let Some(message) = self.unexpected.take(source, tag) {
+            return Ok(Some(message));
+        }
+        if let Some(ack) = (self.reliability.as_ref()).and_then(|r| r.take_ack(source, tag)) {
+            return Ok(Some(ack));
+        }
+        let deadline = match wait {
+            Wait::For(timeout) => Some(Instant::now() + timeout),
+            _ => None,
+        };
+        if let Wait::Poll = wait {
+            self.socket.set_nonblocking(true)?;
+        }
+        let payload = loop {
+            if let Some(deadline) = deadline {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    break Ok(None);
+                }
+                if let Err(e) = self.socket.set_read_timeout(Some(remaining)) {
+                    break Err(e.into());
+                }
+            }
+            match self.socket.recv(&mut scratch) {
+                Ok(len) => match self.dispatch(&scratch[..len], source, tag) {
+                    Ok(None) => {}
+                    matched => break matched,
+                },
+                Err(e)
+                    if !matches!(wait, Wait::Block)
+                        && matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                {
+                    break Ok(None)
+                }
+                Err(e) => break Err(e.into()),
+            }
+        };
+        match wait {
+            Wait::Poll => self.socket.set_nonblocking(false)?,
+            Wait::For(_) => self.socket.set_read_timeout(None)?,
+            Wait::Block => {}
+        }
+        payload
+    }
+
+    /// Returns the message `datagram` completes if it matches, otherwise queues or handles the
+    /// message.
+    fn dispatch(
+        &self,
+        datagram: &[u8],
+        source: u32,
+        tag: Option<u32>,
+    ) -> Result<Option<(Header, Vec<u8>)>, CommError> {
+        let fragment = Fragment::decode(datagram)?;
+        let reliability =
+            (self.reliability.as_ref()).filter(|_| reliable::is_covered(fragment.header.tag));
+        if let Some(reliability) = reliability {
+            self.send_ack(&fragment)?;
+            if reliability.is_duplicate(&fragment) {
+                return Ok(None);
+            }
+        }
+        let Some((header, payload)) = self.reassembler.add(&fragment)? else {
+            return Ok(None);
+        };
+        if let Some(reliability) = reliability {
+            reliability.mark_delivered(&header, fragment.message);
+        }
+        if header.matches(source, tag) {
+            return Ok(Some((header, payload)));
+        }
+        self.handle_unexpected(header, payload)?;
+        Ok(None)
+    }
+
+    fn send_ack(&self, fragment: &Fragment) -> Result<(), CommError> {
+        let ack = reliable::encode_ack(fragment);
+        let message = self.next_message.fetch_add(1, Ordering::Relaxed);
+        let dest = &self.receiver[fragment.header.source as usize];
+        for datagram in fragment::split(self.rank, ACK_TAG, message, &ack, self.max_datagram_len)? {
+            match self.socket.try_send_to(&datagram, dest) {
+                // Waiting could deadlock with a peer that is blocked sending to us. A lost
+                // acknowledgement only delays the retransmission.
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                result => {
+                    result?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_unexpected(&self, header: Header, payload: Vec<u8>) -> Result<(), CommError> {
+        if header.tag == ACK_TAG {
+            // for a send on another thread, or a late one of a retransmitted fragment
+            if let Some(reliability) = &self.reliability {
+                reliability.queue_ack(header.source, payload);
+            }
+            return Ok(());
+        }
+        match self.barrier.intercept(self.rank, &header, &payload) {
+            Control::Queue => self.unexpected.push(header, payload),
+            Control::Discard => {}
+            Control::Release { dest, epoch } => {
+                barrier::complete_now(self.send_control(dest, RELEASE_TAG, epoch))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: DatagramSocket> Drop for DatagramCommunicator<S> {
+    fn drop(&mut self) {
+        if self.reliability.is_some() {
+            // Acknowledge retransmissions for a while, in case our last acknowledgements were
+            // lost. Nothing ever matches, so this waits for the whole time.
+            let _ = self.receive_matching(self.rank, Some(ACK_TAG), Wait::For(LINGER));
+        }
+        if self.split_off {
+            let _ = self.socket.unbind_split();
+        }
+    }
+}
+
+impl<S: DatagramSocket> ControlChannel for DatagramCommunicator<S> {
+    async fn send_control(&self, dest: u32, tag: u32, epoch: u64) -> Result<(), CommError> {
+        match self.send_frame(&barrier::encode_epoch(epoch), dest, tag) {
+            // A Unix socket fails if the peer has not bound its path yet. The barrier treats
+            // this like a lost datagram and sends again.
+            Err(CommError::Io(e))
+                if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) =>
+            {
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    async fn recv_control(
+        &self,
+        source: u32,
+        tag: u32,
+        timeout: Duration,
+    ) -> Result<Option<u64>, CommError> {
+        let payload = self.receive_matching(source, Some(tag), Wait::For(timeout))?;
+        Ok(payload.and_then(|payload| barrier::decode_epoch(&payload)))
+    }
+}
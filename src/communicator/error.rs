@@ -0,0 +1,61 @@
+use std::fmt;
+use std::io;
+
+/// Error returned by the operations of a [`TestCommunicator`](super::TestCommunicator).
+#[derive(Debug)]
+pub enum CommError {
+    /// The underlying socket or file failed.
+    Io(io::Error),
+    /// The peer went away, e.g. a channel was closed or a connection reset.
+    Disconnected { rank: u32 },
+    /// The message did not fit into the receive buffer.
+    Truncated { len: usize, capacity: usize },
+    /// The operation did not complete in time.
+    Timeout,
+    /// The backend does not implement the operation.
+    Unsupported(&'static str),
+    /// A received message could not be decoded as the expected protobuf message.
+    Decode(prost::DecodeError),
+}
+
+impl fmt::Display for CommError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommError::Io(e) => write!(f, "I/O error: {}", e),
+            CommError::Disconnected { rank } => write!(f, "Rank {} disconnected", rank),
+            CommError::Truncated { len, capacity } => write!(
+                f,
+                "Message of {} bytes truncated to a buffer of {} bytes",
+                len, capacity
+            ),
+            CommError::Timeout => write!(f, "Operation timed out"),
+            CommError::Unsupported(operation) => write!(f, "Unsupported operation: {}", operation),
+            CommError::Decode(e) => write!(f, "Invalid message: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CommError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CommError::Io(e) => Some(e),
+            CommError::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CommError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut => CommError::Timeout,
+            _ => CommError::Io(e),
+        }
+    }
+}
+
+impl From<prost::DecodeError> for CommError {
+    fn from(e: prost::DecodeError) -> Self {
+        CommError::Decode(e)
+    }
+}
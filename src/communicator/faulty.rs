@@ -0,0 +1,433 @@
+use super::matching;
+use super::{CommError, CommRequest, ReduceOp, Reducible, Status, TestCommunicator};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Probabilities of the faults a [`FaultyCommunicator`] injects into every message it sends.
+#[derive(Debug, Clone, Default)]
+pub struct FaultConfig {
+    /// The message is not sent at all.
+    pub drop: f64,
+    /// The message is sent twice.
+    pub duplicate: f64,
+    /// The message is held back and sent after the next message to the same rank, or at the
+    /// next barrier.
+    pub reorder: f64,
+    /// The sender sleeps for up to `max_delay` before the message is sent.
+    pub delay: f64,
+    pub max_delay: Duration,
+    /// A random bit of the message is flipped.
+    pub corrupt: f64,
+    /// Seed of the faults, so that a failing run can be repeated. Every rank draws its own
+    /// faults from it.
+    pub seed: u64,
+}
+
+impl FaultConfig {
+    /// Checks that all probabilities are between 0 and 1.
+    pub fn validate(&self) -> Result<(), CommError> {
+        let probabilities = [
+            ("drop", self.drop),
+            ("duplicate", self.duplicate),
+            ("reorder", self.reorder),
+            ("delay", self.delay),
+            ("corrupt", self.corrupt),
+        ];
+        for (fault, probability) in probabilities {
+            if !(0.0..=1.0).contains(&probability) {
+                return Err(CommError::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Probability {} of {} is not between 0 and 1",
+                        probability, fault
+                    ),
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Wraps any communicator and injects faults into the messages it sends, as configured by a
+/// [`FaultConfig`]. Receives are passed on unchanged, so a rank sees the faults of its peers.
+/// Collective operations are passed on as well, so the wrapped communicator runs them with its
+/// own implementation and without faults, after the messages held back have been sent.
+pub struct FaultyCommunicator<C> {
+    inner: C,
+    config: FaultConfig,
+    rng: Mutex<StdRng>,
+    /// Message held back for each destination to reorder it.
+    held: Mutex<Vec<Option<Held>>>,
+}
+
+struct Held {
+    /// The tag of `send_tagged`, `None` for `send`.
+    tag: Option<u32>,
+    payload: Vec<u8>,
+}
+
+struct Faults {
+    drop: bool,
+    corrupt_bit: Option<usize>,
+    delay: Option<Duration>,
+    duplicate: bool,
+    reorder: bool,
+}
+
+impl<C: TestCommunicator> FaultyCommunicator<C> {
+    /// Fails if a probability of `config` is not between 0 and 1.
+    pub fn new(inner: C, config: FaultConfig) -> Result<Self, CommError> {
+        config.validate()?;
+        let rng = StdRng::seed_from_u64(config.seed.wrapping_add(inner.rank() as u64));
+        let held = (0..inner.size()).map(|_| None).collect();
+        Ok(FaultyCommunicator {
+            inner,
+            config,
+            rng: Mutex::new(rng),
+            held: Mutex::new(held),
+        })
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Returns the wrapped communicator. Messages that are still held back are dropped.
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+
+    /// Draws the faults of a message of `len` bytes.
+    fn roll(&self, len: usize) -> Faults {
+        let config = &self.config;
+        let mut rng = self.rng.lock().unwrap();
+        // the probabilities have been validated by `new`
+        let mut happens = |probability: f64| rng.random_bool(probability);
+        let drop = happens(config.drop);
+        let corrupt = happens(config.corrupt) && len > 0;
+        let delay = happens(config.delay);
+        let duplicate = happens(config.duplicate);
+        let reorder = happens(config.reorder);
+        Faults {
+            drop,
+            corrupt_bit: corrupt.then(|| rng.random_range(0..len * 8)),
+            delay: delay.then(|| config.max_delay.mul_f64(rng.random::<f64>())),
+            duplicate,
+            reorder,
+        }
+    }
+
+    fn send_faulty(&self, buffer: &[u8], dest: u32, tag: Option<u32>) -> Result<(), CommError> {
+        let faults = self.roll(buffer.len());
+        if faults.drop {
+            return Ok(());
+        }
+        let mut message = buffer.to_vec();
+        if let Some(bit) = faults.corrupt_bit {
+            message[bit / 8] ^= 1 << (bit % 8);
+        }
+        if let Some(delay) = faults.delay {
+            std::thread::sleep(delay);
+        }
+        let held = {
+            let mut held = self.held.lock().unwrap();
+            let slot = &mut held[dest as usize];
+            if faults.reorder && slot.is_none() {
+                *slot = Some(Held {
+                    tag,
+                    payload: message,
+                });
+                return Ok(());
+            }
+            slot.take()
+        };
+        self.send_inner(&message, dest, tag)?;
+        if faults.duplicate {
+            self.send_inner(&message, dest, tag)?;
+        }
+        if let Some(held) = held {
+            self.send_inner(&held.payload, dest, held.tag)?;
+        }
+        Ok(())
+    }
+
+    fn send_inner(&self, buffer: &[u8], dest: u32, tag: Option<u32>) -> Result<(), CommError> {
+        match tag {
+            Some(tag) => self.inner.send_tagged(buffer, dest, tag),
+            None => self.inner.send(buffer, dest),
+        }
+    }
+
+    /// Sends the messages held back for reordering.
+    fn flush(&self) -> Result<(), CommError> {
+        let held: Vec<_> = self
+            .held
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .map(Option::take)
+            .collect();
+        for (dest, held) in held.into_iter().enumerate() {
+            if let Some(held) = held {
+                self.send_inner(&held.payload, dest as u32, held.tag)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<C: TestCommunicator> TestCommunicator for FaultyCommunicator<C> {
+    fn rank(&self) -> u32 {
+        self.inner.rank()
+    }
+
+    fn size(&self) -> u32 {
+        self.inner.size()
+    }
+
+    fn send(&self, buffer: &[u8], dest: u32) -> Result<(), CommError> {
+        self.send_faulty(buffer, dest, None)
+    }
+
+    fn recv(&self, buffer: &mut [u8], source: u32) -> Result<usize, CommError> {
+        self.inner.recv(buffer, source)
+    }
+
+    fn barrier(&self) -> Result<(), CommError> {
+        self.flush()?;
+        self.inner.barrier()
+    }
+
+    fn isend(&self, buffer: Vec<u8>, dest: u32) -> Result<CommRequest<'_>, CommError> {
+        // the faults are applied right away, so the send completes eagerly
+        self.send(&buffer, dest)?;
+        Ok(CommRequest::completed(buffer))
+    }
+
+    fn irecv(&self, buffer: Vec<u8>, source: u32) -> Result<CommRequest<'_>, CommError> {
+        self.inner.irecv(buffer, source)
+    }
+
+    fn send_tagged(&self, buffer: &[u8], dest: u32, tag: u32) -> Result<(), CommError> {
+        matching::check_tag(tag)?;
+        self.send_faulty(buffer, dest, Some(tag))
+    }
+
+    fn recv_tagged(&self, buffer: &mut [u8], source: u32, tag: u32) -> Result<usize, CommError> {
+        self.inner.recv_tagged(buffer, source, tag)
+    }
+
+    fn probe(&self, source: u32) -> Result<Status, CommError> {
+        self.inner.probe(source)
+    }
+
+    fn recv_vec(&self, source: u32) -> Result<Vec<u8>, CommError> {
+        self.inner.recv_vec(source)
+    }
+
+    fn recv_timeout(
+        &self,
+        buffer: &mut [u8],
+        source: u32,
+        timeout: Duration,
+    ) -> Result<usize, CommError> {
+        self.inner.recv_timeout(buffer, source, timeout)
+    }
+
+    fn set_recv_timeout(&self, timeout: Option<Duration>) -> Result<(), CommError> {
+        self.inner.set_recv_timeout(timeout)
+    }
+
+    fn broadcast(&self, buffer: &mut [u8], root: u32) -> Result<(), CommError> {
+        self.flush()?;
+        self.inner.broadcast(buffer, root)
+    }
+
+    fn reduce<T: Reducible>(
+        &self,
+        send: &[T],
+        recv: &mut [T],
+        op: ReduceOp,
+        root: u32,
+    ) -> Result<(), CommError> {
+        self.flush()?;
+        self.inner.reduce(send, recv, op, root)
+    }
+
+    fn allreduce<T: Reducible>(
+        &self,
+        send: &[T],
+        recv: &mut [T],
+        op: ReduceOp,
+    ) -> Result<(), CommError> {
+        self.flush()?;
+        self.inner.allreduce(send, recv, op)
+    }
+
+    fn gather(&self, send: &[u8], recv: &mut [u8], root: u32) -> Result<(), CommError> {
+        self.flush()?;
+        self.inner.gather(send, recv, root)
+    }
+
+    fn allgather(&self, send: &[u8], recv: &mut [u8]) -> Result<(), CommError> {
+        self.flush()?;
+        self.inner.allgather(send, recv)
+    }
+
+    fn scatter(&self, send: &[u8], recv: &mut [u8], root: u32) -> Result<(), CommError> {
+        self.flush()?;
+        self.inner.scatter(send, recv, root)
+    }
+
+    fn alltoall(&self, send: &[u8], recv: &mut [u8]) -> Result<(), CommError> {
+        self.flush()?;
+        self.inner.alltoall(send, recv)
+    }
+
+    fn alltoallv(
+        &self,
+        send: &[u8],
+        send_counts: &[usize],
+        recv: &mut [u8],
+        recv_counts: &[usize],
+    ) -> Result<(), CommError> {
+        self.flush()?;
+        self.inner.alltoallv(send, send_counts, recv, recv_counts)
+    }
+
+    /// The new communicators inject faults with the same probabilities, drawn from a seed that
+    /// also depends on their color, so that they do not repeat the faults of each other.
+    fn split(&self, color: Option<u32>, key: u32) -> Result<Option<Self>, CommError> {
+        // the split itself is not subject to faults, the new communicators are
+        self.flush()?;
+        let (Some(color), Some(inner)) = (color, self.inner.split(color, key)?) else {
+            return Ok(None);
+        };
+        let mut config = self.config.clone();
+        // colors are spread over the seed, so that neighbouring colors and ranks do not share
+        // seeds, like `seed + rank` would
+        config.seed ^= (color as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        FaultyCommunicator::new(inner, config).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communicator::ChannelSimCommunicator;
+    use std::thread;
+
+    /// Rank 0 of a fresh pair injecting the faults of `config`, and rank 1.
+    fn faulty_pair(
+        config: FaultConfig,
+    ) -> (
+        FaultyCommunicator<ChannelSimCommunicator>,
+        ChannelSimCommunicator,
+    ) {
+        let mut comms = ChannelSimCommunicator::create(2, None);
+        let peer = comms.pop().unwrap();
+        let comm = FaultyCommunicator::new(comms.pop().unwrap(), config).unwrap();
+        (comm, peer)
+    }
+
+    /// The messages from rank 0 that have arrived at `peer`, in order.
+    fn received(peer: &ChannelSimCommunicator) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        let mut buffer = [0; 16];
+        while let Ok(len) = peer.recv_timeout(&mut buffer, 0, Duration::from_millis(10)) {
+            messages.push(buffer[..len].to_vec());
+        }
+        messages
+    }
+
+    #[test]
+    fn drops_and_duplicates_messages() {
+        let (comm, peer) = faulty_pair(FaultConfig {
+            drop: 1.0,
+            ..FaultConfig::default()
+        });
+        comm.send(&[1], 1).unwrap();
+        assert!(received(&peer).is_empty());
+
+        let (comm, peer) = faulty_pair(FaultConfig {
+            duplicate: 1.0,
+            ..FaultConfig::default()
+        });
+        comm.send(&[1], 1).unwrap();
+        comm.send_tagged(&[2], 1, 5).unwrap();
+        assert_eq!(received(&peer), [[1], [1], [2], [2]]);
+    }
+
+    #[test]
+    fn reorders_messages_with_the_next_one() {
+        let (comm, peer) = faulty_pair(FaultConfig {
+            reorder: 1.0,
+            ..FaultConfig::default()
+        });
+        for message in 1..=3 {
+            comm.send(&[message], 1).unwrap();
+        }
+        // the third message waits for the next one
+        assert_eq!(received(&peer), [[2], [1]]);
+    }
+
+    #[test]
+    fn barrier_sends_held_messages() {
+        let (comm, peer) = faulty_pair(FaultConfig {
+            reorder: 1.0,
+            ..FaultConfig::default()
+        });
+        comm.send(&[1], 1).unwrap();
+        assert!(received(&peer).is_empty());
+        thread::scope(|scope| {
+            scope.spawn(|| comm.barrier().unwrap());
+            peer.barrier().unwrap();
+        });
+        assert_eq!(received(&peer), [[1]]);
+    }
+
+    #[test]
+    fn faults_repeat_with_the_same_seed() {
+        let config = FaultConfig {
+            drop: 0.5,
+            seed: 42,
+            ..FaultConfig::default()
+        };
+        let run = || {
+            let (comm, peer) = faulty_pair(config.clone());
+            for message in 0..32 {
+                comm.send(&[message], 1).unwrap();
+            }
+            received(&peer)
+        };
+        let delivered = run();
+        assert!(!delivered.is_empty() && delivered.len() < 32);
+        assert_eq!(run(), delivered);
+    }
+
+    #[test]
+    fn rejects_probabilities_outside_of_zero_to_one() {
+        for probability in [-0.1, 1.5, f64::NAN] {
+            let config = FaultConfig {
+                reorder: probability,
+                ..FaultConfig::default()
+            };
+            assert!(config.validate().is_err());
+        }
+        let config = FaultConfig {
+            drop: 0.0,
+            corrupt: 1.0,
+            ..FaultConfig::default()
+        };
+        assert!(config.validate().is_ok());
+        let comm = ChannelSimCommunicator::create(1, None).pop().unwrap();
+        let config = FaultConfig {
+            delay: 2.0,
+            ..FaultConfig::default()
+        };
+        assert!(FaultyCommunicator::new(comm, config).is_err());
+    }
+}
@@ -0,0 +1,380 @@
+use super::matching::{invalid_data, Header, DEFAULT_MAX_MESSAGE_LEN, HEADER_LEN};
+use super::CommError;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::os::fd::AsFd;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Size of the fragment header that follows the message header in every datagram.
+pub(crate) const FRAGMENT_HEADER_LEN: usize = 8;
+
+/// Smallest datagram that still fits the 8 byte payload of a control message, which is never
+/// fragmented.
+pub(crate) const MIN_DATAGRAM_LEN: usize = HEADER_LEN + FRAGMENT_HEADER_LEN + 8;
+
+/// Receive buffer requested for datagram sockets, as all fragments of a large message arrive
+/// in one burst. The kernel caps it at `net.core.rmem_max`.
+const RECV_BUFFER_SIZE: usize = 8 << 20;
+
+/// Most fragments a message is split into. Together with the fragment length it bounds the
+/// length a fragment may claim for its message.
+pub(crate) const MAX_FRAGMENTS: usize = 1 << 20;
+
+/// How long a message that has not received all its fragments is kept after the last one
+/// arrived. Its other fragments are most likely lost.
+const PARTIAL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Messages from one rank that are put together at the same time. Starting another one drops
+/// the one that got a fragment the longest time ago.
+const MAX_PARTIAL_PER_SOURCE: usize = 64;
+
+pub(crate) fn enlarge_recv_buffer(socket: &impl AsFd) -> io::Result<()> {
+    socket2::SockRef::from(socket).set_recv_buffer_size(RECV_BUFFER_SIZE)
+}
+
+/// Splits a message into datagrams of at most `max_datagram_len` bytes. Each one carries the
+/// header of the whole message, followed by the id of the message and the offset of its chunk.
+/// An empty message still takes one datagram. Fails if the message needs more than
+/// [`MAX_FRAGMENTS`] datagrams.
+pub(crate) fn split<'a>(
+    source: u32,
+    tag: u32,
+    message: u32,
+    payload: &'a [u8],
+    max_datagram_len: usize,
+) -> Result<impl Iterator<Item = Vec<u8>> + 'a, CommError> {
+    let chunk_len = max_datagram_len - HEADER_LEN - FRAGMENT_HEADER_LEN;
+    let chunks = payload.len().div_ceil(chunk_len).max(1);
+    if chunks > MAX_FRAGMENTS || u32::try_from(payload.len()).is_err() {
+        return Err(CommError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Message of {} bytes does not fit into {} datagrams of {} bytes",
+                payload.len(),
+                MAX_FRAGMENTS,
+                max_datagram_len
+            ),
+        )));
+    }
+    let header = Header {
+        source,
+        tag,
+        len: payload.len() as u32,
+    };
+    Ok((0..chunks).map(move |i| {
+        let offset = i * chunk_len;
+        let chunk = &payload[offset..payload.len().min(offset + chunk_len)];
+        let mut datagram = Vec::with_capacity(HEADER_LEN + FRAGMENT_HEADER_LEN + chunk.len());
+        header.encode(&mut datagram);
+        datagram.extend_from_slice(&message.to_le_bytes());
+        datagram.extend_from_slice(&(offset as u32).to_le_bytes());
+        datagram.extend_from_slice(chunk);
+        datagram
+    }))
+}
+
+/// Datagram produced by [`split`].
+pub(crate) struct Fragment<'a> {
+    /// Header of the whole message.
+    pub header: Header,
+    /// Id of the message, unique per sender for all messages in flight.
+    pub message: u32,
+    /// Position of the chunk in the message.
+    pub offset: u32,
+    pub chunk: &'a [u8],
+}
+
+impl<'a> Fragment<'a> {
+    pub fn decode(datagram: &'a [u8]) -> Result<Self, CommError> {
+        let header = Header::decode(datagram)?;
+        if datagram.len() < HEADER_LEN + FRAGMENT_HEADER_LEN {
+            return Err(invalid_data(format!(
+                "Fragment of {} bytes is too short for its header",
+                datagram.len()
+            )));
+        }
+        let field = |i: usize| u32::from_le_bytes(datagram[i..i + 4].try_into().unwrap());
+        let fragment = Fragment {
+            header,
+            message: field(HEADER_LEN),
+            offset: field(HEADER_LEN + 4),
+            chunk: &datagram[HEADER_LEN + FRAGMENT_HEADER_LEN..],
+        };
+        if fragment.end() > header.len as usize {
+            return Err(invalid_data(format!(
+                "Fragment ends at {} beyond the message length of {}",
+                fragment.end(),
+                header.len
+            )));
+        }
+        Ok(fragment)
+    }
+
+    fn end(&self) -> usize {
+        self.offset as usize + self.chunk.len()
+    }
+}
+
+/// Message that has not received all its fragments yet.
+struct Partial {
+    payload: Vec<u8>,
+    /// Offsets of the received chunks, so that duplicates are not counted twice.
+    offsets: HashSet<u32>,
+    received: usize,
+    /// When the last fragment arrived.
+    updated: Instant,
+}
+
+/// Puts the fragments of [`split`] back together. Fragments of different messages may
+/// interleave. Messages whose other fragments do not arrive in time are dropped, so that lost
+/// datagrams do not pile up.
+pub(crate) struct Reassembler {
+    partial: Mutex<HashMap<(u32, u32), Partial>>,
+    max_message_len: usize,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Reassembler::new(DEFAULT_MAX_MESSAGE_LEN)
+    }
+}
+
+impl Reassembler {
+    /// Rejects fragments of messages longer than `max_message_len`, so that a corrupt length
+    /// cannot make it allocate arbitrary amounts of memory.
+    pub fn new(max_message_len: usize) -> Self {
+        Reassembler {
+            partial: Mutex::default(),
+            max_message_len,
+        }
+    }
+
+    pub fn max_message_len(&self) -> usize {
+        self.max_message_len
+    }
+
+    /// Returns the header and payload of the message `fragment` belongs to once it is complete.
+    pub fn add(&self, fragment: &Fragment) -> Result<Option<(Header, Vec<u8>)>, CommError> {
+        let Fragment {
+            header,
+            message,
+            offset,
+            chunk,
+        } = *fragment;
+        self.check_len(fragment)?;
+        // the common case of a message in a single datagram skips the bookkeeping
+        if offset == 0 && chunk.len() == header.len as usize {
+            return Ok(Some((header, chunk.to_vec())));
+        }
+
+        let mut partial = self.partial.lock().unwrap();
+        let now = Instant::now();
+        partial.retain(|_, entry| now.duration_since(entry.updated) < PARTIAL_TIMEOUT);
+        let key = (header.source, message);
+        if !partial.contains_key(&key) {
+            evict_oldest(&mut partial, header.source);
+        }
+        let entry = partial.entry(key).or_insert_with(|| Partial {
+            payload: vec![0; header.len as usize],
+            offsets: HashSet::new(),
+            received: 0,
+            updated: now,
+        });
+        entry.updated = now;
+        if entry.payload.len() != header.len as usize {
+            return Err(invalid_data(format!(
+                "Fragments of message {} from rank {} disagree on its length",
+                message, header.source
+            )));
+        }
+        if entry.offsets.insert(offset) {
+            entry.payload[offset as usize..fragment.end()].copy_from_slice(chunk);
+            entry.received += chunk.len();
+        }
+        if entry.received < entry.payload.len() {
+            return Ok(None);
+        }
+        let complete = partial.remove(&key).unwrap();
+        Ok(Some((header, complete.payload)))
+    }
+
+    /// Checks the length of the message of `fragment` against the configured maximum, and
+    /// against the most a message split into fragments of its length can have.
+    fn check_len(&self, fragment: &Fragment) -> Result<(), CommError> {
+        let len = fragment.header.len as usize;
+        if len > self.max_message_len {
+            return Err(invalid_data(format!(
+                "Message {} from rank {} of {} bytes is longer than the maximum of {}",
+                fragment.message, fragment.header.source, len, self.max_message_len
+            )));
+        }
+        // all fragments but the last one of a message are equally long
+        let is_last = fragment.end() == len;
+        if !is_last && len.div_ceil(fragment.chunk.len().max(1)) > MAX_FRAGMENTS {
+            return Err(invalid_data(format!(
+                "Message {} from rank {} of {} bytes would need more than {} fragments of {} bytes",
+                fragment.message,
+                fragment.header.source,
+                len,
+                MAX_FRAGMENTS,
+                fragment.chunk.len()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Makes room for another message from `source` if it has the most partial messages already.
+fn evict_oldest(partial: &mut HashMap<(u32, u32), Partial>, source: u32) {
+    let of_source = || partial.iter().filter(|((from, _), _)| *from == source);
+    if of_source().count() < MAX_PARTIAL_PER_SOURCE {
+        return;
+    }
+    let oldest = of_source()
+        .min_by_key(|(_, entry)| entry.updated)
+        .map(|(&key, _)| key);
+    if let Some(oldest) = oldest {
+        partial.remove(&oldest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Datagrams of `payload` with `chunk_len` bytes of it each.
+    fn fragments(message: u32, payload: &[u8], chunk_len: usize) -> Vec<Vec<u8>> {
+        let max_datagram_len = HEADER_LEN + FRAGMENT_HEADER_LEN + chunk_len;
+        split(3, 7, message, payload, max_datagram_len)
+            .unwrap()
+            .collect()
+    }
+
+    fn add(reassembler: &Reassembler, datagram: &[u8]) -> Option<(Header, Vec<u8>)> {
+        reassembler
+            .add(&Fragment::decode(datagram).unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn split_covers_the_payload() {
+        let payload: Vec<u8> = (0..=255).collect();
+        let datagrams = fragments(1, &payload, 100);
+        assert_eq!(datagrams.len(), 3);
+        let mut offsets = Vec::new();
+        for datagram in &datagrams {
+            let fragment = Fragment::decode(datagram).unwrap();
+            let header = Header {
+                source: 3,
+                tag: 7,
+                len: 256,
+            };
+            assert_eq!(fragment.header, header);
+            assert_eq!(fragment.message, 1);
+            let offset = fragment.offset as usize;
+            assert_eq!(fragment.chunk, &payload[offset..fragment.end()]);
+            offsets.push(offset);
+        }
+        assert_eq!(offsets, [0, 100, 200]);
+        assert_eq!(fragments(1, &[], 100).len(), 1);
+    }
+
+    #[test]
+    fn split_rejects_too_many_fragments() {
+        let payload = vec![0; MAX_FRAGMENTS + 1];
+        let max_datagram_len = HEADER_LEN + FRAGMENT_HEADER_LEN + 1;
+        assert!(split(0, 0, 0, &payload, max_datagram_len).is_err());
+        assert!(split(0, 0, 0, &payload[1..], max_datagram_len).is_ok());
+    }
+
+    #[test]
+    fn reassembles_interleaved_and_duplicate_fragments() {
+        let reassembler = Reassembler::default();
+        let first: Vec<u8> = (0..250).collect();
+        let second = vec![9; 150];
+        let mut datagrams: Vec<_> = fragments(1, &first, 100).into_iter().rev().collect();
+        datagrams.insert(1, fragments(2, &second, 100)[0].clone());
+        datagrams.insert(2, datagrams[0].clone());
+
+        let mut complete = Vec::new();
+        for datagram in &datagrams {
+            complete.extend(add(&reassembler, datagram));
+        }
+        assert_eq!(complete.len(), 1);
+        assert_eq!(complete[0].1, first);
+        let (header, payload) = add(&reassembler, &fragments(2, &second, 100)[1]).unwrap();
+        assert_eq!((header.len, payload), (150, second));
+    }
+
+    #[test]
+    fn rejects_messages_beyond_the_maximum_length() {
+        let reassembler = Reassembler::new(100);
+        let short = fragments(1, &[1; 100], 60);
+        assert!(add(&reassembler, &short[0]).is_none());
+        assert!(add(&reassembler, &short[1]).is_some());
+
+        let long = fragments(2, &[1; 101], 60);
+        assert!(reassembler
+            .add(&Fragment::decode(&long[0]).unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_lengths_that_need_too_many_fragments() {
+        let reassembler = Reassembler::default();
+        let len = (MAX_FRAGMENTS + 1) as u32;
+        let mut datagram = Vec::new();
+        Header {
+            source: 0,
+            tag: 0,
+            len,
+        }
+        .encode(&mut datagram);
+        datagram.extend_from_slice(&0u32.to_le_bytes());
+        datagram.extend_from_slice(&0u32.to_le_bytes());
+        datagram.push(0);
+        assert!(reassembler
+            .add(&Fragment::decode(&datagram).unwrap())
+            .is_err());
+        // the last fragment says nothing about the length of the others
+        datagram[HEADER_LEN + 4..HEADER_LEN + 8].copy_from_slice(&(len - 1).to_le_bytes());
+        assert!(reassembler
+            .add(&Fragment::decode(&datagram).unwrap())
+            .is_ok());
+    }
+
+    #[test]
+    fn evicts_the_oldest_partial_message_of_a_source() {
+        let reassembler = Reassembler::default();
+        let payload = [1; 20];
+        let other_source = split(4, 7, 0, &payload, HEADER_LEN + FRAGMENT_HEADER_LEN + 10)
+            .unwrap()
+            .next()
+            .unwrap();
+        assert!(add(&reassembler, &other_source).is_none());
+        for message in 0..=MAX_PARTIAL_PER_SOURCE as u32 {
+            assert!(add(&reassembler, &fragments(message, &payload, 10)[0]).is_none());
+            // the order of the partial messages is told by the time of their last fragment
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let partial = reassembler.partial.lock().unwrap();
+        assert_eq!(partial.len(), MAX_PARTIAL_PER_SOURCE + 1);
+        assert!(!partial.contains_key(&(3, 0)));
+        assert!(partial.contains_key(&(3, 1)));
+        assert!(partial.contains_key(&(4, 0)));
+    }
+
+    #[test]
+    fn drops_stale_partial_messages() {
+        let reassembler = Reassembler::default();
+        let datagrams = fragments(1, &[1; 20], 10);
+        assert!(add(&reassembler, &datagrams[0]).is_none());
+        for entry in reassembler.partial.lock().unwrap().values_mut() {
+            entry.updated -= PARTIAL_TIMEOUT;
+        }
+        // the first half is gone, so the second one starts over
+        assert!(add(&reassembler, &datagrams[1]).is_none());
+        assert!(add(&reassembler, &datagrams[0]).is_some());
+    }
+}
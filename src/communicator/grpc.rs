@@ -0,0 +1,506 @@
+use super::address::AddressBook;
+use super::barrier;
+use super::matching::{self, Header, Mailbox, RecvTimeout, DEFAULT_TAG};
+use super::rendezvous;
+use super::runtime::{self, CommRuntime};
+use super::stream::CONNECT_TIMEOUT;
+use super::{
+    AsyncTestCommunicator, CommError, CommRequest, PendingRequest, Status, TestCommunicator,
+};
+use crate::proto::events::comm_service_client::CommServiceClient;
+use crate::proto::events::comm_service_server::{CommService, CommServiceServer};
+use crate::proto::events::{Delivered, Frame};
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, TcpListener};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::metadata::MetadataValue;
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::{Channel, Endpoint, Server};
+use tonic::{Request, Response, Streaming};
+
+/// Metadata key of a `Deliver` call that carries the sending rank.
+const RANK_KEY: &str = "rank";
+
+/// Messages queued per stream before `send` waits for the stream to catch up. Applies to the
+/// received messages as well, which then push back on the sender through HTTP/2 flow control.
+const QUEUE_LEN: usize = 16;
+
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long a dropped communicator waits for its streams to hand over the queued messages.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Communicator on gRPC. Every rank hosts a `CommService` server and opens a client streaming
+/// `Deliver` call to every rank, itself included, which carries all its messages to that rank.
+pub struct GrpcCommunicator {
+    rank: u32,
+    outgoing: Vec<mpsc::Sender<Frame>>,
+    streams: Vec<JoinHandle<Result<(), tonic::Status>>>,
+    incoming: tokio::sync::Mutex<Incoming>,
+    unexpected: Mailbox,
+    recv_timeout: RecvTimeout,
+    server: JoinHandle<Result<(), tonic::transport::Error>>,
+    // dropped last
+    runtime: CommRuntime,
+}
+
+/// What the server hands to the communicator.
+enum Delivery {
+    Message(Header, Vec<u8>),
+    /// The stream from a rank ended, no more messages follow.
+    Closed(u32),
+}
+
+struct Incoming {
+    deliveries: mpsc::Receiver<Delivery>,
+    closed: Vec<bool>,
+}
+
+struct Inbox {
+    deliveries: mpsc::Sender<Delivery>,
+}
+
+#[tonic::async_trait]
+impl CommService for Inbox {
+    async fn deliver(
+        &self,
+        request: Request<Streaming<Frame>>,
+    ) -> Result<Response<Delivered>, tonic::Status> {
+        let source = request
+            .metadata()
+            .get(RANK_KEY)
+            .and_then(|rank| rank.to_str().ok()?.parse().ok())
+            .ok_or_else(|| tonic::Status::invalid_argument("missing rank of the sender"))?;
+        let mut frames = request.into_inner();
+        let result = async {
+            while let Some(Frame { tag, payload }) = frames.message().await? {
+                let header = Header {
+                    source,
+                    tag,
+                    len: payload.len() as u32,
+                };
+                if self
+                    .deliveries
+                    .send(Delivery::Message(header, payload))
+                    .await
+                    .is_err()
+                {
+                    // the communicator is gone
+                    break;
+                }
+            }
+            Ok(Response::new(Delivered {}))
+        }
+        .await;
+        let _ = self.deliveries.send(Delivery::Closed(source)).await;
+        result
+    }
+}
+
+impl TestCommunicator for GrpcCommunicator {
+    fn rank(&self) -> u32 {
+        self.rank
+    }
+
+    fn size(&self) -> u32 {
+        self.outgoing.len() as u32
+    }
+
+    fn send(&self, buffer: &[u8], dest: u32) -> Result<(), CommError> {
+        self.runtime.block_on(self.send(buffer, dest, DEFAULT_TAG))
+    }
+
+    fn recv(&self, buffer: &mut [u8], source: u32) -> Result<usize, CommError> {
+        self.runtime.block_on(self.recv(buffer, source, None))
+    }
+
+    fn barrier(&self) -> Result<(), CommError> {
+        self.runtime.block_on(self.barrier())
+    }
+
+    fn isend(&self, buffer: Vec<u8>, dest: u32) -> Result<CommRequest<'_>, CommError> {
+        // the message is queued for the stream, so the send completes eagerly unless it is full
+        TestCommunicator::send(self, &buffer, dest)?;
+        Ok(CommRequest::completed(buffer))
+    }
+
+    fn irecv(&self, buffer: Vec<u8>, source: u32) -> Result<CommRequest<'_>, CommError> {
+        Ok(CommRequest::new(GrpcRecvRequest {
+            comm: self,
+            buffer,
+            source,
+            payload: None,
+        }))
+    }
+
+    fn send_tagged(&self, buffer: &[u8], dest: u32, tag: u32) -> Result<(), CommError> {
+        matching::check_tag(tag)?;
+        self.runtime.block_on(self.send(buffer, dest, tag))
+    }
+
+    fn recv_tagged(&self, buffer: &mut [u8], source: u32, tag: u32) -> Result<usize, CommError> {
+        self.runtime.block_on(self.recv(buffer, source, Some(tag)))
+    }
+
+    fn probe(&self, source: u32) -> Result<Status, CommError> {
+        self.runtime.block_on(async {
+            let receive = self.receive_message(source, None);
+            let (header, payload) = runtime::with_timeout(self.recv_timeout.get(), receive).await?;
+            Ok(self.unexpected.put_back(header, payload))
+        })
+    }
+
+    fn recv_vec(&self, source: u32) -> Result<Vec<u8>, CommError> {
+        let receive = self.receive_matching(source, None);
+        (self.runtime).block_on(runtime::with_timeout(self.recv_timeout.get(), receive))
+    }
+
+    fn recv_timeout(
+        &self,
+        buffer: &mut [u8],
+        source: u32,
+        timeout: Duration,
+    ) -> Result<usize, CommError> {
+        self.runtime.block_on(async {
+            let receive = self.receive_matching(source, None);
+            let payload = runtime::with_timeout(Some(timeout), receive).await?;
+            matching::copy_payload(&payload, buffer)
+        })
+    }
+
+    fn set_recv_timeout(&self, timeout: Option<Duration>) -> Result<(), CommError> {
+        self.recv_timeout.set(timeout);
+        Ok(())
+    }
+}
+
+impl AsyncTestCommunicator for GrpcCommunicator {
+    fn rank(&self) -> u32 {
+        self.rank
+    }
+
+    fn size(&self) -> u32 {
+        self.outgoing.len() as u32
+    }
+
+    async fn send(&self, buffer: &[u8], dest: u32) -> Result<(), CommError> {
+        self.runtime.check_ambient()?;
+        self.send(buffer, dest, DEFAULT_TAG).await
+    }
+
+    async fn recv(&self, buffer: &mut [u8], source: u32) -> Result<usize, CommError> {
+        self.runtime.check_ambient()?;
+        self.recv(buffer, source, None).await
+    }
+
+    async fn barrier(&self) -> Result<(), CommError> {
+        self.runtime.check_ambient()?;
+        self.barrier().await
+    }
+
+    async fn send_tagged(&self, buffer: &[u8], dest: u32, tag: u32) -> Result<(), CommError> {
+        self.runtime.check_ambient()?;
+        matching::check_tag(tag)?;
+        self.send(buffer, dest, tag).await
+    }
+
+    async fn recv_tagged(
+        &self,
+        buffer: &mut [u8],
+        source: u32,
+        tag: u32,
+    ) -> Result<usize, CommError> {
+        self.runtime.check_ambient()?;
+        self.recv(buffer, source, Some(tag)).await
+    }
+}
+
+struct GrpcRecvRequest<'a> {
+    comm: &'a GrpcCommunicator,
+    buffer: Vec<u8>,
+    source: u32,
+    payload: Option<Vec<u8>>,
+}
+
+impl PendingRequest for GrpcRecvRequest<'_> {
+    fn test(&mut self) -> Result<bool, CommError> {
+        if self.payload.is_some() {
+            return Ok(true);
+        }
+        // receiving from the channel is cancel safe, like the socket of the UDP variant
+        let comm = self.comm;
+        let source = self.source;
+        self.payload = comm.runtime.block_on(async {
+            tokio::select! {
+                biased;
+                payload = comm.receive_matching(source, None) => payload.map(Some),
+                _ = tokio::task::yield_now() => Ok(None),
+            }
+        })?;
+        Ok(self.payload.is_some())
+    }
+
+    fn wait(mut self: Box<Self>) -> Result<Vec<u8>, CommError> {
+        let payload = match self.payload.take() {
+            Some(payload) => payload,
+            None => {
+                let receive = self.comm.receive_matching(self.source, None);
+                let timeout = self.comm.recv_timeout.get();
+                (self.comm.runtime).block_on(runtime::with_timeout(timeout, receive))?
+            }
+        };
+        matching::fill_buffer(&mut self.buffer, &payload)?;
+        Ok(self.buffer)
+    }
+}
+
+impl GrpcCommunicator {
+    pub fn create_n_2_n(n: u32, rank: u32) -> Result<GrpcCommunicator, CommError> {
+        Self::create_with_addresses(&AddressBook::localhost(n)?, rank)
+    }
+
+    /// Like `create_n_2_n`, with the ranks at the addresses of `addresses`.
+    pub fn create_with_addresses(
+        addresses: &AddressBook,
+        rank: u32,
+    ) -> Result<GrpcCommunicator, CommError> {
+        let listener = TcpListener::bind(addresses.bind_address(rank))?;
+        Self::from_listener(listener, addresses, rank)
+    }
+
+    /// Registers with the [`Coordinator`](super::Coordinator) at `coordinator` and connects to
+    /// the other ranks of a job of `world_size` ranks. The rank is assigned by the coordinator.
+    pub fn connect(
+        coordinator: SocketAddr,
+        world_size: u32,
+    ) -> Result<GrpcCommunicator, CommError> {
+        let listener = TcpListener::bind(rendezvous::any_address(coordinator))?;
+        let port = listener.local_addr()?.port();
+        let (rank, addresses) = rendezvous::register(coordinator, world_size, port)?;
+        Self::from_listener(listener, &addresses, rank)
+    }
+
+    /// Like `create_n_2_n`, for the [`AsyncTestCommunicator`] API in the runtime of the caller.
+    pub async fn create_n_2_n_async(n: u32, rank: u32) -> Result<GrpcCommunicator, CommError> {
+        Self::create_with_addresses_async(&AddressBook::localhost(n)?, rank).await
+    }
+
+    /// Like `create_with_addresses`, for the [`AsyncTestCommunicator`] API in the runtime of the
+    /// caller.
+    pub async fn create_with_addresses_async(
+        addresses: &AddressBook,
+        rank: u32,
+    ) -> Result<GrpcCommunicator, CommError> {
+        let listener = TcpListener::bind(addresses.bind_address(rank))?;
+        Self::start(listener, addresses, rank).await
+    }
+
+    fn from_listener(
+        listener: TcpListener,
+        addresses: &AddressBook,
+        rank: u32,
+    ) -> Result<GrpcCommunicator, CommError> {
+        // the streams make progress while the caller is outside of the runtime
+        let runtime = CommRuntime::multi_thread(2)?;
+        let mut comm = runtime.block_on(Self::start(listener, addresses, rank))?;
+        comm.runtime = runtime;
+        Ok(comm)
+    }
+
+    /// Serves on `listener` and opens a stream to every rank of `addresses`, both as tasks of
+    /// the current runtime.
+    async fn start(
+        listener: TcpListener,
+        addresses: &AddressBook,
+        rank: u32,
+    ) -> Result<GrpcCommunicator, CommError> {
+        let (deliveries, receiver) = mpsc::channel(QUEUE_LEN);
+        let service =
+            CommServiceServer::new(Inbox { deliveries }).max_decoding_message_size(usize::MAX);
+        listener.set_nonblocking(true)?;
+        let incoming = TcpIncoming::from(tokio::net::TcpListener::from_std(listener)?)
+            .with_nodelay(Some(true));
+        let server = tokio::spawn(
+            Server::builder()
+                .http2_adaptive_window(Some(true))
+                .add_service(service)
+                .serve_with_incoming(incoming),
+        );
+
+        let mut outgoing = Vec::new();
+        let mut streams = Vec::new();
+        for &address in addresses.addresses() {
+            let mut client = connect_client(address).await?;
+            let (sender, receiver) = mpsc::channel(QUEUE_LEN);
+            let mut request = Request::new(ReceiverStream::new(receiver));
+            request
+                .metadata_mut()
+                .insert(RANK_KEY, MetadataValue::from(rank));
+            streams.push(tokio::spawn(async move {
+                client.deliver(request).await?;
+                Ok(())
+            }));
+            outgoing.push(sender);
+        }
+
+        Ok(GrpcCommunicator {
+            rank,
+            outgoing,
+            streams,
+            incoming: tokio::sync::Mutex::new(Incoming {
+                deliveries: receiver,
+                closed: vec![false; addresses.len() as usize],
+            }),
+            unexpected: Mailbox::default(),
+            recv_timeout: RecvTimeout::default(),
+            server,
+            runtime: CommRuntime::ambient(),
+        })
+    }
+
+    async fn recv(
+        &self,
+        buffer: &mut [u8],
+        source: u32,
+        tag: Option<u32>,
+    ) -> Result<usize, CommError> {
+        let receive = self.receive_matching(source, tag);
+        let payload = runtime::with_timeout(self.recv_timeout.get(), receive).await?;
+        matching::copy_payload(&payload, buffer)
+    }
+
+    async fn send(&self, buffer: &[u8], dest: u32, tag: u32) -> Result<(), CommError> {
+        let frame = Frame {
+            tag,
+            payload: buffer.to_vec(),
+        };
+        // the stream task ends, and drops the receiver, when the call fails
+        self.outgoing[dest as usize]
+            .send(frame)
+            .await
+            .map_err(|_| CommError::Disconnected { rank: dest })
+    }
+
+    async fn barrier(&self) -> Result<(), CommError> {
+        barrier::linear_barrier_async(
+            self.rank,
+            self.outgoing.len() as u32,
+            |dest, tag| self.send(&[], dest, tag),
+            |source, tag| async move {
+                let receive = self.receive_matching(source, Some(tag));
+                runtime::with_timeout(self.recv_timeout.get(), receive).await?;
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    /// Returns the payload of the next message from `source` with `tag`. Other messages that
+    /// arrive in the meantime are queued as unexpected.
+    async fn receive_matching(&self, source: u32, tag: Option<u32>) -> Result<Vec<u8>, CommError> {
+        let (_, payload) = self.receive_message(source, tag).await?;
+        Ok(payload)
+    }
+
+    /// Like [`Self::receive_matching`], but also returns the header of the message.
+    async fn receive_message(
+        &self,
+        source: u32,
+        tag: Option<u32>,
+    ) -> Result<(Header, Vec<u8>), CommError> {
+        let mut incoming = self.incoming.lock().await;
+        if let Some(message) = self.unexpected.take(source, tag) {
+            return Ok(message);
+        }
+        let Incoming { deliveries, closed } = &mut *incoming;
+        loop {
+            if closed[source as usize] {
+                return Err(CommError::Disconnected { rank: source });
+            }
+            // the server keeps a sender for as long as the runtime runs
+            match deliveries.recv().await {
+                Some(Delivery::Message(header, payload)) => {
+                    if header.matches(source, tag) {
+                        return Ok((header, payload));
+                    }
+                    self.unexpected.push(header, payload);
+                }
+                Some(Delivery::Closed(rank)) => {
+                    if let Some(closed) = closed.get_mut(rank as usize) {
+                        *closed = true;
+                    }
+                }
+                None => return Err(CommError::Disconnected { rank: source }),
+            }
+        }
+    }
+}
+
+impl Drop for GrpcCommunicator {
+    fn drop(&mut self) {
+        // messages to us that were not received are discarded, so the server need not wait
+        self.incoming.get_mut().deliveries.close();
+        // ending the streams lets the other ranks know that we are done
+        self.outgoing.clear();
+        let streams = std::mem::take(&mut self.streams);
+        let server = self.server.abort_handle();
+        // The server goes away only after the streams have handed over the queued messages, as
+        // the one to ourselves ends there. If the runtime cannot be blocked, this happens in a
+        // detached task some time later.
+        let flush = self.runtime.spawn(async move {
+            let _ = tokio::time::timeout(FLUSH_TIMEOUT, async {
+                for stream in streams {
+                    let _ = stream.await;
+                }
+            })
+            .await;
+            server.abort();
+        });
+        let Some(flush) = flush else {
+            self.server.abort();
+            return;
+        };
+        let server = &mut self.server;
+        self.runtime.block_on_drop(async {
+            let _ = flush.await;
+            // the port is free again once the listener is dropped with the task
+            let _ = server.await;
+        });
+    }
+}
+
+/// Connects to the server of a rank, waiting for it to come up.
+async fn connect_client(address: SocketAddr) -> Result<CommServiceClient<Channel>, CommError> {
+    let endpoint = Endpoint::from_shared(format!("http://{}", address))
+        .map_err(|e| CommError::Io(io::Error::new(ErrorKind::InvalidInput, e)))?
+        .tcp_nodelay(true)
+        .http2_adaptive_window(true);
+    let deadline = Instant::now() + CONNECT_TIMEOUT;
+    loop {
+        match endpoint.connect().await {
+            Ok(channel) => return Ok(CommServiceClient::new(channel)),
+            Err(e) if is_refused(&e) => {
+                if Instant::now() >= deadline {
+                    return Err(CommError::Timeout);
+                }
+                tokio::time::sleep(CONNECT_RETRY_INTERVAL).await;
+            }
+            Err(e) => return Err(CommError::Io(io::Error::other(e))),
+        }
+    }
+}
+
+/// Whether the connection was refused, i.e. the server of the rank is not up yet.
+fn is_refused(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if let Some(error) = error.downcast_ref::<io::Error>() {
+            return error.kind() == ErrorKind::ConnectionRefused;
+        }
+        source = error.source();
+    }
+    false
+}
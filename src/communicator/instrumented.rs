@@ -0,0 +1,599 @@
+use super::{
+    CommError, CommRequest, PendingRequest, ReduceOp, Reducible, Status, TestCommunicator,
+};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{field, instrument, Span};
+
+/// Messages and bytes exchanged with a single peer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Traffic {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+/// Snapshot of the counters of an [`InstrumentedCommunicator`].
+#[derive(Debug, Clone, Default)]
+pub struct CommStats {
+    pub rank: u32,
+    /// Traffic to each destination, by rank.
+    pub sent: Vec<Traffic>,
+    /// Traffic from each source, by rank.
+    pub received: Vec<Traffic>,
+    /// Time spent blocked in sends, including starting immediate ones.
+    pub send_time: Duration,
+    /// Time spent blocked in receives and probes, including waiting for immediate ones.
+    pub recv_time: Duration,
+    pub barrier_time: Duration,
+    /// Collective operations, which are counted as a whole instead of by peer.
+    pub collectives: CollectiveStats,
+}
+
+/// Calls of one collective operation, the bytes this rank passed to them and the time they
+/// took.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OperationStats {
+    pub calls: u64,
+    /// Length of the buffer this rank contributes, i.e. the send buffer, or the buffer of a
+    /// broadcast.
+    pub bytes: u64,
+    pub time: Duration,
+}
+
+/// [`OperationStats`] of each collective operation, and of splitting the communicator.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CollectiveStats {
+    pub broadcast: OperationStats,
+    pub reduce: OperationStats,
+    pub allreduce: OperationStats,
+    pub gather: OperationStats,
+    pub allgather: OperationStats,
+    pub scatter: OperationStats,
+    pub alltoall: OperationStats,
+    pub alltoallv: OperationStats,
+    pub split: OperationStats,
+}
+
+/// Traffic between all pairs of ranks, as gathered by
+/// [`InstrumentedCommunicator::gather_matrix`].
+#[derive(Debug, Clone, Default)]
+pub struct CommMatrix {
+    /// `traffic[source][dest]` is what `source` sent to `dest`.
+    pub traffic: Vec<Vec<Traffic>>,
+}
+
+impl CommMatrix {
+    pub fn write_csv(&self, writer: impl io::Write) -> Result<(), csv::Error> {
+        let mut wtr = csv::Writer::from_writer(writer);
+        wtr.write_record(["source", "dest", "messages", "bytes"])?;
+        for (source, row) in self.traffic.iter().enumerate() {
+            for (dest, traffic) in row.iter().enumerate() {
+                wtr.write_record(&[
+                    source.to_string(),
+                    dest.to_string(),
+                    traffic.messages.to_string(),
+                    traffic.bytes.to_string(),
+                ])?;
+            }
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct PeerCounters {
+    messages_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    messages_received: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+#[derive(Default)]
+struct OperationCounters {
+    calls: AtomicU64,
+    bytes: AtomicU64,
+    nanos: AtomicU64,
+}
+
+impl OperationCounters {
+    /// Runs `operation` on `bytes` and counts it.
+    fn count<T>(&self, bytes: usize, operation: impl FnOnce() -> T) -> T {
+        let result = timed(&self.nanos, operation);
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        result
+    }
+
+    fn stats(&self) -> OperationStats {
+        OperationStats {
+            calls: self.calls.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            time: Duration::from_nanos(self.nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+#[derive(Default)]
+struct CollectiveCounters {
+    broadcast: OperationCounters,
+    reduce: OperationCounters,
+    allreduce: OperationCounters,
+    gather: OperationCounters,
+    allgather: OperationCounters,
+    scatter: OperationCounters,
+    alltoall: OperationCounters,
+    alltoallv: OperationCounters,
+    split: OperationCounters,
+}
+
+impl CollectiveCounters {
+    fn stats(&self) -> CollectiveStats {
+        CollectiveStats {
+            broadcast: self.broadcast.stats(),
+            reduce: self.reduce.stats(),
+            allreduce: self.allreduce.stats(),
+            gather: self.gather.stats(),
+            allgather: self.allgather.stats(),
+            scatter: self.scatter.stats(),
+            alltoall: self.alltoall.stats(),
+            alltoallv: self.alltoallv.stats(),
+            split: self.split.stats(),
+        }
+    }
+}
+
+/// Wraps any communicator and counts the messages and bytes it exchanges with each peer and the
+/// time it is blocked. Every operation runs in a `tracing` span at debug level with the rank,
+/// the peer and the bytes, so the spans show up in any subscriber, e.g. one that logs them on
+/// close with their duration.
+///
+/// Collective operations are forwarded to the wrapped communicator, so that it can use its own
+/// implementation of them, and are counted by operation rather than by peer.
+pub struct InstrumentedCommunicator<C> {
+    inner: C,
+    peers: Vec<PeerCounters>,
+    send_nanos: AtomicU64,
+    recv_nanos: AtomicU64,
+    barrier_nanos: AtomicU64,
+    collectives: CollectiveCounters,
+}
+
+impl<C: TestCommunicator> InstrumentedCommunicator<C> {
+    pub fn new(inner: C) -> Self {
+        let peers = (0..inner.size()).map(|_| PeerCounters::default()).collect();
+        InstrumentedCommunicator {
+            inner,
+            peers,
+            send_nanos: AtomicU64::new(0),
+            recv_nanos: AtomicU64::new(0),
+            barrier_nanos: AtomicU64::new(0),
+            collectives: CollectiveCounters::default(),
+        }
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+
+    pub fn stats(&self) -> CommStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let nanos = |counter: &AtomicU64| Duration::from_nanos(load(counter));
+        CommStats {
+            rank: self.inner.rank(),
+            sent: (self.peers.iter())
+                .map(|peer| Traffic {
+                    messages: load(&peer.messages_sent),
+                    bytes: load(&peer.bytes_sent),
+                })
+                .collect(),
+            received: (self.peers.iter())
+                .map(|peer| Traffic {
+                    messages: load(&peer.messages_received),
+                    bytes: load(&peer.bytes_received),
+                })
+                .collect(),
+            send_time: nanos(&self.send_nanos),
+            recv_time: nanos(&self.recv_nanos),
+            barrier_time: nanos(&self.barrier_nanos),
+            collectives: self.collectives.stats(),
+        }
+    }
+
+    /// Gathers the traffic sent by every rank at `root`, which gets the full matrix. All ranks
+    /// have to call it. The gather itself is not counted.
+    pub fn gather_matrix(&self, root: u32) -> Result<Option<CommMatrix>, CommError> {
+        let size = self.inner.size() as usize;
+        let mut row = Vec::with_capacity(size * 16);
+        for traffic in self.stats().sent {
+            row.extend_from_slice(&traffic.messages.to_le_bytes());
+            row.extend_from_slice(&traffic.bytes.to_le_bytes());
+        }
+        let len = if self.inner.rank() == root {
+            row.len() * size
+        } else {
+            0
+        };
+        let mut rows = vec![0; len];
+        self.inner.gather(&row, &mut rows, root)?;
+        if self.inner.rank() != root {
+            return Ok(None);
+        }
+        let field = |bytes: &[u8]| u64::from_le_bytes(bytes.try_into().unwrap());
+        let traffic = rows
+            .chunks_exact(row.len().max(1))
+            .map(|row| {
+                (row.chunks_exact(16))
+                    .map(|pair| Traffic {
+                        messages: field(&pair[..8]),
+                        bytes: field(&pair[8..]),
+                    })
+                    .collect()
+            })
+            .collect();
+        Ok(Some(CommMatrix { traffic }))
+    }
+
+    fn count_sent(&self, dest: u32, len: usize) {
+        let peer = &self.peers[dest as usize];
+        peer.messages_sent.fetch_add(1, Ordering::Relaxed);
+        peer.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn count_received(&self, source: u32, len: usize) {
+        let peer = &self.peers[source as usize];
+        peer.messages_received.fetch_add(1, Ordering::Relaxed);
+        peer.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+    }
+}
+
+/// Runs `operation` and adds the time it took to `nanos`.
+fn timed<T>(nanos: &AtomicU64, operation: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = operation();
+    nanos.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    result
+}
+
+impl<C: TestCommunicator> TestCommunicator for InstrumentedCommunicator<C> {
+    fn rank(&self) -> u32 {
+        self.inner.rank()
+    }
+
+    fn size(&self) -> u32 {
+        self.inner.size()
+    }
+
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(rank = self.rank(), peer = dest, bytes = buffer.len())
+    )]
+    fn send(&self, buffer: &[u8], dest: u32) -> Result<(), CommError> {
+        timed(&self.send_nanos, || self.inner.send(buffer, dest))?;
+        self.count_sent(dest, buffer.len());
+        Ok(())
+    }
+
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(rank = self.rank(), peer = source, bytes = field::Empty)
+    )]
+    fn recv(&self, buffer: &mut [u8], source: u32) -> Result<usize, CommError> {
+        let len = timed(&self.recv_nanos, || self.inner.recv(buffer, source))?;
+        Span::current().record("bytes", len);
+        self.count_received(source, len);
+        Ok(len)
+    }
+
+    #[instrument(level = "debug", skip_all, fields(rank = self.rank()))]
+    fn barrier(&self) -> Result<(), CommError> {
+        timed(&self.barrier_nanos, || self.inner.barrier())
+    }
+
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(rank = self.rank(), peer = dest, bytes = buffer.len())
+    )]
+    fn isend(&self, buffer: Vec<u8>, dest: u32) -> Result<CommRequest<'_>, CommError> {
+        let len = buffer.len();
+        let request = timed(&self.send_nanos, || self.inner.isend(buffer, dest))?;
+        self.count_sent(dest, len);
+        Ok(request)
+    }
+
+    #[instrument(level = "debug", skip_all, fields(rank = self.rank(), peer = source))]
+    fn irecv(&self, buffer: Vec<u8>, source: u32) -> Result<CommRequest<'_>, CommError> {
+        Ok(CommRequest::new(InstrumentedRecvRequest {
+            comm: self,
+            request: self.inner.irecv(buffer, source)?,
+            source,
+        }))
+    }
+
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(rank = self.rank(), peer = dest, tag = tag, bytes = buffer.len())
+    )]
+    fn send_tagged(&self, buffer: &[u8], dest: u32, tag: u32) -> Result<(), CommError> {
+        timed(&self.send_nanos, || {
+            self.inner.send_tagged(buffer, dest, tag)
+        })?;
+        self.count_sent(dest, buffer.len());
+        Ok(())
+    }
+
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(rank = self.rank(), peer = source, tag = tag, bytes = field::Empty)
+    )]
+    fn recv_tagged(&self, buffer: &mut [u8], source: u32, tag: u32) -> Result<usize, CommError> {
+        let len = timed(&self.recv_nanos, || {
+            self.inner.recv_tagged(buffer, source, tag)
+        })?;
+        Span::current().record("bytes", len);
+        self.count_received(source, len);
+        Ok(len)
+    }
+
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(rank = self.rank(), peer = source, bytes = field::Empty)
+    )]
+    fn probe(&self, source: u32) -> Result<Status, CommError> {
+        let status = timed(&self.recv_nanos, || self.inner.probe(source))?;
+        Span::current().record("bytes", status.len);
+        Ok(status)
+    }
+
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(rank = self.rank(), peer = source, bytes = field::Empty)
+    )]
+    fn recv_vec(&self, source: u32) -> Result<Vec<u8>, CommError> {
+        let payload = timed(&self.recv_nanos, || self.inner.recv_vec(source))?;
+        Span::current().record("bytes", payload.len());
+        self.count_received(source, payload.len());
+        Ok(payload)
+    }
+
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(rank = self.rank(), peer = source, bytes = field::Empty)
+    )]
+    fn recv_timeout(
+        &self,
+        buffer: &mut [u8],
+        source: u32,
+        timeout: Duration,
+    ) -> Result<usize, CommError> {
+        let len = timed(&self.recv_nanos, || {
+            self.inner.recv_timeout(buffer, source, timeout)
+        })?;
+        Span::current().record("bytes", len);
+        self.count_received(source, len);
+        Ok(len)
+    }
+
+    fn set_recv_timeout(&self, timeout: Option<Duration>) -> Result<(), CommError> {
+        self.inner.set_recv_timeout(timeout)
+    }
+
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(rank = self.rank(), root = root, bytes = buffer.len())
+    )]
+    fn broadcast(&self, buffer: &mut [u8], root: u32) -> Result<(), CommError> {
+        (self.collectives.broadcast).count(buffer.len(), || self.inner.broadcast(buffer, root))
+    }
+
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(rank = self.rank(), root = root, bytes = std::mem::size_of_val(send))
+    )]
+    fn reduce<T: Reducible>(
+        &self,
+        send: &[T],
+        recv: &mut [T],
+        op: ReduceOp,
+        root: u32,
+    ) -> Result<(), CommError> {
+        (self.collectives.reduce).count(std::mem::size_of_val(send), || {
+            self.inner.reduce(send, recv, op, root)
+        })
+    }
+
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(rank = self.rank(), bytes = std::mem::size_of_val(send))
+    )]
+    fn allreduce<T: Reducible>(
+        &self,
+        send: &[T],
+        recv: &mut [T],
+        op: ReduceOp,
+    ) -> Result<(), CommError> {
+        (self.collectives.allreduce).count(std::mem::size_of_val(send), || {
+            self.inner.allreduce(send, recv, op)
+        })
+    }
+
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(rank = self.rank(), root = root, bytes = send.len())
+    )]
+    fn gather(&self, send: &[u8], recv: &mut [u8], root: u32) -> Result<(), CommError> {
+        (self.collectives.gather).count(send.len(), || self.inner.gather(send, recv, root))
+    }
+
+    #[instrument(level = "debug", skip_all, fields(rank = self.rank(), bytes = send.len()))]
+    fn allgather(&self, send: &[u8], recv: &mut [u8]) -> Result<(), CommError> {
+        (self.collectives.allgather).count(send.len(), || self.inner.allgather(send, recv))
+    }
+
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(rank = self.rank(), root = root, bytes = send.len())
+    )]
+    fn scatter(&self, send: &[u8], recv: &mut [u8], root: u32) -> Result<(), CommError> {
+        (self.collectives.scatter).count(send.len(), || self.inner.scatter(send, recv, root))
+    }
+
+    #[instrument(level = "debug", skip_all, fields(rank = self.rank(), bytes = send.len()))]
+    fn alltoall(&self, send: &[u8], recv: &mut [u8]) -> Result<(), CommError> {
+        (self.collectives.alltoall).count(send.len(), || self.inner.alltoall(send, recv))
+    }
+
+    #[instrument(level = "debug", skip_all, fields(rank = self.rank(), bytes = send.len()))]
+    fn alltoallv(
+        &self,
+        send: &[u8],
+        send_counts: &[usize],
+        recv: &mut [u8],
+        recv_counts: &[usize],
+    ) -> Result<(), CommError> {
+        (self.collectives.alltoallv).count(send.len(), || {
+            self.inner.alltoallv(send, send_counts, recv, recv_counts)
+        })
+    }
+
+    /// The new communicators count their own traffic.
+    #[instrument(level = "debug", skip_all, fields(rank = self.rank(), color = color, key = key))]
+    fn split(&self, color: Option<u32>, key: u32) -> Result<Option<Self>, CommError> {
+        let split = (self.collectives.split).count(0, || self.inner.split(color, key))?;
+        Ok(split.map(InstrumentedCommunicator::new))
+    }
+}
+
+/// Counts an immediate receive once it has completed.
+struct InstrumentedRecvRequest<'a, C> {
+    comm: &'a InstrumentedCommunicator<C>,
+    request: CommRequest<'a>,
+    source: u32,
+}
+
+impl<C: TestCommunicator> PendingRequest for InstrumentedRecvRequest<'_, C> {
+    fn test(&mut self) -> Result<bool, CommError> {
+        self.request.test()
+    }
+
+    fn wait(self: Box<Self>) -> Result<Vec<u8>, CommError> {
+        let InstrumentedRecvRequest {
+            comm,
+            request,
+            source,
+        } = *self;
+        let buffer = timed(&comm.recv_nanos, || request.wait())?;
+        comm.count_received(source, buffer.len());
+        Ok(buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communicator::ChannelSimCommunicator;
+    use std::thread;
+
+    fn instrumented(n: u32) -> Vec<InstrumentedCommunicator<ChannelSimCommunicator>> {
+        let comms = ChannelSimCommunicator::create(n, None);
+        comms
+            .into_iter()
+            .map(InstrumentedCommunicator::new)
+            .collect()
+    }
+
+    #[test]
+    fn counts_messages_and_bytes_by_peer() {
+        let comms = instrumented(3);
+        comms[0].send(&[0; 10], 1).unwrap();
+        comms[0].send_tagged(&[0; 3], 2, 7).unwrap();
+        comms[2].isend(vec![0; 5], 1).unwrap().wait().unwrap();
+
+        let mut buffer = [0; 16];
+        assert_eq!(comms[1].recv(&mut buffer, 0).unwrap(), 10);
+        // the received length counts, not the length of the buffer
+        let received = comms[1].irecv(vec![0; 64], 2).unwrap().wait().unwrap();
+        assert_eq!(received.len(), 5);
+        assert_eq!(comms[2].recv_tagged(&mut buffer, 0, 7).unwrap(), 3);
+
+        let traffic = |messages, bytes| Traffic { messages, bytes };
+        let stats = comms[0].stats();
+        assert_eq!(stats.rank, 0);
+        assert_eq!(stats.sent, [traffic(0, 0), traffic(1, 10), traffic(1, 3)]);
+        assert_eq!(stats.received, [Traffic::default(); 3]);
+        let stats = comms[1].stats();
+        assert_eq!(stats.sent, [Traffic::default(); 3]);
+        assert_eq!(
+            stats.received,
+            [traffic(1, 10), traffic(0, 0), traffic(1, 5)]
+        );
+        assert_eq!(comms[2].stats().received[0], traffic(1, 3));
+    }
+
+    #[test]
+    fn gathers_the_matrix_at_the_root() {
+        let comms = instrumented(3);
+        let matrices = thread::scope(|scope| {
+            let ranks: Vec<_> = (comms.iter())
+                .map(|comm| {
+                    scope.spawn(move || {
+                        let dest = (comm.rank() + 1) % 3;
+                        comm.send(&vec![0; comm.rank() as usize + 1], dest).unwrap();
+                        let source = (comm.rank() + 2) % 3;
+                        comm.recv_vec(source).unwrap();
+                        comm.gather_matrix(1).unwrap()
+                    })
+                })
+                .collect();
+            ranks
+                .into_iter()
+                .map(|rank| rank.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        assert!(matrices[0].is_none() && matrices[2].is_none());
+        let matrix = matrices[1].as_ref().unwrap();
+        let traffic = |source: usize, dest: usize| matrix.traffic[source][dest];
+        assert_eq!(
+            traffic(0, 1),
+            Traffic {
+                messages: 1,
+                bytes: 1
+            }
+        );
+        assert_eq!(
+            traffic(1, 2),
+            Traffic {
+                messages: 1,
+                bytes: 2
+            }
+        );
+        assert_eq!(
+            traffic(2, 0),
+            Traffic {
+                messages: 1,
+                bytes: 3
+            }
+        );
+        assert_eq!(traffic(0, 2), Traffic::default());
+        // the gather is not counted
+        assert_eq!(comms[1].stats().collectives.gather.calls, 0);
+    }
+}
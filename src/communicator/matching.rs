@@ -0,0 +1,283 @@
+use super::CommError;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Size of the encoded [`Header`] in bytes.
+pub(crate) const HEADER_LEN: usize = 12;
+
+/// Tag of messages sent with plain `send`, as in MPI.
+pub(crate) const DEFAULT_TAG: u32 = 0;
+
+/// Tags from here on are used for internal control messages, such as the barrier.
+pub(crate) const RESERVED_TAG_START: u32 = u32::MAX - 15;
+
+/// Tag of the messages of the collective operations built on point-to-point messages. The
+/// transports handle them like user messages, but a receive without a tag does not match them.
+pub(crate) const COLLECTIVE_TAG: u32 = RESERVED_TAG_START - 1;
+
+/// Largest payload of a single UDP datagram including our header.
+pub(crate) const MAX_DATAGRAM_LEN: usize = 65_507;
+
+/// Largest UDP datagram sent unless configured otherwise, which avoids IP fragmentation on an
+/// Ethernet MTU of 1500.
+pub(crate) const DEFAULT_DATAGRAM_LEN: usize = 1472;
+
+/// Longest message a receiver accepts if not configured otherwise.
+pub(crate) const DEFAULT_MAX_MESSAGE_LEN: usize = 1 << 30;
+
+/// How long a receive on a blocking socket waits for a matching message.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Wait {
+    /// Return `None` right away if no matching message is available.
+    Poll,
+    Block,
+    /// Return `None` if no matching message arrived in time.
+    For(Duration),
+}
+
+/// Default deadline of the blocking receives of a communicator, as set by
+/// [`TestCommunicator::set_recv_timeout`](super::TestCommunicator::set_recv_timeout).
+#[derive(Debug, Default)]
+pub(crate) struct RecvTimeout {
+    /// Zero for none.
+    nanos: AtomicU64,
+}
+
+impl RecvTimeout {
+    pub fn set(&self, timeout: Option<Duration>) {
+        // a zero timeout has to be told apart from none
+        let nanos = timeout.map_or(0, |timeout| timeout.as_nanos().clamp(1, u64::MAX as u128));
+        self.nanos.store(nanos as u64, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Option<Duration> {
+        match self.nanos.load(Ordering::Relaxed) {
+            0 => None,
+            nanos => Some(Duration::from_nanos(nanos)),
+        }
+    }
+
+    /// How long a blocking receive waits for a matching message.
+    pub fn wait(&self) -> Wait {
+        self.get().map_or(Wait::Block, Wait::For)
+    }
+}
+
+/// Envelope of a message that is ready to be received, as returned by
+/// [`TestCommunicator::probe`](super::TestCommunicator::probe).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    pub source: u32,
+    pub tag: u32,
+    /// Length of the payload in bytes.
+    pub len: usize,
+}
+
+/// Envelope put in front of every message of the socket based communicators, so that the
+/// receiver can match on source and tag the way MPI does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Header {
+    pub source: u32,
+    pub tag: u32,
+    pub len: u32,
+}
+
+impl Header {
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.source.to_le_bytes());
+        out.extend_from_slice(&self.tag.to_le_bytes());
+        out.extend_from_slice(&self.len.to_le_bytes());
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Header, CommError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(invalid_data(format!(
+                "Message of {} bytes is too short for a header",
+                bytes.len()
+            )));
+        }
+        let field = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        Ok(Header {
+            source: field(0),
+            tag: field(4),
+            len: field(8),
+        })
+    }
+
+    /// A `tag` of `None` matches any tag, like `MPI_ANY_TAG`, except the reserved ones and
+    /// the one of the collective operations.
+    pub fn matches(&self, source: u32, tag: Option<u32>) -> bool {
+        self.source == source
+            && match tag {
+                Some(tag) => self.tag == tag,
+                None => self.tag < COLLECTIVE_TAG,
+            }
+    }
+}
+
+/// Rejects tags that user messages must not carry.
+pub(crate) fn check_tag(tag: u32) -> Result<(), CommError> {
+    if tag >= RESERVED_TAG_START {
+        return Err(CommError::Unsupported("tags reserved for control messages"));
+    }
+    Ok(())
+}
+
+/// Prepends a header to `payload`.
+pub(crate) fn frame(source: u32, tag: u32, payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(HEADER_LEN + payload.len());
+    Header {
+        source,
+        tag,
+        len: payload.len() as u32,
+    }
+    .encode(&mut message);
+    message.extend_from_slice(payload);
+    message
+}
+
+/// Copies a matched payload into the receive buffer of the caller, and returns its length.
+pub(crate) fn copy_payload(payload: &[u8], buffer: &mut [u8]) -> Result<usize, CommError> {
+    if payload.len() > buffer.len() {
+        return Err(CommError::Truncated {
+            len: payload.len(),
+            capacity: buffer.len(),
+        });
+    }
+    buffer[..payload.len()].copy_from_slice(payload);
+    Ok(payload.len())
+}
+
+/// Like [`copy_payload`], but also shrinks `buffer` to the length of the payload.
+pub(crate) fn fill_buffer(buffer: &mut Vec<u8>, payload: &[u8]) -> Result<(), CommError> {
+    copy_payload(payload, buffer)?;
+    buffer.truncate(payload.len());
+    Ok(())
+}
+
+/// Rejects a message longer than `max_message_len` before room is made for it, so that a
+/// corrupt header cannot make a rank allocate arbitrary amounts of memory.
+pub(crate) fn check_message_len(header: &Header, max_message_len: usize) -> Result<(), CommError> {
+    if header.len as usize > max_message_len {
+        return Err(invalid_data(format!(
+            "Message from rank {} of {} bytes is longer than the maximum of {}",
+            header.source, header.len, max_message_len
+        )));
+    }
+    Ok(())
+}
+
+pub(crate) fn invalid_data(message: String) -> CommError {
+    CommError::Io(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+/// Messages that arrived before a matching receive was posted.
+#[derive(Default)]
+pub(crate) struct Mailbox {
+    unexpected: Mutex<VecDeque<(Header, Vec<u8>)>>,
+}
+
+impl Mailbox {
+    /// Removes the oldest message matching `source` and `tag`, so messages between the same
+    /// pair of ranks are not overtaken.
+    pub fn take(&self, source: u32, tag: Option<u32>) -> Option<(Header, Vec<u8>)> {
+        let mut unexpected = self.unexpected.lock().unwrap();
+        let position = unexpected
+            .iter()
+            .position(|(header, _)| header.matches(source, tag))?;
+        unexpected.remove(position)
+    }
+
+    pub fn push(&self, header: Header, payload: Vec<u8>) {
+        self.unexpected.lock().unwrap().push_back((header, payload));
+    }
+
+    /// Returns a probed message to the front of the queue, so the next receive from its source
+    /// gets it before any later message.
+    pub fn put_back(&self, header: Header, payload: Vec<u8>) -> Status {
+        let status = Status {
+            source: header.source,
+            tag: header.tag,
+            len: payload.len(),
+        };
+        self.unexpected
+            .lock()
+            .unwrap()
+            .push_front((header, payload));
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push(mailbox: &Mailbox, source: u32, tag: u32, payload: u8) {
+        let header = Header {
+            source,
+            tag,
+            len: 1,
+        };
+        mailbox.push(header, vec![payload]);
+    }
+
+    fn take(mailbox: &Mailbox, source: u32, tag: Option<u32>) -> Option<u8> {
+        mailbox.take(source, tag).map(|(_, payload)| payload[0])
+    }
+
+    #[test]
+    fn takes_the_oldest_message_of_the_source() {
+        let mailbox = Mailbox::default();
+        push(&mailbox, 1, 0, 10);
+        push(&mailbox, 2, 0, 20);
+        push(&mailbox, 1, 0, 11);
+        assert_eq!(take(&mailbox, 2, None), Some(20));
+        assert_eq!(take(&mailbox, 2, None), None);
+        assert_eq!(take(&mailbox, 1, None), Some(10));
+        assert_eq!(take(&mailbox, 1, Some(0)), Some(11));
+    }
+
+    #[test]
+    fn matches_tags() {
+        let mailbox = Mailbox::default();
+        push(&mailbox, 1, 5, 10);
+        push(&mailbox, 1, 6, 11);
+        push(&mailbox, 1, 5, 12);
+        assert_eq!(take(&mailbox, 1, Some(6)), Some(11));
+        assert_eq!(take(&mailbox, 1, Some(7)), None);
+        assert_eq!(take(&mailbox, 1, Some(5)), Some(10));
+        assert_eq!(take(&mailbox, 1, None), Some(12));
+    }
+
+    #[test]
+    fn any_tag_skips_internal_messages() {
+        let mailbox = Mailbox::default();
+        push(&mailbox, 1, RESERVED_TAG_START, 10);
+        push(&mailbox, 1, COLLECTIVE_TAG, 11);
+        push(&mailbox, 1, COLLECTIVE_TAG - 1, 12);
+        assert_eq!(take(&mailbox, 1, None), Some(12));
+        assert_eq!(take(&mailbox, 1, None), None);
+        assert_eq!(take(&mailbox, 1, Some(COLLECTIVE_TAG)), Some(11));
+        assert_eq!(take(&mailbox, 1, Some(RESERVED_TAG_START)), Some(10));
+        assert!(check_tag(COLLECTIVE_TAG).is_ok());
+        assert!(check_tag(RESERVED_TAG_START).is_err());
+    }
+
+    #[test]
+    fn put_back_goes_first() {
+        let mailbox = Mailbox::default();
+        push(&mailbox, 1, 0, 10);
+        let header = Header {
+            source: 1,
+            tag: 3,
+            len: 1,
+        };
+        let status = mailbox.put_back(header, vec![9]);
+        assert_eq!((status.source, status.tag, status.len), (1, 3, 1));
+        assert_eq!(take(&mailbox, 1, None), Some(9));
+        assert_eq!(take(&mailbox, 1, None), Some(10));
+    }
+}
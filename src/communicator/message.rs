@@ -0,0 +1,73 @@
+use super::{CommError, TestCommunicator};
+use prost::{Message, Name};
+use prost_types::Any;
+
+/// Sends `message` length-delimited, i.e. prefixed with its encoded length as a varint.
+pub(crate) fn send_msg<C: TestCommunicator + ?Sized, M: Message>(
+    comm: &C,
+    message: &M,
+    dest: u32,
+) -> Result<(), CommError> {
+    comm.send(&message.encode_length_delimited_to_vec(), dest)
+}
+
+/// Receives the next message from `source` and decodes it as a length-delimited `M`. A
+/// message shorter than its length prefix fails to decode.
+pub(crate) fn recv_msg<C: TestCommunicator + ?Sized, M: Message + Default>(
+    comm: &C,
+    source: u32,
+) -> Result<M, CommError> {
+    let buffer = comm.recv_vec(source)?;
+    Ok(M::decode_length_delimited(buffer.as_slice())?)
+}
+
+/// Wraps `message` into an [`Any`] with the type URL of `M`, as [`Any::from_msg`] does.
+pub(crate) fn send_any<C: TestCommunicator + ?Sized, M: Name>(
+    comm: &C,
+    message: &M,
+    dest: u32,
+) -> Result<(), CommError> {
+    let any = Any {
+        type_url: M::type_url(),
+        value: message.encode_to_vec(),
+    };
+    send_msg(comm, &any, dest)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::communicator::{ChannelSimCommunicator, CommError, TestCommunicator};
+    use crate::proto::events::{LoginEvent, LogoutEvent};
+
+    fn login() -> LoginEvent {
+        LoginEvent {
+            user_id: "ada".to_string(),
+            timestamp: 1_700_000_000,
+        }
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let comms = ChannelSimCommunicator::create(2, None);
+        comms[0].send_msg(&login(), 1).unwrap();
+        assert_eq!(comms[1].recv_msg::<LoginEvent>(0).unwrap(), login());
+
+        comms[1].send_any(&login(), 0).unwrap();
+        let any = comms[0].recv_any(1).unwrap();
+        assert_eq!(any.to_msg::<LoginEvent>().unwrap(), login());
+        assert!(any.to_msg::<LogoutEvent>().is_err());
+    }
+
+    #[test]
+    fn mismatched_messages_fail_to_decode() {
+        let comms = ChannelSimCommunicator::create(2, None);
+        // the timestamp does not have the wire type of the value of an `Any`
+        comms[0].send_msg(&login(), 1).unwrap();
+        assert!(matches!(comms[1].recv_any(0), Err(CommError::Decode(_))));
+
+        // shorter than its length prefix
+        comms[0].send(&[5, 0], 1).unwrap();
+        let truncated = comms[1].recv_msg::<LoginEvent>(0);
+        assert!(matches!(truncated, Err(CommError::Decode(_))));
+    }
+}
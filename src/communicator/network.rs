@@ -0,0 +1,315 @@
+use super::matching::{Header, Mailbox};
+use super::CommError;
+use clap::{Parser, ValueEnum};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Delay model of the links between the ranks of a
+/// [`ChannelSimCommunicator`](super::ChannelSimCommunicator). A message of `len` bytes arrives
+/// after `hops * latency + len / bandwidth` plus a jitter. Messages on the same link are
+/// transferred one after the other and never overtake each other.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkModel {
+    /// Latency of a single hop.
+    pub latency: Duration,
+    /// Bandwidth of every link in bytes per second. Unlimited if `None`.
+    pub bandwidth: Option<u64>,
+    pub jitter: Jitter,
+    pub topology: Topology,
+    /// Seed of the jitter, so that runs can be repeated.
+    pub seed: u64,
+}
+
+/// Random delay added to every message.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Jitter {
+    #[default]
+    None,
+    /// Uniformly distributed between zero and `max`.
+    Uniform { max: Duration },
+    /// Normally distributed around zero, cut off at zero.
+    Normal { std_dev: Duration },
+    /// Exponentially distributed, i.e. mostly small with a long tail.
+    Exponential { mean: Duration },
+}
+
+/// Arrangement of the ranks, which gives the number of hops between two of them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Topology {
+    /// Every rank is a single hop away from every other.
+    #[default]
+    FullyConnected,
+    /// Ranks are the leaves of a tree of switches with `radix` children each. A message goes up
+    /// to the lowest common switch and down again.
+    FatTree { radix: u32 },
+    /// Rank `i` sits at `(i % width, i / width)` of a grid that wraps around in both dimensions.
+    Torus2d { width: u32, height: u32 },
+}
+
+impl Jitter {
+    fn sample(&self, rng: &mut StdRng) -> Duration {
+        let seconds = match *self {
+            Jitter::None => 0.0,
+            Jitter::Uniform { max } => rng.random::<f64>() * max.as_secs_f64(),
+            Jitter::Normal { std_dev } => {
+                // Box-Muller transform
+                let u1 = 1.0 - rng.random::<f64>();
+                let u2 = rng.random::<f64>();
+                let normal = (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos();
+                (normal * std_dev.as_secs_f64()).max(0.0)
+            }
+            Jitter::Exponential { mean } => -(1.0 - rng.random::<f64>()).ln() * mean.as_secs_f64(),
+        };
+        Duration::from_secs_f64(seconds)
+    }
+}
+
+impl Topology {
+    pub fn hops(&self, source: u32, dest: u32) -> u32 {
+        if source == dest {
+            return 0;
+        }
+        match *self {
+            Topology::FullyConnected => 1,
+            Topology::FatTree { radix } => {
+                let (mut source, mut dest, mut levels) = (source, dest, 0);
+                while source != dest {
+                    source /= radix;
+                    dest /= radix;
+                    levels += 1;
+                }
+                2 * levels
+            }
+            Topology::Torus2d { width, height } => {
+                let distance = |a: u32, b: u32, len: u32| {
+                    let d = a.abs_diff(b);
+                    d.min(len - d)
+                };
+                distance(source % width, dest % width, width)
+                    + distance(source / width, dest / width, height)
+            }
+        }
+    }
+
+    /// Fails if `size` ranks do not fit into the topology.
+    pub fn check(&self, size: u32) -> Result<(), CommError> {
+        let fits = match *self {
+            Topology::FullyConnected => true,
+            Topology::FatTree { radix } => radix >= 2,
+            Topology::Torus2d { width, height } => (width as u64) * (height as u64) >= size as u64,
+        };
+        if !fits {
+            return Err(CommError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} cannot hold {} ranks", self, size),
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum JitterKind {
+    #[default]
+    None,
+    Uniform,
+    Normal,
+    Exponential,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TopologyKind {
+    #[default]
+    FullyConnected,
+    FatTree,
+    Torus2d,
+}
+
+/// Command line form of a [`NetworkModel`].
+#[derive(Parser, Debug, Clone, Default)]
+pub struct NetworkArguments {
+    /// Latency of a single hop in microseconds.
+    #[arg(long, default_value_t = 0)]
+    pub latency_micros: u64,
+    /// Bandwidth of every link in bytes per second. Unlimited if not given.
+    #[arg(long)]
+    pub bandwidth: Option<u64>,
+    #[arg(long, value_enum, default_value_t = JitterKind::None)]
+    pub jitter: JitterKind,
+    /// Scale of the jitter in microseconds: the maximum, standard deviation or mean.
+    #[arg(long, default_value_t = 0)]
+    pub jitter_micros: u64,
+    #[arg(long, value_enum, default_value_t = TopologyKind::FullyConnected)]
+    pub topology: TopologyKind,
+    /// Children of every switch of a fat tree.
+    #[arg(long, default_value_t = 2)]
+    pub radix: u32,
+    /// Width of a 2D torus. The height follows from the number of ranks.
+    #[arg(long, default_value_t = 1)]
+    pub torus_width: u32,
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+}
+
+impl NetworkArguments {
+    /// The model of a network of `size` ranks.
+    pub fn model(&self, size: u32) -> NetworkModel {
+        let scale = Duration::from_micros(self.jitter_micros);
+        NetworkModel {
+            latency: Duration::from_micros(self.latency_micros),
+            bandwidth: self.bandwidth,
+            jitter: match self.jitter {
+                JitterKind::None => Jitter::None,
+                JitterKind::Uniform => Jitter::Uniform { max: scale },
+                JitterKind::Normal => Jitter::Normal { std_dev: scale },
+                JitterKind::Exponential => Jitter::Exponential { mean: scale },
+            },
+            topology: match self.topology {
+                TopologyKind::FullyConnected => Topology::FullyConnected,
+                TopologyKind::FatTree => Topology::FatTree { radix: self.radix },
+                TopologyKind::Torus2d => Topology::Torus2d {
+                    width: self.torus_width,
+                    height: size.div_ceil(self.torus_width.max(1)),
+                },
+            },
+            seed: self.seed,
+        }
+    }
+}
+
+/// Outgoing links of a rank under a [`NetworkModel`].
+pub(crate) struct Network {
+    model: NetworkModel,
+    rank: u32,
+    rng: Mutex<StdRng>,
+    links: Vec<Mutex<Link>>,
+}
+
+#[derive(Clone, Copy)]
+struct Link {
+    /// When the link has finished transferring the messages sent so far.
+    free_at: Instant,
+    /// Arrival of the last message sent, which later ones must not overtake.
+    last_arrival: Instant,
+}
+
+impl Network {
+    pub fn new(model: NetworkModel, rank: u32, size: u32) -> Self {
+        let now = Instant::now();
+        let link = Link {
+            free_at: now,
+            last_arrival: now,
+        };
+        // every rank draws its own jitter
+        let rng = StdRng::seed_from_u64(model.seed.wrapping_add(rank as u64));
+        Network {
+            model,
+            rank,
+            rng: Mutex::new(rng),
+            links: (0..size).map(|_| Mutex::new(link)).collect(),
+        }
+    }
+
+    pub fn model(&self) -> &NetworkModel {
+        &self.model
+    }
+
+    /// When a message of `len` bytes sent to `dest` now arrives.
+    pub fn arrival(&self, dest: u32, len: usize) -> Instant {
+        let model = &self.model;
+        let transfer = match model.bandwidth {
+            Some(bandwidth) => Duration::from_secs_f64(len as f64 / bandwidth.max(1) as f64),
+            None => Duration::ZERO,
+        };
+        let jitter = match model.jitter {
+            Jitter::None => Duration::ZERO,
+            jitter => jitter.sample(&mut self.rng.lock().unwrap()),
+        };
+        let latency = model.latency * model.topology.hops(self.rank, dest);
+
+        let mut link = self.links[dest as usize].lock().unwrap();
+        link.free_at = link.free_at.max(Instant::now()) + transfer;
+        link.last_arrival = link.last_arrival.max(link.free_at + latency + jitter);
+        link.last_arrival
+    }
+}
+
+/// Message on its way to a rank of a [`ChannelSimCommunicator`](super::ChannelSimCommunicator).
+pub(crate) struct InFlight {
+    pub header: Header,
+    pub payload: Vec<u8>,
+    pub arrival: Instant,
+}
+
+/// Messages a rank has taken off its channel but that have not arrived yet, by arrival.
+#[derive(Default)]
+pub(crate) struct InTransit {
+    messages: Vec<InFlight>,
+}
+
+impl InTransit {
+    pub fn add(&mut self, message: InFlight) {
+        // after those arriving at the same time, which were sent before
+        let position = self
+            .messages
+            .partition_point(|other| other.arrival <= message.arrival);
+        self.messages.insert(position, message);
+    }
+
+    /// Moves the messages that have arrived by `now` to `mailbox`, in the order of arrival.
+    pub fn deliver(&mut self, now: Instant, mailbox: &Mailbox) {
+        let arrived = self
+            .messages
+            .partition_point(|message| message.arrival <= now);
+        for message in self.messages.drain(..arrived) {
+            mailbox.push(message.header, message.payload);
+        }
+    }
+
+    pub fn next_arrival(&self) -> Option<Instant> {
+        self.messages.first().map(|message| message.arrival)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hops_of_a_fully_connected_network() {
+        let topology = Topology::FullyConnected;
+        assert_eq!(topology.hops(3, 3), 0);
+        assert_eq!(topology.hops(0, 7), 1);
+    }
+
+    #[test]
+    fn hops_of_a_fat_tree() {
+        let topology = Topology::FatTree { radix: 4 };
+        assert_eq!(topology.hops(5, 5), 0);
+        // siblings below the same switch
+        assert_eq!(topology.hops(4, 7), 2);
+        assert_eq!(topology.hops(3, 4), 4);
+        assert_eq!(topology.hops(0, 16), 6);
+        assert_eq!(topology.hops(16, 0), 6);
+    }
+
+    #[test]
+    fn hops_of_a_torus() {
+        let topology = Topology::Torus2d {
+            width: 4,
+            height: 3,
+        };
+        assert_eq!(topology.hops(0, 1), 1);
+        // wraps around in both dimensions
+        assert_eq!(topology.hops(0, 3), 1);
+        assert_eq!(topology.hops(0, 8), 1);
+        assert_eq!(topology.hops(0, 6), 3);
+        assert_eq!(topology.hops(5, 11), 3);
+        assert_eq!(topology.hops(11, 5), 3);
+        assert!(topology.check(12).is_ok());
+        assert!(topology.check(13).is_err());
+    }
+}
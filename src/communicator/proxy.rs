@@ -0,0 +1,834 @@
+use super::matching::{self, RecvTimeout};
+use super::{
+    CommError, CommRequest, PendingRequest, ReduceOp, Reducible, Status, TestCommunicator,
+};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::panic;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, JoinHandle, Thread};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, watch};
+
+type Done<T> = oneshot::Sender<Result<T, CommError>>;
+type BlockingJob<C> = Box<dyn FnOnce(Result<&C, CommError>) + Send>;
+/// Messages received for handles that gave up waiting, by source, for the next receives.
+type Unclaimed = HashMap<u32, VecDeque<Vec<u8>>>;
+
+/// Rounds without progress the progress thread spins for before it starts to sleep.
+const SPIN_ROUNDS: u32 = 64;
+const MIN_BACKOFF: Duration = Duration::from_micros(10);
+const MAX_BACKOFF: Duration = Duration::from_millis(1);
+
+enum Job<C> {
+    Isend {
+        buffer: Vec<u8>,
+        dest: u32,
+        done: Done<Vec<u8>>,
+    },
+    Irecv {
+        buffer: Vec<u8>,
+        source: u32,
+        done: Done<Vec<u8>>,
+    },
+    /// Runs on the progress thread, which does nothing else meanwhile.
+    Run(Box<dyn FnOnce(&C) + Send>),
+    /// Like `Run`, but may wait for other ranks, so it is handed an error instead of the
+    /// communicator while sends or receives are outstanding or received messages unclaimed.
+    Blocking(BlockingJob<C>),
+}
+
+/// Funnels the communication of several worker threads through one progress thread, which owns
+/// the real communicator. The workers talk to it through [`ProxyHandle`]s, so the communicator
+/// needs neither to be thread safe nor to be shared, e.g. MPI initialized with
+/// `MPI_THREAD_FUNNELED` or a tokio based communicator with its own runtime.
+///
+/// Sends and receives of the handles become non-blocking operations of the communicator, which
+/// the progress thread drives all at once. Everything else runs on the progress thread one at a
+/// time and holds up the other operations until it has returned. Operations that wait for other
+/// ranks are therefore rejected while sends or receives are outstanding, see [`ProxyHandle`].
+pub struct CommProxy<C> {
+    rank: u32,
+    size: u32,
+    jobs: UnboundedSender<Job<C>>,
+    recv_timeout: Arc<RecvTimeout>,
+    shutdown: watch::Sender<bool>,
+    progress: Option<JoinHandle<Result<(), CommError>>>,
+}
+
+/// Loop of the progress thread of a [`CommProxy`] created with [`CommProxy::new`].
+pub struct Progress<C> {
+    jobs: UnboundedReceiver<Job<C>>,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl<C: TestCommunicator + 'static> CommProxy<C> {
+    /// For a communicator that has to stay on the current thread, like MPI with
+    /// `MPI_THREAD_FUNNELED`. The current thread becomes the progress thread by running the
+    /// returned [`Progress`] with `comm`.
+    pub fn new(comm: &C) -> (Self, Progress<C>) {
+        let (jobs, jobs_receiver) = mpsc::unbounded_channel();
+        let (shutdown, shutdown_receiver) = watch::channel(false);
+        let proxy = CommProxy {
+            rank: comm.rank(),
+            size: comm.size(),
+            jobs,
+            recv_timeout: Arc::default(),
+            shutdown,
+            progress: None,
+        };
+        let progress = Progress {
+            jobs: jobs_receiver,
+            shutdown: shutdown_receiver,
+        };
+        (proxy, progress)
+    }
+
+    /// Spawns the progress thread, which creates the communicator with `create`, so that it
+    /// does not have to be `Send`.
+    pub fn spawn<F>(create: F) -> Result<Self, CommError>
+    where
+        F: FnOnce() -> Result<C, CommError> + Send + 'static,
+    {
+        let (jobs, jobs_receiver) = mpsc::unbounded_channel();
+        let (shutdown, shutdown_receiver) = watch::channel(false);
+        let progress = Progress {
+            jobs: jobs_receiver,
+            shutdown: shutdown_receiver,
+        };
+        let (created, creation) = std::sync::mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("comm-proxy".to_string())
+            .spawn(move || {
+                let comm = match create() {
+                    Ok(comm) => comm,
+                    Err(e) => {
+                        let _ = created.send(Err(e));
+                        return Ok(());
+                    }
+                };
+                let _ = created.send(Ok((comm.rank(), comm.size())));
+                progress.run(&comm)
+            })?;
+        let (rank, size) = match creation.recv() {
+            Ok(created) => created?,
+            Err(_) => panic::resume_unwind(thread.join().unwrap_err()),
+        };
+        Ok(CommProxy {
+            rank,
+            size,
+            jobs,
+            recv_timeout: Arc::default(),
+            shutdown,
+            progress: Some(thread),
+        })
+    }
+
+    /// A new handle for a worker thread.
+    pub fn handle(&self) -> ProxyHandle<C> {
+        ProxyHandle {
+            rank: self.rank,
+            size: self.size,
+            jobs: self.jobs.clone(),
+            recv_timeout: self.recv_timeout.clone(),
+        }
+    }
+
+    /// Tells the progress thread to finish the operations posted so far and stop. Waits for it
+    /// if it was spawned by [`CommProxy::spawn`].
+    pub fn shutdown(mut self) -> Result<(), CommError> {
+        self.shutdown.send_replace(true);
+        match self.progress.take() {
+            Some(thread) => thread
+                .join()
+                .unwrap_or_else(|payload| panic::resume_unwind(payload)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<C> Drop for CommProxy<C> {
+    fn drop(&mut self) {
+        self.shutdown.send_replace(true);
+        if let Some(thread) = self.progress.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Non-blocking operation of the communicator, started for a handle.
+struct Outstanding<'a> {
+    request: CommRequest<'a>,
+    done: Done<Vec<u8>>,
+    /// Source of a receive.
+    source: Option<u32>,
+    /// Buffer of a receive that took the request over from one that timed out.
+    buffer: Option<Vec<u8>>,
+}
+
+impl<C: TestCommunicator> Progress<C> {
+    /// Serves the handles until [`CommProxy::shutdown`] is called or the proxy and all handles
+    /// are dropped. Operations posted before still complete, so they must be able to.
+    pub fn run(mut self, comm: &C) -> Result<(), CommError> {
+        // only waits for the next job; the communicator is driven outside of it, as it may run
+        // its own runtime
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()?;
+        let mut outstanding = Vec::new();
+        let mut unclaimed = Unclaimed::new();
+        let mut stopped = false;
+        let mut idle_rounds = 0;
+        while !stopped {
+            let mut active = false;
+            loop {
+                match self.jobs.try_recv() {
+                    Ok(job) => {
+                        start(comm, job, &mut outstanding, &mut unclaimed);
+                        active = true;
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        stopped = true;
+                        break;
+                    }
+                }
+            }
+            let before = outstanding.len();
+            outstanding = progress(outstanding, &mut unclaimed);
+            active |= outstanding.len() < before;
+            stopped |= *self.shutdown.borrow();
+
+            if !stopped && outstanding.is_empty() {
+                let next = runtime.block_on(async {
+                    tokio::select! {
+                        // jobs posted before the shutdown must still be started
+                        biased;
+                        job = self.jobs.recv() => job.ok_or(true),
+                        changed = self.shutdown.changed() => {
+                            // the proxy is gone if the sender is
+                            Err(changed.is_err() || *self.shutdown.borrow())
+                        }
+                    }
+                });
+                match next {
+                    Ok(job) => start(comm, job, &mut outstanding, &mut unclaimed),
+                    Err(stop) => stopped = stop,
+                }
+            } else if !outstanding.is_empty() {
+                idle_rounds = if active { 0 } else { idle_rounds + 1 };
+                if idle_rounds < SPIN_ROUNDS {
+                    thread::yield_now();
+                } else {
+                    // nothing moves, so stop burning the core, but still take new jobs at once
+                    let backoff = backoff(idle_rounds - SPIN_ROUNDS);
+                    let next = runtime
+                        .block_on(async { tokio::time::timeout(backoff, self.jobs.recv()).await });
+                    if let Ok(Some(job)) = next {
+                        start(comm, job, &mut outstanding, &mut unclaimed);
+                        idle_rounds = 0;
+                    }
+                }
+            }
+        }
+
+        for operation in outstanding {
+            // nobody waits for a receive that timed out, and it may never complete
+            if !operation.done.is_closed() {
+                let _ = operation
+                    .done
+                    .send(complete(operation.request, operation.buffer));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn start<'a, C: TestCommunicator>(
+    comm: &'a C,
+    job: Job<C>,
+    outstanding: &mut Vec<Outstanding<'a>>,
+    unclaimed: &mut Unclaimed,
+) {
+    let (request, done, source) = match job {
+        Job::Isend { buffer, dest, done } => (comm.isend(buffer, dest), done, None),
+        Job::Irecv {
+            mut buffer,
+            source,
+            done,
+        } => {
+            // messages of receives that timed out come first, in the order they were received
+            if let Some(payload) = unclaimed.get_mut(&source).and_then(VecDeque::pop_front) {
+                let result = matching::fill_buffer(&mut buffer, &payload).map(|()| buffer);
+                let _ = done.send(result);
+                return;
+            }
+            let abandoned = outstanding
+                .iter_mut()
+                .find(|operation| operation.source == Some(source) && operation.done.is_closed());
+            if let Some(operation) = abandoned {
+                operation.done = done;
+                operation.buffer = Some(buffer);
+                return;
+            }
+            (comm.irecv(buffer, source), done, Some(source))
+        }
+        Job::Run(run) => return run(comm),
+        Job::Blocking(run)
+            if outstanding.is_empty() && unclaimed.values().all(VecDeque::is_empty) =>
+        {
+            return run(Ok(comm))
+        }
+        Job::Blocking(run) => return run(Err(busy())),
+    };
+    match request {
+        Ok(request) => outstanding.push(Outstanding {
+            request,
+            done,
+            source,
+            buffer: None,
+        }),
+        Err(e) => {
+            let _ = done.send(Err(e));
+        }
+    }
+}
+
+/// Tests every outstanding operation once and hands the completed ones back to their handles.
+/// Messages of receives that timed out are kept in `unclaimed`.
+fn progress<'a>(
+    outstanding: Vec<Outstanding<'a>>,
+    unclaimed: &mut Unclaimed,
+) -> Vec<Outstanding<'a>> {
+    let mut pending = Vec::with_capacity(outstanding.len());
+    for mut operation in outstanding {
+        match operation.request.test() {
+            Ok(false) => pending.push(operation),
+            Ok(true) => match operation.source {
+                Some(source) if operation.done.is_closed() => {
+                    if let Ok(payload) = operation.request.wait() {
+                        unclaimed.entry(source).or_default().push_back(payload);
+                    }
+                }
+                _ => {
+                    let result = complete(operation.request, operation.buffer);
+                    let _ = operation.done.send(result);
+                }
+            },
+            Err(e) => {
+                let _ = operation.done.send(Err(e));
+            }
+        }
+    }
+    pending
+}
+
+/// Result of a completed operation, copied into `buffer` if another receive took it over.
+fn complete(request: CommRequest<'_>, buffer: Option<Vec<u8>>) -> Result<Vec<u8>, CommError> {
+    let payload = request.wait()?;
+    match buffer {
+        Some(mut buffer) => {
+            matching::fill_buffer(&mut buffer, &payload)?;
+            Ok(buffer)
+        }
+        None => Ok(payload),
+    }
+}
+
+/// Sleep of the progress thread after `rounds` rounds of spinning without progress.
+fn backoff(rounds: u32) -> Duration {
+    MIN_BACKOFF
+        .saturating_mul(1 << rounds.min(10))
+        .min(MAX_BACKOFF)
+}
+
+fn busy() -> CommError {
+    CommError::Unsupported(
+        "blocking operations while sends or receives of the proxy are outstanding",
+    )
+}
+
+/// Waits for `receiver` for up to `timeout` outside of a runtime, parking the thread until the
+/// progress thread sends the result.
+fn wait_for<T>(
+    mut receiver: oneshot::Receiver<Result<T, CommError>>,
+    timeout: Duration,
+) -> Result<T, CommError> {
+    let deadline = Instant::now() + timeout;
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(result) = Pin::new(&mut receiver).poll(&mut context) {
+            return result.unwrap_or_else(|_| Err(shut_down()));
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(CommError::Timeout);
+        }
+        thread::park_timeout(deadline - now);
+    }
+}
+
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+fn shut_down() -> CommError {
+    CommError::Io(io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "The progress thread of the proxy has shut down",
+    ))
+}
+
+/// Access of a worker thread to the communicator of a [`CommProxy`].
+///
+/// Tagged sends and receives, receives with a timeout, `probe`, `recv_vec`, `barrier` and the
+/// collective operations have no non-blocking counterpart, so they block the progress thread
+/// until they return. No outstanding send or receive makes progress meanwhile, and if the
+/// blocking operation waits for a rank that in turn waits for one of them, both deadlock. These
+/// operations therefore fail with [`CommError::Unsupported`] while sends or receives of any
+/// handle are outstanding.
+///
+/// A receive that times out, see [`TestCommunicator::set_recv_timeout`], stays posted on the
+/// progress thread. The message it receives goes to the next receive from the same rank, and
+/// the blocking operations fail until then.
+pub struct ProxyHandle<C> {
+    rank: u32,
+    size: u32,
+    jobs: UnboundedSender<Job<C>>,
+    recv_timeout: Arc<RecvTimeout>,
+}
+
+impl<C> Clone for ProxyHandle<C> {
+    fn clone(&self) -> Self {
+        ProxyHandle {
+            rank: self.rank,
+            size: self.size,
+            jobs: self.jobs.clone(),
+            recv_timeout: self.recv_timeout.clone(),
+        }
+    }
+}
+
+/// Operation handed to the progress thread with [`ProxyHandle::post`].
+pub struct Posted<T> {
+    receiver: oneshot::Receiver<Result<T, CommError>>,
+}
+
+impl<T> Posted<T> {
+    /// Blocks until the progress thread has run the operation and returns its result.
+    pub fn wait(self) -> Result<T, CommError> {
+        self.receiver
+            .blocking_recv()
+            .unwrap_or_else(|_| Err(shut_down()))
+    }
+}
+
+impl<C: 'static> ProxyHandle<C> {
+    /// Hands `operation` to the progress thread, which runs it on the communicator and keeps the
+    /// result for [`try_complete`](Self::try_complete) or [`Posted::wait`].
+    pub fn post<T, F>(&self, operation: F) -> Result<Posted<T>, CommError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&C) -> Result<T, CommError> + Send + 'static,
+    {
+        let (done, receiver) = oneshot::channel();
+        self.submit(Job::Run(Box::new(move |comm| {
+            let _ = done.send(operation(comm));
+        })))?;
+        Ok(Posted { receiver })
+    }
+
+    /// Returns the result of `posted` once the progress thread has run it, without blocking. The
+    /// result can only be taken once.
+    pub fn try_complete<T>(&self, posted: &mut Posted<T>) -> Option<Result<T, CommError>> {
+        match posted.receiver.try_recv() {
+            Ok(result) => Some(result),
+            Err(oneshot::error::TryRecvError::Empty) => None,
+            Err(oneshot::error::TryRecvError::Closed) => Some(Err(shut_down())),
+        }
+    }
+
+    /// Runs `operation` on the progress thread and waits for it, unless sends or receives are
+    /// outstanding.
+    fn run_blocking<T, F>(&self, operation: F) -> Result<T, CommError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&C) -> Result<T, CommError> + Send + 'static,
+    {
+        let (done, receiver) = oneshot::channel();
+        self.submit(Job::Blocking(Box::new(move |comm| {
+            let _ = done.send(comm.and_then(operation));
+        })))?;
+        Posted { receiver }.wait()
+    }
+
+    fn submit(&self, job: Job<C>) -> Result<(), CommError> {
+        self.jobs.send(job).map_err(|_| shut_down())
+    }
+}
+
+struct ProxyRequest {
+    receiver: oneshot::Receiver<Result<Vec<u8>, CommError>>,
+    result: Option<Result<Vec<u8>, CommError>>,
+    /// Deadline of `wait` for a receive.
+    timeout: Option<Duration>,
+}
+
+impl PendingRequest for ProxyRequest {
+    fn test(&mut self) -> Result<bool, CommError> {
+        if self.result.is_none() {
+            self.result = match self.receiver.try_recv() {
+                Ok(result) => Some(result),
+                Err(oneshot::error::TryRecvError::Empty) => None,
+                Err(oneshot::error::TryRecvError::Closed) => return Err(shut_down()),
+            };
+        }
+        Ok(self.result.is_some())
+    }
+
+    fn wait(self: Box<Self>) -> Result<Vec<u8>, CommError> {
+        match (self.result, self.timeout) {
+            (Some(result), _) => result,
+            (None, Some(timeout)) => wait_for(self.receiver, timeout),
+            (None, None) => Posted {
+                receiver: self.receiver,
+            }
+            .wait(),
+        }
+    }
+}
+
+impl<C: TestCommunicator + 'static> TestCommunicator for ProxyHandle<C> {
+    fn rank(&self) -> u32 {
+        self.rank
+    }
+
+    fn size(&self) -> u32 {
+        self.size
+    }
+
+    fn send(&self, buffer: &[u8], dest: u32) -> Result<(), CommError> {
+        self.isend(buffer.to_vec(), dest)?.wait()?;
+        Ok(())
+    }
+
+    fn recv(&self, buffer: &mut [u8], source: u32) -> Result<usize, CommError> {
+        let received = self.irecv(vec![0; buffer.len()], source)?.wait()?;
+        // a short message leaves the rest of the buffer as it was
+        buffer[..received.len()].copy_from_slice(&received);
+        Ok(received.len())
+    }
+
+    /// Blocks the progress thread, see [`ProxyHandle`].
+    fn barrier(&self) -> Result<(), CommError> {
+        self.run_blocking(|comm| comm.barrier())
+    }
+
+    fn isend(&self, buffer: Vec<u8>, dest: u32) -> Result<CommRequest<'_>, CommError> {
+        let (done, receiver) = oneshot::channel();
+        self.submit(Job::Isend { buffer, dest, done })?;
+        Ok(CommRequest::new(ProxyRequest {
+            receiver,
+            result: None,
+            timeout: None,
+        }))
+    }
+
+    fn irecv(&self, buffer: Vec<u8>, source: u32) -> Result<CommRequest<'_>, CommError> {
+        let (done, receiver) = oneshot::channel();
+        self.submit(Job::Irecv {
+            buffer,
+            source,
+            done,
+        })?;
+        Ok(CommRequest::new(ProxyRequest {
+            receiver,
+            result: None,
+            timeout: self.recv_timeout.get(),
+        }))
+    }
+
+    /// Blocks the progress thread, see [`ProxyHandle`].
+    fn send_tagged(&self, buffer: &[u8], dest: u32, tag: u32) -> Result<(), CommError> {
+        let buffer = buffer.to_vec();
+        self.run_blocking(move |comm| comm.send_tagged(&buffer, dest, tag))
+    }
+
+    /// Blocks the progress thread, see [`ProxyHandle`].
+    fn recv_tagged(&self, buffer: &mut [u8], source: u32, tag: u32) -> Result<usize, CommError> {
+        let mut received = buffer.to_vec();
+        let (received, len) = self.run_blocking(move |comm| {
+            let len = comm.recv_tagged(&mut received, source, tag)?;
+            Ok((received, len))
+        })?;
+        buffer.copy_from_slice(&received);
+        Ok(len)
+    }
+
+    /// Blocks the progress thread, see [`ProxyHandle`].
+    fn probe(&self, source: u32) -> Result<Status, CommError> {
+        self.run_blocking(move |comm| comm.probe(source))
+    }
+
+    /// Blocks the progress thread, see [`ProxyHandle`].
+    fn recv_vec(&self, source: u32) -> Result<Vec<u8>, CommError> {
+        self.run_blocking(move |comm| comm.recv_vec(source))
+    }
+
+    /// Blocks the progress thread for up to `timeout`, see [`ProxyHandle`].
+    fn recv_timeout(
+        &self,
+        buffer: &mut [u8],
+        source: u32,
+        timeout: Duration,
+    ) -> Result<usize, CommError> {
+        let mut received = buffer.to_vec();
+        let (received, len) = self.run_blocking(move |comm| {
+            let len = comm.recv_timeout(&mut received, source, timeout)?;
+            Ok((received, len))
+        })?;
+        buffer.copy_from_slice(&received);
+        Ok(len)
+    }
+
+    /// Sets the deadline of `recv` and of waiting for an `irecv` of all handles, and that of the
+    /// communicator for the operations that run on the progress thread.
+    fn set_recv_timeout(&self, timeout: Option<Duration>) -> Result<(), CommError> {
+        self.recv_timeout.set(timeout);
+        self.post(move |comm| comm.set_recv_timeout(timeout))?
+            .wait()
+    }
+
+    // The collective operations run those of the communicator on the progress thread, which
+    // blocks it like the receives above.
+
+    fn broadcast(&self, buffer: &mut [u8], root: u32) -> Result<(), CommError> {
+        let mut received = buffer.to_vec();
+        let received = self.run_blocking(move |comm| {
+            comm.broadcast(&mut received, root)?;
+            Ok(received)
+        })?;
+        buffer.copy_from_slice(&received);
+        Ok(())
+    }
+
+    fn reduce<T: Reducible>(
+        &self,
+        send: &[T],
+        recv: &mut [T],
+        op: ReduceOp,
+        root: u32,
+    ) -> Result<(), CommError> {
+        let send = send.to_vec();
+        let mut reduced = recv.to_vec();
+        let reduced = self.run_blocking(move |comm| {
+            comm.reduce(&send, &mut reduced, op, root)?;
+            Ok(reduced)
+        })?;
+        recv.copy_from_slice(&reduced);
+        Ok(())
+    }
+
+    fn allreduce<T: Reducible>(
+        &self,
+        send: &[T],
+        recv: &mut [T],
+        op: ReduceOp,
+    ) -> Result<(), CommError> {
+        let send = send.to_vec();
+        let mut reduced = recv.to_vec();
+        let reduced = self.run_blocking(move |comm| {
+            comm.allreduce(&send, &mut reduced, op)?;
+            Ok(reduced)
+        })?;
+        recv.copy_from_slice(&reduced);
+        Ok(())
+    }
+
+    fn gather(&self, send: &[u8], recv: &mut [u8], root: u32) -> Result<(), CommError> {
+        let send = send.to_vec();
+        let mut received = recv.to_vec();
+        let received = self.run_blocking(move |comm| {
+            comm.gather(&send, &mut received, root)?;
+            Ok(received)
+        })?;
+        recv.copy_from_slice(&received);
+        Ok(())
+    }
+
+    fn allgather(&self, send: &[u8], recv: &mut [u8]) -> Result<(), CommError> {
+        let send = send.to_vec();
+        let mut received = recv.to_vec();
+        let received = self.run_blocking(move |comm| {
+            comm.allgather(&send, &mut received)?;
+            Ok(received)
+        })?;
+        recv.copy_from_slice(&received);
+        Ok(())
+    }
+
+    fn scatter(&self, send: &[u8], recv: &mut [u8], root: u32) -> Result<(), CommError> {
+        let send = send.to_vec();
+        let mut received = recv.to_vec();
+        let received = self.run_blocking(move |comm| {
+            comm.scatter(&send, &mut received, root)?;
+            Ok(received)
+        })?;
+        recv.copy_from_slice(&received);
+        Ok(())
+    }
+
+    fn alltoall(&self, send: &[u8], recv: &mut [u8]) -> Result<(), CommError> {
+        let send = send.to_vec();
+        let mut received = recv.to_vec();
+        let received = self.run_blocking(move |comm| {
+            comm.alltoall(&send, &mut received)?;
+            Ok(received)
+        })?;
+        recv.copy_from_slice(&received);
+        Ok(())
+    }
+
+    fn alltoallv(
+        &self,
+        send: &[u8],
+        send_counts: &[usize],
+        recv: &mut [u8],
+        recv_counts: &[usize],
+    ) -> Result<(), CommError> {
+        let send = send.to_vec();
+        let send_counts = send_counts.to_vec();
+        let recv_counts = recv_counts.to_vec();
+        let mut received = recv.to_vec();
+        let received = self.run_blocking(move |comm| {
+            comm.alltoallv(&send, &send_counts, &mut received, &recv_counts)?;
+            Ok(received)
+        })?;
+        recv.copy_from_slice(&received);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communicator::ChannelSimCommunicator;
+
+    /// A proxy for rank 0 and the communicator of rank 1 of a fresh pair.
+    fn proxy_and_peer() -> (CommProxy<ChannelSimCommunicator>, ChannelSimCommunicator) {
+        let mut comms = ChannelSimCommunicator::create(2, None);
+        let peer = comms.pop().unwrap();
+        let comm = comms.pop().unwrap();
+        (CommProxy::spawn(move || Ok(comm)).unwrap(), peer)
+    }
+
+    #[test]
+    fn handles_send_and_receive_concurrently() {
+        let (proxy, peer) = proxy_and_peer();
+        let workers = 4u8;
+        let received = thread::scope(|scope| {
+            let handles: Vec<_> = (0..workers)
+                .map(|worker| {
+                    let handle = proxy.handle();
+                    scope.spawn(move || {
+                        handle.isend(vec![worker], 1).unwrap().wait().unwrap();
+                        handle.irecv(vec![0; 4], 1).unwrap().wait().unwrap()[0]
+                    })
+                })
+                .collect();
+            for _ in 0..workers {
+                let message = peer.recv_vec(0).unwrap();
+                peer.send(&[message[0] + 10], 0).unwrap();
+            }
+            let mut received: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+            received.sort();
+            received
+        });
+        assert_eq!(received, [10, 11, 12, 13]);
+        proxy.shutdown().unwrap();
+    }
+
+    #[test]
+    fn posted_operations_complete_once() {
+        let (proxy, _peer) = proxy_and_peer();
+        let handle = proxy.handle();
+        let mut posted = handle.post(|comm| Ok(comm.rank() + comm.size())).unwrap();
+        let result = loop {
+            if let Some(result) = handle.try_complete(&mut posted) {
+                break result;
+            }
+            thread::yield_now();
+        };
+        assert_eq!(result.unwrap(), 2);
+        assert!(handle.try_complete(&mut posted).unwrap().is_err());
+        let posted = handle.post(|comm| comm.send(&[1], 1)).unwrap();
+        posted.wait().unwrap();
+    }
+
+    #[test]
+    fn blocking_operations_wait_for_outstanding_requests() {
+        let (proxy, peer) = proxy_and_peer();
+        let handle = proxy.handle();
+        let request = handle.irecv(vec![0; 4], 1).unwrap();
+        assert!(matches!(handle.barrier(), Err(CommError::Unsupported(_))));
+        let mut buffer = [0; 4];
+        let tagged = handle.recv_tagged(&mut buffer, 1, 3);
+        assert!(matches!(tagged, Err(CommError::Unsupported(_))));
+
+        peer.send(&[1, 2], 0).unwrap();
+        assert_eq!(request.wait().unwrap(), [1, 2]);
+        thread::scope(|scope| {
+            scope.spawn(|| peer.barrier().unwrap());
+            handle.barrier().unwrap();
+        });
+    }
+
+    #[test]
+    fn shutdown_finishes_posted_sends() {
+        let (proxy, peer) = proxy_and_peer();
+        let handle = proxy.handle();
+        let request = handle.isend(vec![7], 1).unwrap();
+        proxy.shutdown().unwrap();
+        assert_eq!(request.wait().unwrap(), [7]);
+        assert_eq!(peer.recv_vec(0).unwrap(), [7]);
+        assert!(handle.send(&[8], 1).is_err());
+    }
+
+    #[test]
+    fn timed_out_receives_hand_their_message_to_the_next_one() {
+        let (proxy, peer) = proxy_and_peer();
+        let handle = proxy.handle();
+        let timeout = Duration::from_millis(20);
+        handle.set_recv_timeout(Some(timeout)).unwrap();
+        let mut buffer = [0u8; 4];
+        assert!(matches!(
+            handle.recv(&mut buffer, 1),
+            Err(CommError::Timeout)
+        ));
+        let request = handle.irecv(vec![0; 4], 1).unwrap();
+        assert!(matches!(request.wait(), Err(CommError::Timeout)));
+        // both receives are still posted
+        assert!(matches!(handle.barrier(), Err(CommError::Unsupported(_))));
+
+        for message in [&[1][..], &[2, 2], &[3, 3, 3]] {
+            peer.send(message, 0).unwrap();
+        }
+        assert_eq!(handle.recv(&mut buffer, 1).unwrap(), 1);
+        assert_eq!(buffer[..1], [1]);
+        let received = handle.irecv(vec![0; 4], 1).unwrap().wait().unwrap();
+        assert_eq!(received, [2, 2]);
+        assert_eq!(handle.recv(&mut buffer, 1).unwrap(), 3);
+        assert_eq!(buffer[..3], [3, 3, 3]);
+        proxy.shutdown().unwrap();
+    }
+}
@@ -0,0 +1,259 @@
+use super::fragment::Fragment;
+use super::matching::{Header, RESERVED_TAG_START};
+use super::CommError;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Acknowledges a single fragment. The payload holds its message id and offset.
+pub(crate) const ACK_TAG: u32 = RESERVED_TAG_START + 2;
+
+/// Time to wait for an acknowledgement before the fragment is sent again. Doubles with every
+/// retransmission up to `MAX_RTO`.
+const INITIAL_RTO: Duration = Duration::from_millis(20);
+const MAX_RTO: Duration = Duration::from_secs(1);
+
+/// Number of fragments of a message that may be unacknowledged at a time. Small enough for the
+/// default queue of a Unix datagram socket, net.unix.max_dgram_qlen.
+const WINDOW: usize = 8;
+
+/// Acknowledgements kept per rank for sends that are not waiting for them right now. Older ones
+/// are dropped, they belong to messages that have completed long ago.
+const MAX_QUEUED_ACKS: usize = 4 * WINDOW;
+
+/// How long a send keeps retransmitting without any acknowledgement before it fails. Generous
+/// like the barrier timeout, as the receiver may be busy elsewhere.
+const SEND_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How long a communicator keeps answering retransmissions when it is dropped, in case its last
+/// acknowledgements were lost.
+pub(crate) const LINGER: Duration = Duration::from_millis(200);
+
+/// Whether a message with `tag` goes through the reliability layer. Barrier messages have
+/// retries of their own and acknowledgements are never acknowledged.
+pub(crate) fn is_covered(tag: u32) -> bool {
+    tag < RESERVED_TAG_START
+}
+
+pub(crate) fn encode_ack(fragment: &Fragment) -> [u8; 8] {
+    let mut ack = [0; 8];
+    ack[..4].copy_from_slice(&fragment.message.to_le_bytes());
+    ack[4..].copy_from_slice(&fragment.offset.to_le_bytes());
+    ack
+}
+
+fn decode_ack(payload: &[u8]) -> Option<(u32, u32)> {
+    let message = u32::from_le_bytes(payload.get(..4)?.try_into().ok()?);
+    let offset = u32::from_le_bytes(payload.get(4..8)?.try_into().ok()?);
+    Some((message, offset))
+}
+
+/// State of the optional reliability layer of the datagram communicators.
+///
+/// Messages to each destination get consecutive ids, which serve as sequence numbers. The
+/// receiver acknowledges every fragment, including duplicates, and drops fragments of messages
+/// it has delivered already. The sender retransmits the unacknowledged fragments of a message
+/// until all of them are acknowledged, with at most `WINDOW` of them in flight, see
+/// [`Outstanding`].
+pub(crate) struct Reliability {
+    next_message: Vec<AtomicU32>,
+    delivered: Vec<Mutex<Delivered>>,
+    /// Acknowledgements received while waiting for another message.
+    acks: Vec<Mutex<VecDeque<Vec<u8>>>>,
+}
+
+/// Ids of the messages delivered from one source.
+#[derive(Default)]
+struct Delivered {
+    /// All messages before this id have been delivered.
+    below: u32,
+    /// Delivered messages after `below`.
+    ahead: BTreeSet<u32>,
+}
+
+impl Reliability {
+    pub fn new(size: u32) -> Self {
+        Reliability {
+            next_message: (0..size).map(|_| AtomicU32::new(0)).collect(),
+            delivered: (0..size).map(|_| Mutex::default()).collect(),
+            acks: (0..size).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    pub fn next_message(&self, dest: u32) -> u32 {
+        self.next_message[dest as usize].fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Whether `fragment` belongs to a message that was delivered already.
+    pub fn is_duplicate(&self, fragment: &Fragment) -> bool {
+        let delivered = self.delivered[fragment.header.source as usize]
+            .lock()
+            .unwrap();
+        // ids wrap around, so compare them like TCP sequence numbers
+        fragment.message.wrapping_sub(delivered.below) > u32::MAX / 2
+            || delivered.ahead.contains(&fragment.message)
+    }
+
+    /// Keeps an acknowledgement that a receive came across, for a concurrent send to `source`.
+    pub fn queue_ack(&self, source: u32, ack: Vec<u8>) {
+        let mut acks = self.acks[source as usize].lock().unwrap();
+        if acks.len() == MAX_QUEUED_ACKS {
+            acks.pop_front();
+        }
+        acks.push_back(ack);
+    }
+
+    /// Returns a queued acknowledgement from `source` if the receive waits for one.
+    pub fn take_ack(&self, source: u32, tag: Option<u32>) -> Option<(Header, Vec<u8>)> {
+        if tag != Some(ACK_TAG) {
+            return None;
+        }
+        let ack = self.acks[source as usize].lock().unwrap().pop_front()?;
+        let header = Header {
+            source,
+            tag: ACK_TAG,
+            len: ack.len() as u32,
+        };
+        Some((header, ack))
+    }
+
+    pub fn mark_delivered(&self, header: &Header, message: u32) {
+        let mut delivered = self.delivered[header.source as usize].lock().unwrap();
+        if message != delivered.below {
+            delivered.ahead.insert(message);
+            return;
+        }
+        delivered.below = delivered.below.wrapping_add(1);
+        while let Some(next) = delivered.ahead.first().copied() {
+            if next != delivered.below {
+                break;
+            }
+            delivered.ahead.pop_first();
+            delivered.below = delivered.below.wrapping_add(1);
+        }
+    }
+}
+
+/// Fragments of a message being sent that have not been acknowledged yet.
+pub(crate) struct Outstanding {
+    message: u32,
+    unacked: BTreeMap<u32, Vec<u8>>,
+    /// Offsets below this have been sent at least once.
+    sent: u32,
+    rto: Duration,
+    /// When the send fails unless another fragment is acknowledged.
+    deadline: Instant,
+}
+
+impl Outstanding {
+    pub fn new(message: u32, datagrams: impl Iterator<Item = Vec<u8>>) -> Result<Self, CommError> {
+        let mut unacked = BTreeMap::new();
+        for datagram in datagrams {
+            unacked.insert(Fragment::decode(&datagram)?.offset, datagram);
+        }
+        Ok(Outstanding {
+            message,
+            unacked,
+            sent: 0,
+            rto: INITIAL_RTO,
+            deadline: Instant::now() + SEND_TIMEOUT,
+        })
+    }
+
+    /// Returns the fragments to send now: those that entered the window since the last call,
+    /// and all in the window if they are due for retransmission.
+    pub fn window(&mut self, retransmit: bool) -> Vec<&[u8]> {
+        let sent = self.sent;
+        let window: Vec<_> = self.unacked.iter().take(WINDOW).collect();
+        if let Some((&last, _)) = window.last() {
+            self.sent = sent.max(last + 1);
+        }
+        window
+            .into_iter()
+            .filter(|(&offset, _)| retransmit || offset >= sent)
+            .map(|(_, datagram)| datagram.as_slice())
+            .collect()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.unacked.is_empty()
+    }
+
+    /// How long to wait for the next acknowledgement before retransmitting.
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// Handles the payload of an acknowledgement. Late ones of earlier messages are ignored.
+    pub fn acknowledge(&mut self, ack: &[u8]) {
+        let Some((message, offset)) = decode_ack(ack) else {
+            return;
+        };
+        if message == self.message && self.unacked.remove(&offset).is_some() {
+            self.rto = INITIAL_RTO;
+            self.deadline = Instant::now() + SEND_TIMEOUT;
+        }
+    }
+
+    /// Called when no acknowledgement arrived within the retransmission timeout.
+    pub fn back_off(&mut self) -> Result<(), CommError> {
+        if Instant::now() >= self.deadline {
+            return Err(CommError::Timeout);
+        }
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragment(message: u32) -> Fragment<'static> {
+        let header = Header {
+            source: 1,
+            tag: 0,
+            len: 0,
+        };
+        Fragment {
+            header,
+            message,
+            offset: 0,
+            chunk: &[],
+        }
+    }
+
+    fn deliver(reliability: &Reliability, message: u32) {
+        assert!(!reliability.is_duplicate(&fragment(message)));
+        reliability.mark_delivered(&fragment(message).header, message);
+        assert!(reliability.is_duplicate(&fragment(message)));
+    }
+
+    #[test]
+    fn detects_duplicates_out_of_order() {
+        let reliability = Reliability::new(2);
+        deliver(&reliability, 0);
+        deliver(&reliability, 2);
+        assert!(!reliability.is_duplicate(&fragment(1)));
+        deliver(&reliability, 1);
+        assert!(!reliability.is_duplicate(&fragment(3)));
+        let delivered = reliability.delivered[1].lock().unwrap();
+        assert_eq!(delivered.below, 3);
+        assert!(delivered.ahead.is_empty());
+    }
+
+    #[test]
+    fn detects_duplicates_across_wraparound() {
+        let reliability = Reliability::new(2);
+        reliability.delivered[1].lock().unwrap().below = u32::MAX - 1;
+        assert!(reliability.is_duplicate(&fragment(u32::MAX - 2)));
+        deliver(&reliability, 0);
+        deliver(&reliability, u32::MAX - 1);
+        assert!(!reliability.is_duplicate(&fragment(u32::MAX)));
+        deliver(&reliability, u32::MAX);
+        assert!(!reliability.is_duplicate(&fragment(1)));
+        assert!(reliability.is_duplicate(&fragment(u32::MAX - 1)));
+        assert_eq!(reliability.delivered[1].lock().unwrap().below, 1);
+    }
+}
@@ -0,0 +1,135 @@
+use super::address::{self, AddressBook};
+use super::stream::{self, CONNECT_TIMEOUT};
+use super::CommError;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::time::Instant;
+
+/// Hands out ranks and the address table of a job to ranks that do not know each other in
+/// advance. Ranks register with [`register`] and get their rank in the order they register.
+///
+/// The protocol is line based text on a TCP connection. A rank sends
+/// `register <world size> <address>` and receives either `error <reason>`, or `rank <rank>`
+/// followed by the address of every rank on a line of its own.
+pub struct Coordinator {
+    listener: TcpListener,
+}
+
+impl Coordinator {
+    pub fn bind(address: impl ToSocketAddrs) -> Result<Self, CommError> {
+        Ok(Coordinator {
+            listener: TcpListener::bind(address)?,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, CommError> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Waits for `world_size` ranks to register and sends everybody the address table, which
+    /// is also returned. Ranks that register for another world size are turned away.
+    pub fn serve(&self, world_size: u32) -> Result<AddressBook, CommError> {
+        let mut ranks = Vec::with_capacity(world_size as usize);
+        while ranks.len() < world_size as usize {
+            let (stream, _) = self.listener.accept()?;
+            stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            // the rank is at fault, not the job, so it is turned away and the job goes on
+            if reader.read_line(&mut line).is_err() {
+                continue;
+            }
+            let mut stream = reader.into_inner();
+            match parse_registration(&line, world_size) {
+                Ok(address) => ranks.push((stream, address)),
+                Err(reason) => {
+                    let _ = writeln!(stream, "error {}", reason);
+                }
+            }
+        }
+
+        let book = AddressBook::new(ranks.iter().map(|(_, address)| *address).collect());
+        let mut table = String::new();
+        for address in book.addresses() {
+            table.push_str(&format!("{}\n", address));
+        }
+        for (rank, (mut stream, _)) in ranks.into_iter().enumerate() {
+            // a rank that went away in the meantime finds out when the others cannot reach it
+            let _ = write!(stream, "rank {}\n{}", rank, table);
+        }
+        Ok(book)
+    }
+}
+
+fn parse_registration(line: &str, world_size: u32) -> Result<SocketAddr, String> {
+    let mut words = line.split_whitespace();
+    let (Some("register"), Some(size), Some(address), None) =
+        (words.next(), words.next(), words.next(), words.next())
+    else {
+        return Err(format!("invalid registration {:?}", line.trim_end()));
+    };
+    if size.parse() != Ok(world_size) {
+        return Err(format!(
+            "world size {} does not match {} of the job",
+            size, world_size
+        ));
+    }
+    address
+        .parse()
+        .map_err(|_| format!("invalid address {:?}", address))
+}
+
+/// Address to bind a socket to that is reachable from `coordinator`, on a port picked by the
+/// system. The port is then registered with [`register`].
+pub(crate) fn any_address(coordinator: SocketAddr) -> SocketAddr {
+    SocketAddr::new(address::unspecified(coordinator), 0)
+}
+
+/// Registers a rank listening on `port` with the coordinator and returns the assigned rank
+/// and the addresses of all ranks. The host is the local address of the connection to the
+/// coordinator. Waits for the coordinator to come up and for all ranks to register.
+pub(crate) fn register(
+    coordinator: SocketAddr,
+    world_size: u32,
+    port: u16,
+) -> Result<(u32, AddressBook), CommError> {
+    let deadline = Instant::now() + CONNECT_TIMEOUT;
+    let mut stream = stream::retry(
+        deadline,
+        || TcpStream::connect(coordinator),
+        |kind| kind == ErrorKind::ConnectionRefused,
+    )?;
+    stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+    let address = SocketAddr::new(stream.local_addr()?.ip(), port);
+    writeln!(stream, "register {} {}", world_size, address)?;
+
+    let mut lines = BufReader::new(stream).lines();
+    let mut next_line = || -> Result<String, CommError> {
+        match lines.next() {
+            Some(line) => Ok(line?),
+            None => Err(invalid_response("connection closed".to_string())),
+        }
+    };
+    let line = next_line()?;
+    let rank = match line.split_once(' ') {
+        Some(("rank", rank)) => rank
+            .parse::<u32>()
+            .map_err(|_| invalid_response(line.clone()))?,
+        Some(("error", reason)) => return Err(invalid_response(reason.to_string())),
+        _ => return Err(invalid_response(line)),
+    };
+    let addresses = (0..world_size)
+        .map(|_| {
+            let line = next_line()?;
+            line.parse().map_err(|_| invalid_response(line))
+        })
+        .collect::<Result<_, CommError>>()?;
+    Ok((rank, AddressBook::new(addresses)))
+}
+
+fn invalid_response(message: String) -> CommError {
+    CommError::Io(io::Error::new(
+        ErrorKind::InvalidData,
+        format!("Rendezvous failed: {}", message),
+    ))
+}
@@ -0,0 +1,423 @@
+use super::{CommError, MpiCommunicator, ReduceOp, Reducible};
+use mpi::datatype::Equivalence;
+use mpi::ffi;
+use mpi::raw::AsRaw;
+use mpi::topology::Communicator;
+use std::cell::Cell;
+use std::ffi::{c_int, c_void};
+use std::io;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+
+/// Memory every rank of an [`MpiCommunicator`] exposes to the other ranks for one-sided
+/// communication, i.e. an MPI window. Created with [`MpiCommunicator::create_window`].
+///
+/// Puts, gets and accumulates are issued in an [`Epoch`], which only exists within the closure
+/// given to [`fence`](Self::fence) or [`start`](Self::start). The operations have completed
+/// when these return, so the origin buffers are only borrowed until then, and the local memory
+/// cannot be touched while an epoch is open.
+pub struct RmaWindow<'a> {
+    comm: &'a MpiCommunicator,
+    win: ffi::MPI_Win,
+    memory: *mut [u8],
+    open: Cell<OpenEpochs>,
+}
+
+/// Epochs that are open on a window, to reject the ones MPI does not allow at the same time.
+#[derive(Debug, Clone, Copy, Default)]
+struct OpenEpochs {
+    fence: bool,
+    access: bool,
+    exposure: bool,
+}
+
+impl<'a> RmaWindow<'a> {
+    /// `len` has to be the same on all ranks, as offsets are checked against the local memory.
+    pub(crate) fn create(comm: &'a MpiCommunicator, len: usize) -> Result<Self, CommError> {
+        let size = ffi::MPI_Aint::try_from(len)
+            .map_err(|_| invalid_input(format!("Window of {} bytes is too large", len)))?;
+        let memory = Box::into_raw(vec![0u8; len].into_boxed_slice());
+        let mut win = MaybeUninit::uninit();
+        // SAFETY: the memory stays allocated until the window has been freed in `drop`
+        let rc = unsafe {
+            ffi::MPI_Win_create(
+                memory.cast::<c_void>(),
+                size,
+                1,
+                ffi::RSMPI_INFO_NULL,
+                comm.comm.as_raw(),
+                win.as_mut_ptr(),
+            )
+        };
+        if let Err(e) = check("MPI_Win_create", rc) {
+            // SAFETY: MPI did not take the memory
+            drop(unsafe { Box::from_raw(memory) });
+            return Err(e);
+        }
+        Ok(RmaWindow {
+            comm,
+            // SAFETY: initialized by the successful MPI_Win_create
+            win: unsafe { win.assume_init() },
+            memory,
+            open: Cell::default(),
+        })
+    }
+
+    /// Memory of this rank, with what the other ranks have put into it in the epochs that have
+    /// ended.
+    pub fn memory(&mut self) -> &mut [u8] {
+        // SAFETY: no epoch is open, as it would borrow the window until it has ended
+        unsafe { &mut *self.memory }
+    }
+
+    /// Runs `operations` in an epoch in which all ranks may access the memory of all ranks,
+    /// between two calls of `MPI_Win_fence`. All ranks have to call it together. The operations
+    /// have completed at the origins and the targets when it returns.
+    pub fn fence<'b, R>(
+        &self,
+        operations: impl FnOnce(&mut Epoch<'_, 'b>) -> Result<R, CommError>,
+    ) -> Result<R, CommError> {
+        let open = self.open.get();
+        if open.fence || open.access || open.exposure {
+            return Err(already_open("MPI_Win_fence"));
+        }
+        // SAFETY: the window is valid until dropped, which the borrow of `self` prevents
+        let rc = unsafe { ffi::MPI_Win_fence(ffi::MPI_MODE_NOPRECEDE as c_int, self.win) };
+        check("MPI_Win_fence", rc)?;
+        self.run(Synchronization::Fence, operations)
+    }
+
+    /// Runs `operations` in an epoch that accesses the memory of `targets`, between
+    /// `MPI_Win_start` and `MPI_Win_complete`. The targets expose it with [`post`](Self::post).
+    /// The operations have completed at the origin when it returns.
+    pub fn start<'b, R>(
+        &self,
+        targets: &[u32],
+        operations: impl FnOnce(&mut Epoch<'_, 'b>) -> Result<R, CommError>,
+    ) -> Result<R, CommError> {
+        let open = self.open.get();
+        if open.fence || open.access {
+            return Err(already_open("MPI_Win_start"));
+        }
+        let group = self.group(targets)?;
+        // SAFETY: MPI keeps its own reference to the group for the epoch
+        let rc = unsafe { ffi::MPI_Win_start(group.0, 0, self.win) };
+        check("MPI_Win_start", rc)?;
+        self.run(Synchronization::Start, operations)
+    }
+
+    /// Exposes the memory of this rank to the epochs `origins` start with
+    /// [`start`](Self::start), between `MPI_Win_post` and `MPI_Win_wait`, while `during` runs.
+    /// Returns once all origins have ended their epochs, when their operations have completed
+    /// in the local memory.
+    pub fn post<R>(
+        &self,
+        origins: &[u32],
+        during: impl FnOnce() -> Result<R, CommError>,
+    ) -> Result<R, CommError> {
+        let open = self.open.get();
+        if open.fence || open.exposure {
+            return Err(already_open("MPI_Win_post"));
+        }
+        let group = self.group(origins)?;
+        // SAFETY: MPI keeps its own reference to the group for the exposure
+        let rc = unsafe { ffi::MPI_Win_post(group.0, 0, self.win) };
+        check("MPI_Win_post", rc)?;
+        let guard = OpenGuard::new(self, Synchronization::Post);
+        let result = during();
+        let closed = guard.close();
+        let result = result?;
+        closed?;
+        Ok(result)
+    }
+
+    /// Runs `operations` in the epoch that was just opened with `sync`, and ends it.
+    fn run<'b, R>(
+        &self,
+        sync: Synchronization,
+        operations: impl FnOnce(&mut Epoch<'_, 'b>) -> Result<R, CommError>,
+    ) -> Result<R, CommError> {
+        // Ending the epoch is left to a guard instead of the epoch, which the operations only
+        // get borrowed. So it cannot be leaked, and it ends even if the operations panic,
+        // before the origin buffers they were given can go away.
+        let guard = OpenGuard::new(self, sync);
+        let result = operations(&mut Epoch {
+            window: self,
+            buffers: PhantomData,
+        });
+        let closed = guard.close();
+        let result = result?;
+        closed?;
+        Ok(result)
+    }
+
+    fn group(&self, ranks: &[u32]) -> Result<Group, CommError> {
+        let ranks = ranks
+            .iter()
+            .map(|&rank| self.check_rank(rank))
+            .collect::<Result<Vec<c_int>, CommError>>()?;
+        let mut all = MaybeUninit::uninit();
+        // SAFETY: the communicator outlives the window
+        check("MPI_Comm_group", unsafe {
+            ffi::MPI_Comm_group(self.comm.comm.as_raw(), all.as_mut_ptr())
+        })?;
+        let all = Group(unsafe { all.assume_init() });
+        let mut group = MaybeUninit::uninit();
+        check("MPI_Group_incl", unsafe {
+            ffi::MPI_Group_incl(
+                all.0,
+                ranks.len() as c_int,
+                ranks.as_ptr(),
+                group.as_mut_ptr(),
+            )
+        })?;
+        Ok(Group(unsafe { group.assume_init() }))
+    }
+
+    fn check_rank(&self, rank: u32) -> Result<c_int, CommError> {
+        if rank >= self.comm.comm.size() as u32 {
+            return Err(invalid_input(format!(
+                "Rank {} is not in the window's communicator",
+                rank
+            )));
+        }
+        Ok(rank as c_int)
+    }
+
+    /// Checks that `len` bytes at `offset` are within the memory, and converts them for MPI.
+    fn check_range(&self, len: usize, offset: usize) -> Result<ffi::MPI_Aint, CommError> {
+        if offset
+            .checked_add(len)
+            .is_none_or(|end| end > self.memory.len())
+        {
+            return Err(invalid_input(format!(
+                "{} bytes at offset {} are outside of the window of {} bytes",
+                len,
+                offset,
+                self.memory.len()
+            )));
+        }
+        Ok(offset as ffi::MPI_Aint)
+    }
+}
+
+impl Drop for RmaWindow<'_> {
+    fn drop(&mut self) {
+        // SAFETY: no epoch is open, as it would borrow the window, and MPI is done with the
+        // memory once the window is freed
+        unsafe {
+            ffi::MPI_Win_free(&mut self.win);
+            drop(Box::from_raw(self.memory));
+        }
+    }
+}
+
+struct Group(ffi::MPI_Group);
+
+impl Drop for Group {
+    fn drop(&mut self) {
+        // SAFETY: only frees this handle; epochs started with the group keep their own
+        unsafe {
+            ffi::MPI_Group_free(&mut self.0);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Synchronization {
+    Fence,
+    Start,
+    Post,
+}
+
+/// Marks an epoch as open on its window and ends it, at the latest when dropped.
+struct OpenGuard<'w> {
+    window: &'w RmaWindow<'w>,
+    sync: Synchronization,
+    closed: bool,
+}
+
+impl<'w> OpenGuard<'w> {
+    fn new(window: &'w RmaWindow<'w>, sync: Synchronization) -> Self {
+        window.open.set(Self::mark(window.open.get(), sync, true));
+        OpenGuard {
+            window,
+            sync,
+            closed: false,
+        }
+    }
+
+    fn mark(mut open: OpenEpochs, sync: Synchronization, is_open: bool) -> OpenEpochs {
+        match sync {
+            Synchronization::Fence => open.fence = is_open,
+            Synchronization::Start => open.access = is_open,
+            Synchronization::Post => open.exposure = is_open,
+        }
+        open
+    }
+
+    /// Ends the epoch, with `MPI_Win_fence`, `MPI_Win_complete` or `MPI_Win_wait`.
+    fn close(mut self) -> Result<(), CommError> {
+        self.end()
+    }
+
+    fn end(&mut self) -> Result<(), CommError> {
+        self.closed = true;
+        let window = self.window;
+        window
+            .open
+            .set(Self::mark(window.open.get(), self.sync, false));
+        // SAFETY: the window is valid for the lifetime of the guard
+        match self.sync {
+            Synchronization::Fence => check("MPI_Win_fence", unsafe {
+                ffi::MPI_Win_fence(ffi::MPI_MODE_NOSUCCEED as c_int, window.win)
+            }),
+            Synchronization::Start => check("MPI_Win_complete", unsafe {
+                ffi::MPI_Win_complete(window.win)
+            }),
+            Synchronization::Post => {
+                check("MPI_Win_wait", unsafe { ffi::MPI_Win_wait(window.win) })
+            }
+        }
+    }
+}
+
+impl Drop for OpenGuard<'_> {
+    fn drop(&mut self) {
+        if !self.closed {
+            let _ = self.end();
+        }
+    }
+}
+
+/// Access epoch of an [`RmaWindow`], given to the operations of [`RmaWindow::fence`] and
+/// [`RmaWindow::start`]. The operations complete when these return.
+pub struct Epoch<'w, 'b> {
+    window: &'w RmaWindow<'w>,
+    /// Origin buffers of the operations, which MPI reads and writes until the epoch has ended.
+    buffers: PhantomData<&'b mut [u8]>,
+}
+
+impl<'b> Epoch<'_, 'b> {
+    /// Writes `origin` into the memory of `target` at `offset`, with `MPI_Put`.
+    pub fn put(&mut self, origin: &'b [u8], target: u32, offset: usize) -> Result<(), CommError> {
+        let (target, offset, count) = self.target(target, origin.len(), offset)?;
+        let datatype = u8::equivalent_datatype();
+        // SAFETY: `origin` is borrowed until the epoch has ended and MPI has completed the put
+        let rc = unsafe {
+            ffi::MPI_Put(
+                origin.as_ptr().cast::<c_void>(),
+                count,
+                datatype.as_raw(),
+                target,
+                offset,
+                count,
+                datatype.as_raw(),
+                self.window.win,
+            )
+        };
+        check("MPI_Put", rc)
+    }
+
+    /// Reads the memory of `target` at `offset` into `origin`, with `MPI_Get`. `origin` is only
+    /// filled once the epoch has ended.
+    pub fn get(
+        &mut self,
+        origin: &'b mut [u8],
+        target: u32,
+        offset: usize,
+    ) -> Result<(), CommError> {
+        let (target, offset, count) = self.target(target, origin.len(), offset)?;
+        let datatype = u8::equivalent_datatype();
+        // SAFETY: `origin` is borrowed until the epoch has ended and MPI has completed the get
+        let rc = unsafe {
+            ffi::MPI_Get(
+                origin.as_mut_ptr().cast::<c_void>(),
+                count,
+                datatype.as_raw(),
+                target,
+                offset,
+                count,
+                datatype.as_raw(),
+                self.window.win,
+            )
+        };
+        check("MPI_Get", rc)
+    }
+
+    /// Combines `origin` element-wise with `op` into the elements of `target` starting at byte
+    /// `offset`, with `MPI_Accumulate`. Accumulates of several ranks to the same element do not
+    /// get in each other's way.
+    pub fn accumulate<T: Reducible>(
+        &mut self,
+        origin: &'b [T],
+        target: u32,
+        offset: usize,
+        op: ReduceOp,
+    ) -> Result<(), CommError> {
+        let (target, offset, _) = self.target(target, std::mem::size_of_val(origin), offset)?;
+        let count = to_count(origin.len())?;
+        let datatype = T::equivalent_datatype();
+        // SAFETY: `origin` is borrowed until the epoch has ended and MPI has completed the
+        // accumulate
+        let rc = unsafe {
+            ffi::MPI_Accumulate(
+                origin.as_ptr().cast::<c_void>(),
+                count,
+                datatype.as_raw(),
+                target,
+                offset,
+                count,
+                datatype.as_raw(),
+                op.to_mpi().as_raw(),
+                self.window.win,
+            )
+        };
+        check("MPI_Accumulate", rc)
+    }
+
+    fn target(
+        &self,
+        target: u32,
+        len: usize,
+        offset: usize,
+    ) -> Result<(c_int, ffi::MPI_Aint, c_int), CommError> {
+        Ok((
+            self.window.check_rank(target)?,
+            self.window.check_range(len, offset)?,
+            to_count(len)?,
+        ))
+    }
+}
+
+fn to_count(len: usize) -> Result<c_int, CommError> {
+    c_int::try_from(len).map_err(|_| {
+        invalid_input(format!(
+            "{} elements are too many for a single MPI operation",
+            len
+        ))
+    })
+}
+
+/// MPI aborts on errors with its default error handler, so this only fails if the handler of
+/// the window was changed.
+fn check(function: &str, rc: c_int) -> Result<(), CommError> {
+    if rc != ffi::MPI_SUCCESS as c_int {
+        return Err(CommError::Io(io::Error::other(format!(
+            "{} failed with error code {}",
+            function, rc
+        ))));
+    }
+    Ok(())
+}
+
+fn already_open(function: &str) -> CommError {
+    invalid_input(format!(
+        "{} while another epoch is open on the window",
+        function
+    ))
+}
+
+fn invalid_input(message: String) -> CommError {
+    CommError::Io(io::Error::new(io::ErrorKind::InvalidInput, message))
+}
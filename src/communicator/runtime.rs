@@ -0,0 +1,127 @@
+use super::CommError;
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+use tokio::runtime::{EnterGuard, Handle, Runtime, RuntimeFlavor};
+use tokio::task::JoinHandle;
+
+/// Runtime that drives a tokio based communicator.
+///
+/// Communicators created for the blocking [`TestCommunicator`](super::TestCommunicator) API own
+/// one and enter it for every operation. Those created in an async context are driven by the
+/// runtime of the caller and only offer the
+/// [`AsyncTestCommunicator`](super::AsyncTestCommunicator) API.
+pub(crate) struct CommRuntime {
+    owned: Option<Runtime>,
+}
+
+impl CommRuntime {
+    pub fn current_thread() -> io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        Ok(CommRuntime {
+            owned: Some(runtime),
+        })
+    }
+
+    /// For communicators with background tasks, which progress while the caller is outside of
+    /// the runtime.
+    pub fn multi_thread(worker_threads: usize) -> io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(worker_threads)
+            .enable_all()
+            .build()?;
+        Ok(CommRuntime {
+            owned: Some(runtime),
+        })
+    }
+
+    /// The runtime of the caller, which has to be in an async context.
+    pub fn ambient() -> Self {
+        CommRuntime { owned: None }
+    }
+
+    /// Runs `future` to completion on the own runtime.
+    pub fn block_on<T>(
+        &self,
+        future: impl Future<Output = Result<T, CommError>>,
+    ) -> Result<T, CommError> {
+        match &self.owned {
+            Some(runtime) => runtime.block_on(future),
+            None => Err(CommError::Unsupported(
+                "blocking operations on a communicator created in an async context",
+            )),
+        }
+    }
+
+    /// Runs `future` to completion while a communicator is dropped, which cannot await. The
+    /// runtime of an async caller can only be blocked if it is multi threaded, otherwise `future`
+    /// is not run and `None` returned.
+    pub fn block_on_drop<T>(&self, future: impl Future<Output = T>) -> Option<T> {
+        if let Some(runtime) = &self.owned {
+            return Some(runtime.block_on(future));
+        }
+        let handle = Handle::try_current().ok()?;
+        if handle.runtime_flavor() != RuntimeFlavor::MultiThread {
+            return None;
+        }
+        Some(tokio::task::block_in_place(|| handle.block_on(future)))
+    }
+
+    /// Spawns `future` on the own runtime or the one of the caller. `None` outside of a runtime.
+    pub fn spawn<F>(&self, future: F) -> Option<JoinHandle<F::Output>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        match &self.owned {
+            Some(runtime) => Some(runtime.spawn(future)),
+            None => Some(Handle::try_current().ok()?.spawn(future)),
+        }
+    }
+
+    /// Fails for an own runtime. Its IO driver only runs within `block_on`, so the caller's
+    /// runtime would wait forever.
+    pub fn check_ambient(&self) -> Result<(), CommError> {
+        match self.owned {
+            Some(_) => Err(CommError::Unsupported(
+                "async operations on a communicator created for blocking operations",
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Enters the own runtime, e.g. to register sockets with it. The runtime of the caller is
+    /// entered already.
+    pub fn enter(&self) -> Option<EnterGuard<'_>> {
+        self.owned.as_ref().map(Runtime::enter)
+    }
+}
+
+/// Runs blocking work on the blocking pool of the current runtime. Panics of `work` are passed
+/// on to the caller.
+pub(crate) async fn spawn_blocking<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T, CommError> + Send + 'static,
+) -> Result<T, CommError> {
+    match tokio::task::spawn_blocking(work).await {
+        Ok(result) => result,
+        Err(e) => match e.try_into_panic() {
+            Ok(panic) => std::panic::resume_unwind(panic),
+            Err(e) => Err(CommError::Io(io::Error::other(e))),
+        },
+    }
+}
+
+/// Runs `future` to completion, or fails with [`CommError::Timeout`] once `timeout` has passed.
+pub(crate) async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    future: impl Future<Output = Result<T, CommError>>,
+) -> Result<T, CommError> {
+    match timeout {
+        Some(timeout) => {
+            (tokio::time::timeout(timeout, future).await).map_err(|_elapsed| CommError::Timeout)?
+        }
+        None => future.await,
+    }
+}
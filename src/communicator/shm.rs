@@ -0,0 +1,676 @@
+use super::barrier;
+use super::matching::{
+    self, Header, Mailbox, RecvTimeout, Wait, DEFAULT_MAX_MESSAGE_LEN, DEFAULT_TAG, HEADER_LEN,
+};
+use super::{CommError, CommRequest, PendingRequest, Status, TestCommunicator};
+use clap::Parser;
+use std::fs::{self, File, OpenOptions};
+use std::hint;
+use std::io::{self, ErrorKind};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SHM_DIR: &str = "/dev/shm";
+const FILE_PREFIX: &str = "rust-hpc-communication-test";
+
+/// Job id of ranks that are not given one and do not run in a Slurm job.
+const DEFAULT_JOB_ID: &str = "local";
+
+/// Bytes of payload space of every ring. Larger messages are passed through in pieces.
+const RING_CAPACITY: usize = 1 << 20;
+
+/// How long setting up the rings waits for the other ranks to show up.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(120);
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Busy polls before a waiting rank starts to yield its CPU.
+const SPIN_LIMIT: u32 = 1 << 12;
+
+// states of a ring, see `RingHeader::state`
+const READY: u32 = 1;
+const CONNECTED: u32 = 2;
+const CLOSED: u32 = 3;
+
+#[repr(C, align(64))]
+struct CachePadded<T>(T);
+
+/// Start of every ring file, followed by `RING_CAPACITY` bytes of data. The positions only
+/// grow, the position in the data is taken modulo the capacity.
+#[repr(C)]
+struct RingHeader {
+    /// Set to `READY` by the consumer once the ring is initialized, to `CONNECTED` by the
+    /// producer once it has mapped it, and to `CLOSED` by either side when it goes away.
+    state: CachePadded<AtomicU32>,
+    /// Read position, only written by the consumer.
+    head: CachePadded<AtomicU64>,
+    /// Write position, only written by the producer.
+    tail: CachePadded<AtomicU64>,
+}
+
+const RING_FILE_LEN: usize = std::mem::size_of::<RingHeader>() + RING_CAPACITY;
+
+#[derive(Parser, Debug, Clone)]
+pub struct ShmArguments {
+    /// Identifies the job in the names of the ring files, so that jobs running on the same node
+    /// at the same time do not share rings.
+    #[arg(long, env = "SLURM_JOB_ID", default_value = DEFAULT_JOB_ID)]
+    pub job_id: String,
+    /// Longest message received. Longer ones fail the receive, so that a corrupt header cannot
+    /// make a rank allocate arbitrary amounts of memory.
+    #[arg(long, default_value_t = DEFAULT_MAX_MESSAGE_LEN)]
+    pub max_message_len: usize,
+}
+
+impl Default for ShmArguments {
+    fn default() -> Self {
+        ShmArguments {
+            job_id: default_job_id(),
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+        }
+    }
+}
+
+/// Job id used by `create_n_2_n`: the Slurm job, if any.
+fn default_job_id() -> String {
+    std::env::var("SLURM_JOB_ID").unwrap_or_else(|_| DEFAULT_JOB_ID.to_string())
+}
+
+/// A ring file mapped into the address space of this process. The file stays open with it, as
+/// the consumer holds a lock on it.
+struct Ring {
+    ptr: NonNull<u8>,
+    file: File,
+}
+
+// The ring is only accessed through atomics and by a single producer and consumer.
+unsafe impl Send for Ring {}
+
+impl Ring {
+    fn map(file: File) -> io::Result<Ring> {
+        // SAFETY: maps a shared file of `RING_FILE_LEN` bytes, the mapping outlives the file
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                RING_FILE_LEN,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Ring {
+            ptr: NonNull::new(ptr.cast()).unwrap(),
+            file,
+        })
+    }
+
+    /// Takes a lock on the file, shared if `exclusive` is false. Returns false if another
+    /// process holds a conflicting one.
+    fn try_lock(&self, exclusive: bool) -> io::Result<bool> {
+        let operation = if exclusive {
+            libc::LOCK_EX
+        } else {
+            libc::LOCK_SH
+        };
+        // SAFETY: the file descriptor is open as long as the ring exists
+        if unsafe { libc::flock(self.file.as_raw_fd(), operation | libc::LOCK_NB) } == 0 {
+            return Ok(true);
+        }
+        let error = io::Error::last_os_error();
+        match error.kind() {
+            ErrorKind::WouldBlock => Ok(false),
+            _ => Err(error),
+        }
+    }
+
+    fn unlock(&self) -> io::Result<()> {
+        // SAFETY: the file descriptor is open as long as the ring exists
+        if unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn header(&self) -> &RingHeader {
+        // SAFETY: the mapping starts with the header, which only consists of atomics
+        unsafe { &*self.ptr.as_ptr().cast::<RingHeader>() }
+    }
+
+    fn state(&self) -> &AtomicU32 {
+        &self.header().state.0
+    }
+
+    fn data(&self) -> *mut u8 {
+        // SAFETY: the data follows the header within the mapping
+        unsafe { self.ptr.as_ptr().add(std::mem::size_of::<RingHeader>()) }
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        // SAFETY: unmaps the mapping created in `Ring::map`, which is not used anymore
+        unsafe {
+            libc::munmap(self.ptr.as_ptr().cast(), RING_FILE_LEN);
+        }
+    }
+}
+
+/// Spins for a while before yielding the CPU, so a waiting rank reacts quickly without
+/// starving the other ranks on the node.
+struct Backoff {
+    spins: u32,
+    limit: u32,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        static LIMIT: OnceLock<u32> = OnceLock::new();
+        // spinning only pays off if the other rank can run at the same time
+        let limit = *LIMIT.get_or_init(|| match thread::available_parallelism() {
+            Ok(cpus) if cpus.get() > 1 => SPIN_LIMIT,
+            _ => 0,
+        });
+        Backoff { spins: 0, limit }
+    }
+
+    fn snooze(&mut self) {
+        if self.spins < self.limit {
+            self.spins += 1;
+            hint::spin_loop();
+        } else {
+            thread::yield_now();
+        }
+    }
+}
+
+/// Writing end of the ring from this rank to another one.
+struct Producer {
+    ring: Ring,
+    dest: u32,
+}
+
+impl Producer {
+    fn write_all(&mut self, mut bytes: &[u8]) -> Result<(), CommError> {
+        let header = self.ring.header();
+        let mut tail = header.tail.0.load(Ordering::Relaxed);
+        let mut backoff = Backoff::new();
+        while !bytes.is_empty() {
+            let free = RING_CAPACITY - (tail - header.head.0.load(Ordering::Acquire)) as usize;
+            if free == 0 {
+                if self.ring.state().load(Ordering::Acquire) == CLOSED {
+                    return Err(CommError::Disconnected { rank: self.dest });
+                }
+                backoff.snooze();
+                continue;
+            }
+            let len = free.min(bytes.len());
+            copy_to_ring(&self.ring, tail, &bytes[..len]);
+            tail += len as u64;
+            header.tail.0.store(tail, Ordering::Release);
+            bytes = &bytes[len..];
+            backoff = Backoff::new();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Producer {
+    fn drop(&mut self) {
+        self.ring.state().store(CLOSED, Ordering::Release);
+    }
+}
+
+/// Reading end of the ring from another rank to this one.
+struct Consumer {
+    ring: Ring,
+    source: u32,
+    max_message_len: usize,
+    /// Message whose payload had not fully arrived when a receive gave up waiting for it. The
+    /// next receive goes on with it.
+    partial: Option<Partial>,
+}
+
+struct Partial {
+    header: Header,
+    payload: Vec<u8>,
+    filled: usize,
+}
+
+impl Consumer {
+    fn available(&self) -> usize {
+        let header = self.ring.header();
+        (header.tail.0.load(Ordering::Acquire) - header.head.0.load(Ordering::Relaxed)) as usize
+    }
+
+    /// Waits until at least `len` bytes are available. Returns false if `wait` does not allow
+    /// to wait any longer.
+    fn wait_for(
+        &self,
+        len: usize,
+        wait: Wait,
+        deadline: Option<Instant>,
+    ) -> Result<bool, CommError> {
+        let mut backoff = Backoff::new();
+        while self.available() < len {
+            if self.ring.state().load(Ordering::Acquire) == CLOSED && self.available() < len {
+                return Err(CommError::Disconnected { rank: self.source });
+            }
+            match (wait, deadline) {
+                (Wait::Poll, _) => return Ok(false),
+                (_, Some(deadline)) if Instant::now() >= deadline => return Ok(false),
+                _ => backoff.snooze(),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Reads as many bytes as are available into the start of `buffer`, and returns how many.
+    fn read_available(&mut self, buffer: &mut [u8]) -> usize {
+        let header = self.ring.header();
+        let head = header.head.0.load(Ordering::Relaxed);
+        let len = self.available().min(buffer.len());
+        copy_from_ring(&self.ring, head, &mut buffer[..len]);
+        header.head.0.store(head + len as u64, Ordering::Release);
+        len
+    }
+
+    /// Reads the next message, or returns `None` if it has not fully arrived within `wait`.
+    fn next_message(
+        &mut self,
+        wait: Wait,
+        deadline: Option<Instant>,
+    ) -> Result<Option<(Header, Vec<u8>)>, CommError> {
+        let mut partial = match self.partial.take() {
+            Some(partial) => partial,
+            None => {
+                if !self.wait_for(HEADER_LEN, wait, deadline)? {
+                    return Ok(None);
+                }
+                let mut header = [0; HEADER_LEN];
+                self.read_available(&mut header);
+                let header = Header::decode(&header)?;
+                matching::check_message_len(&header, self.max_message_len)?;
+                Partial {
+                    payload: vec![0; header.len as usize],
+                    header,
+                    filled: 0,
+                }
+            }
+        };
+        while partial.filled < partial.payload.len() {
+            // the producer is in the middle of writing the message
+            if !self.wait_for(1, wait, deadline)? {
+                self.partial = Some(partial);
+                return Ok(None);
+            }
+            partial.filled += self.read_available(&mut partial.payload[partial.filled..]);
+        }
+        Ok(Some((partial.header, partial.payload)))
+    }
+}
+
+impl Drop for Consumer {
+    fn drop(&mut self) {
+        self.ring.state().store(CLOSED, Ordering::Release);
+    }
+}
+
+fn copy_to_ring(ring: &Ring, position: u64, bytes: &[u8]) {
+    let start = position as usize % RING_CAPACITY;
+    let first = bytes.len().min(RING_CAPACITY - start);
+    // SAFETY: both parts lie within the data of the ring, which the consumer does not read
+    // before the tail is moved past them
+    unsafe {
+        ptr::copy_nonoverlapping(bytes.as_ptr(), ring.data().add(start), first);
+        ptr::copy_nonoverlapping(bytes[first..].as_ptr(), ring.data(), bytes.len() - first);
+    }
+}
+
+fn copy_from_ring(ring: &Ring, position: u64, buffer: &mut [u8]) {
+    let start = position as usize % RING_CAPACITY;
+    let first = buffer.len().min(RING_CAPACITY - start);
+    // SAFETY: both parts lie within the data of the ring, which the producer does not write
+    // before the head is moved past them
+    unsafe {
+        ptr::copy_nonoverlapping(ring.data().add(start), buffer.as_mut_ptr(), first);
+        ptr::copy_nonoverlapping(
+            ring.data(),
+            buffer[first..].as_mut_ptr(),
+            buffer.len() - first,
+        );
+    }
+}
+
+/// Path of the ring from `source` to `dest`. Rings of other users and jobs on the node have
+/// other paths.
+fn ring_path(job_id: &str, source: u32, dest: u32) -> PathBuf {
+    // SAFETY: getuid cannot fail
+    let uid = unsafe { libc::getuid() };
+    Path::new(SHM_DIR).join(format!(
+        "{}-{}-{}-{}-to-{}",
+        FILE_PREFIX, uid, job_id, source, dest
+    ))
+}
+
+fn check_job_id(job_id: &str) -> Result<(), CommError> {
+    if job_id.is_empty() || job_id.contains('/') {
+        return Err(CommError::Io(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("Job id {:?} cannot be part of a file name", job_id),
+        )));
+    }
+    Ok(())
+}
+
+/// Creates the ring from `source` to `rank`, replacing one a crashed run left behind. The ring
+/// only appears under its path once it is initialized, and with a shared lock held on it, which
+/// tells producers that its consumer is alive.
+fn create_ring(job_id: &str, source: u32, rank: u32) -> io::Result<Ring> {
+    let path = ring_path(job_id, source, rank);
+    let staging = path.with_extension(format!("{}.tmp", std::process::id()));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&staging)?;
+    file.set_len(RING_FILE_LEN as u64)?;
+    let ring = Ring::map(file)?;
+    if !ring.try_lock(false)? {
+        return Err(io::Error::new(
+            ErrorKind::WouldBlock,
+            format!("{} is locked by another process", staging.display()),
+        ));
+    }
+    ring.state().store(READY, Ordering::Release);
+    fs::rename(&staging, &path)?;
+    Ok(ring)
+}
+
+/// Opens the ring from `rank` to `dest` once `dest` has created it.
+fn open_ring(job_id: &str, rank: u32, dest: u32, deadline: Instant) -> Result<Ring, CommError> {
+    let path = ring_path(job_id, rank, dest);
+    loop {
+        match OpenOptions::new().read(true).write(true).open(&path) {
+            Ok(file) => {
+                let ring = Ring::map(file)?;
+                // only a crashed run leaves a ring behind without its consumer holding the lock,
+                // which is about to be replaced, so leave it alone
+                if ring.try_lock(true)? {
+                    ring.unlock()?;
+                } else {
+                    let connected = ring.state().compare_exchange(
+                        READY,
+                        CONNECTED,
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                    );
+                    if connected.is_ok() {
+                        return Ok(ring);
+                    }
+                    // connected by a producer of an earlier run that is still alive
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        if Instant::now() >= deadline {
+            return Err(CommError::Timeout);
+        }
+        thread::sleep(CONNECT_RETRY_INTERVAL);
+    }
+}
+
+/// Ranks of one node exchanging messages through lock-free single-producer/single-consumer
+/// rings in shared memory, one for every ordered pair of ranks.
+pub struct ShmCommunicator {
+    rank: u32,
+    outgoing: Vec<Mutex<Producer>>,
+    incoming: Vec<Mutex<Consumer>>,
+    unexpected: Mailbox,
+    recv_timeout: RecvTimeout,
+}
+
+impl TestCommunicator for ShmCommunicator {
+    fn rank(&self) -> u32 {
+        self.rank
+    }
+
+    fn size(&self) -> u32 {
+        self.outgoing.len() as u32
+    }
+
+    fn send(&self, buffer: &[u8], dest: u32) -> Result<(), CommError> {
+        self.send_frame(buffer, dest, DEFAULT_TAG)
+    }
+
+    fn recv(&self, buffer: &mut [u8], source: u32) -> Result<usize, CommError> {
+        let payload = self
+            .receive_matching(source, None, self.recv_timeout.wait())?
+            .ok_or(CommError::Timeout)?;
+        matching::copy_payload(&payload, buffer)
+    }
+
+    fn barrier(&self) -> Result<(), CommError> {
+        barrier::linear_barrier(
+            self.rank,
+            self.size(),
+            |dest, tag| self.send_frame(&[], dest, tag),
+            |source, tag| {
+                self.receive_matching(source, Some(tag), self.recv_timeout.wait())?
+                    .ok_or(CommError::Timeout)?;
+                Ok(())
+            },
+        )
+    }
+
+    fn isend(&self, buffer: Vec<u8>, dest: u32) -> Result<CommRequest<'_>, CommError> {
+        // completes eagerly unless the ring is full
+        self.send(&buffer, dest)?;
+        Ok(CommRequest::completed(buffer))
+    }
+
+    fn irecv(&self, buffer: Vec<u8>, source: u32) -> Result<CommRequest<'_>, CommError> {
+        Ok(CommRequest::new(ShmRecvRequest {
+            comm: self,
+            buffer,
+            source,
+            payload: None,
+        }))
+    }
+
+    fn send_tagged(&self, buffer: &[u8], dest: u32, tag: u32) -> Result<(), CommError> {
+        matching::check_tag(tag)?;
+        self.send_frame(buffer, dest, tag)
+    }
+
+    fn recv_tagged(&self, buffer: &mut [u8], source: u32, tag: u32) -> Result<usize, CommError> {
+        let payload = self
+            .receive_matching(source, Some(tag), self.recv_timeout.wait())?
+            .ok_or(CommError::Timeout)?;
+        matching::copy_payload(&payload, buffer)
+    }
+
+    fn probe(&self, source: u32) -> Result<Status, CommError> {
+        let (header, payload) = self
+            .receive_message(source, None, self.recv_timeout.wait())?
+            .ok_or(CommError::Timeout)?;
+        Ok(self.unexpected.put_back(header, payload))
+    }
+
+    fn recv_vec(&self, source: u32) -> Result<Vec<u8>, CommError> {
+        self.receive_matching(source, None, self.recv_timeout.wait())?
+            .ok_or(CommError::Timeout)
+    }
+
+    fn recv_timeout(
+        &self,
+        buffer: &mut [u8],
+        source: u32,
+        timeout: Duration,
+    ) -> Result<usize, CommError> {
+        let payload = self
+            .receive_matching(source, None, Wait::For(timeout))?
+            .ok_or(CommError::Timeout)?;
+        matching::copy_payload(&payload, buffer)
+    }
+
+    fn set_recv_timeout(&self, timeout: Option<Duration>) -> Result<(), CommError> {
+        self.recv_timeout.set(timeout);
+        Ok(())
+    }
+}
+
+struct ShmRecvRequest<'a> {
+    comm: &'a ShmCommunicator,
+    buffer: Vec<u8>,
+    source: u32,
+    payload: Option<Vec<u8>>,
+}
+
+impl PendingRequest for ShmRecvRequest<'_> {
+    fn test(&mut self) -> Result<bool, CommError> {
+        if self.payload.is_none() {
+            self.payload = self.comm.receive_matching(self.source, None, Wait::Poll)?;
+        }
+        Ok(self.payload.is_some())
+    }
+
+    fn wait(mut self: Box<Self>) -> Result<Vec<u8>, CommError> {
+        let payload = match self.payload.take() {
+            Some(payload) => payload,
+            None => self
+                .comm
+                .receive_matching(self.source, None, self.comm.recv_timeout.wait())?
+                .ok_or(CommError::Timeout)?,
+        };
+        matching::fill_buffer(&mut self.buffer, &payload)?;
+        Ok(self.buffer)
+    }
+}
+
+impl ShmCommunicator {
+    /// Sets up the rings between `n` ranks in `/dev/shm`. Every rank creates the rings it
+    /// receives on and connects to the ones it sends on. The files are removed again once all
+    /// ranks are connected. The rings belong to the Slurm job the rank runs in, if any.
+    pub fn create_n_2_n(n: u32, rank: u32) -> Result<ShmCommunicator, CommError> {
+        Self::create_n_2_n_for_job(&default_job_id(), n, rank)
+    }
+
+    /// Like `create_n_2_n`, with the rings of the job `job_id`, which all ranks have to agree on.
+    pub fn create_n_2_n_for_job(
+        job_id: &str,
+        n: u32,
+        rank: u32,
+    ) -> Result<ShmCommunicator, CommError> {
+        check_job_id(job_id)?;
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        let incoming = (0..n)
+            .map(|source| create_ring(job_id, source, rank))
+            .collect::<Result<Vec<_>, _>>()?;
+        let outgoing = (0..n)
+            .map(|dest| open_ring(job_id, rank, dest, deadline))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (source, ring) in incoming.iter().enumerate() {
+            // the producer may already be gone again, which leaves the ring closed
+            while ring.state().load(Ordering::Acquire) == READY {
+                if Instant::now() >= deadline {
+                    return Err(CommError::Timeout);
+                }
+                thread::sleep(CONNECT_RETRY_INTERVAL);
+            }
+            fs::remove_file(ring_path(job_id, source as u32, rank))?;
+        }
+
+        Ok(ShmCommunicator {
+            rank,
+            outgoing: outgoing
+                .into_iter()
+                .zip(0..)
+                .map(|(ring, dest)| Mutex::new(Producer { ring, dest }))
+                .collect(),
+            incoming: incoming
+                .into_iter()
+                .zip(0..)
+                .map(|(ring, source)| {
+                    Mutex::new(Consumer {
+                        ring,
+                        source,
+                        max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+                        partial: None,
+                    })
+                })
+                .collect(),
+            unexpected: Mailbox::default(),
+            recv_timeout: RecvTimeout::default(),
+        })
+    }
+
+    /// Limits the messages this rank receives, so that a corrupt length in a message header
+    /// cannot make it allocate arbitrary amounts of memory. Defaults to 1 GiB.
+    pub fn with_max_message_len(mut self, len: usize) -> Self {
+        for consumer in &mut self.incoming {
+            consumer.get_mut().unwrap().max_message_len = len;
+        }
+        self
+    }
+
+    fn send_frame(&self, buffer: &[u8], dest: u32, tag: u32) -> Result<(), CommError> {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        Header {
+            source: self.rank,
+            tag,
+            len: buffer.len() as u32,
+        }
+        .encode(&mut header);
+        // the lock keeps the header and payload of concurrent sends together
+        let mut producer = self.outgoing[dest as usize].lock().unwrap();
+        producer.write_all(&header)?;
+        producer.write_all(buffer)
+    }
+
+    /// Returns the payload of the next message from `source` with `tag`. Other messages that
+    /// arrive in the meantime are queued as unexpected. Returns `None` if `wait` does not allow
+    /// to wait any longer.
+    fn receive_matching(
+        &self,
+        source: u32,
+        tag: Option<u32>,
+        wait: Wait,
+    ) -> Result<Option<Vec<u8>>, CommError> {
+        let message = self.receive_message(source, tag, wait)?;
+        Ok(message.map(|(_, payload)| payload))
+    }
+
+    /// Like [`Self::receive_matching`], but also returns the header of the message.
+    fn receive_message(
+        &self,
+        source: u32,
+        tag: Option<u32>,
+        wait: Wait,
+    ) -> Result<Option<(Header, Vec<u8>)>, CommError> {
+        let mut consumer = self.incoming[source as usize].lock().unwrap();
+        if let Some(message) = self.unexpected.take(source, tag) {
+            return Ok(Some(message));
+        }
+        let deadline = match wait {
+            Wait::For(timeout) => Some(Instant::now() + timeout),
+            _ => None,
+        };
+        while let Some((header, payload)) = consumer.next_message(wait, deadline)? {
+            if header.matches(source, tag) {
+                return Ok(Some((header, payload)));
+            }
+            self.unexpected.push(header, payload);
+        }
+        Ok(None)
+    }
+}
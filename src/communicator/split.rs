@@ -0,0 +1,197 @@
+use super::{CommError, TestCommunicator};
+use std::ffi::OsString;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Length of the fixed part a rank contributes to a split: a flag whether it has a color, the
+/// color, the key and the length of its address.
+const ENTRY_LEN: usize = 13;
+
+/// Place of a rank in a communicator created by
+/// [`TestCommunicator::split`](super::TestCommunicator::split).
+pub(crate) struct Group {
+    /// Rank in the new communicator.
+    pub rank: u32,
+    /// Rank in the old communicator of every rank of the new one.
+    pub members: Vec<u32>,
+    /// Address every rank of the new one passed to [`join`], by new rank.
+    pub addresses: Vec<Vec<u8>>,
+}
+
+impl Group {
+    pub fn size(&self) -> u32 {
+        self.members.len() as u32
+    }
+}
+
+struct Entry {
+    color: Option<u32>,
+    key: u32,
+    len: usize,
+}
+
+/// Joins the new communicator of `color`, in which the ranks are ordered by `key` and their
+/// rank in `comm`. `address` tells the other ranks how to reach this one in the new
+/// communicator, e.g. the socket it has bound for it.
+pub(crate) fn join<C: TestCommunicator + ?Sized>(
+    comm: &C,
+    color: u32,
+    key: u32,
+    address: &[u8],
+) -> Result<Group, CommError> {
+    let (entries, addresses) = exchange(comm, Some(color), key, address)?;
+    let mut members: Vec<u32> = (0..comm.size())
+        .filter(|&rank| entries[rank as usize].color == Some(color))
+        .collect();
+    members.sort_by_key(|&rank| (entries[rank as usize].key, rank));
+    let rank = members
+        .iter()
+        .position(|&member| member == comm.rank())
+        .expect("a rank is a member of the group of its color") as u32;
+    let addresses = members
+        .iter()
+        .map(|&member| addresses[member as usize].clone())
+        .collect();
+    Ok(Group {
+        rank,
+        members,
+        addresses,
+    })
+}
+
+/// Takes part in a split without joining a new communicator, for a color of `None`.
+pub(crate) fn abstain<C: TestCommunicator + ?Sized>(comm: &C) -> Result<(), CommError> {
+    exchange(comm, None, 0, &[])?;
+    Ok(())
+}
+
+/// Allgathers the color, key and address of all ranks. Addresses are padded to the longest
+/// one, as the blocks of an allgather have the same length on all ranks.
+fn exchange<C: TestCommunicator + ?Sized>(
+    comm: &C,
+    color: Option<u32>,
+    key: u32,
+    address: &[u8],
+) -> Result<(Vec<Entry>, Vec<Vec<u8>>), CommError> {
+    let size = comm.size() as usize;
+    let mut entry = Vec::with_capacity(ENTRY_LEN);
+    entry.push(color.is_some() as u8);
+    entry.extend_from_slice(&color.unwrap_or(0).to_le_bytes());
+    entry.extend_from_slice(&key.to_le_bytes());
+    entry.extend_from_slice(&(address.len() as u32).to_le_bytes());
+    let mut entries = vec![0; ENTRY_LEN * size];
+    comm.allgather(&entry, &mut entries)?;
+
+    let field = |entry: &[u8], i: usize| u32::from_le_bytes(entry[i..i + 4].try_into().unwrap());
+    let entries: Vec<Entry> = entries
+        .chunks_exact(ENTRY_LEN)
+        .map(|entry| Entry {
+            color: (entry[0] != 0).then(|| field(entry, 1)),
+            key: field(entry, 5),
+            len: field(entry, 9) as usize,
+        })
+        .collect();
+
+    let padded = entries.iter().map(|entry| entry.len).max().unwrap_or(0);
+    let mut addresses = vec![0; padded * size];
+    if padded > 0 {
+        let mut own = address.to_vec();
+        own.resize(padded, 0);
+        comm.allgather(&own, &mut addresses)?;
+    }
+    let addresses = entries
+        .iter()
+        .enumerate()
+        .map(|(rank, entry)| addresses[rank * padded..rank * padded + entry.len].to_vec())
+        .collect();
+    Ok((entries, addresses))
+}
+
+pub(crate) fn encode_socket_address(address: SocketAddr) -> Vec<u8> {
+    address.to_string().into_bytes()
+}
+
+pub(crate) fn decode_socket_address(bytes: &[u8]) -> io::Result<SocketAddr> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|address| address.parse().ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Invalid socket address {:?}",
+                    String::from_utf8_lossy(bytes)
+                ),
+            )
+        })
+}
+
+/// Socket file of a communicator split off the one with the socket file `path`. Unique within
+/// the process, and the process id keeps ranks in different processes apart.
+pub(crate) fn socket_path(path: &Path) -> PathBuf {
+    static NEXT_SPLIT: AtomicU32 = AtomicU32::new(0);
+    let mut split = OsString::from(path.as_os_str());
+    split.push(format!(
+        ".split-{}-{}",
+        std::process::id(),
+        NEXT_SPLIT.fetch_add(1, Ordering::Relaxed)
+    ));
+    split.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::communicator::{ChannelSimCommunicator, CommError, TestCommunicator};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn splits_by_color_and_orders_by_key() {
+        let comms = ChannelSimCommunicator::create(5, None);
+        let groups = thread::scope(|scope| {
+            let ranks: Vec<_> = (comms.iter())
+                .map(|comm| {
+                    scope.spawn(move || {
+                        // ranks 0 and 2, and 1 and 3 in reverse order, rank 4 in none
+                        let color = (comm.rank() < 4).then_some(comm.rank() % 2);
+                        let sub = comm.split(color, comm.size() - comm.rank()).unwrap()?;
+                        Some((sub.rank(), sub.size()))
+                    })
+                })
+                .collect();
+            ranks
+                .into_iter()
+                .map(|rank| rank.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(
+            groups,
+            [Some((1, 2)), Some((1, 2)), Some((0, 2)), Some((0, 2)), None]
+        );
+    }
+
+    #[test]
+    fn messages_stay_in_their_communicator() {
+        let comms = ChannelSimCommunicator::create(4, None);
+        thread::scope(|scope| {
+            for comm in &comms {
+                scope.spawn(move || {
+                    let sub = comm.split(Some(comm.rank() % 2), 0).unwrap().unwrap();
+                    let partner = (comm.rank() + 2) % 4;
+                    if sub.rank() == 0 {
+                        comm.send(&[200], partner).unwrap();
+                        sub.send(&[comm.rank() as u8], 1).unwrap();
+                    } else {
+                        assert_eq!(sub.recv_vec(0).unwrap(), [partner as u8]);
+                        assert_eq!(comm.recv_vec(partner).unwrap(), [200]);
+                        let timeout = Duration::from_millis(10);
+                        let leaked = comm.recv_timeout(&mut [0; 4], partner, timeout);
+                        assert!(matches!(leaked, Err(CommError::Timeout)));
+                    }
+                });
+            }
+        });
+    }
+}
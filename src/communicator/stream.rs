@@ -0,0 +1,569 @@
+use super::barrier;
+use super::matching::{
+    self, Header, Mailbox, RecvTimeout, Wait, DEFAULT_MAX_MESSAGE_LEN, DEFAULT_TAG, HEADER_LEN,
+};
+use super::split;
+use super::tcp;
+use super::uds;
+use super::{CommError, CommRequest, PendingRequest, Status, TestCommunicator};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// How much is read from a stream at once while no larger frame is pending.
+const READ_CHUNK: usize = 64 * 1024;
+
+/// How long setting up a mesh waits for the other ranks to show up.
+pub(crate) const CONNECT_TIMEOUT: Duration = Duration::from_secs(120);
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Bytes received on a stream that do not form a complete frame yet. Frames are the messages
+/// of [`matching::frame`](super::matching::frame), i.e. a header followed by the payload.
+pub(crate) struct FrameBuffer {
+    /// Only grows, so that reads do not have to initialize it again.
+    bytes: Vec<u8>,
+    filled: usize,
+    max_message_len: usize,
+}
+
+impl Default for FrameBuffer {
+    fn default() -> Self {
+        FrameBuffer::new(DEFAULT_MAX_MESSAGE_LEN)
+    }
+}
+
+impl FrameBuffer {
+    /// Rejects frames with a payload longer than `max_message_len`.
+    pub fn new(max_message_len: usize) -> Self {
+        FrameBuffer {
+            bytes: vec![0; READ_CHUNK],
+            filled: 0,
+            max_message_len,
+        }
+    }
+
+    /// Splits off the next complete frame, if one has been received.
+    pub fn next_frame(&mut self) -> Result<Option<(Header, Vec<u8>)>, CommError> {
+        if self.filled < HEADER_LEN {
+            return Ok(None);
+        }
+        let header = Header::decode(&self.bytes[..self.filled])?;
+        matching::check_message_len(&header, self.max_message_len)?;
+        let end = HEADER_LEN + header.len as usize;
+        if self.filled < end {
+            // make room for the rest of the frame, so it is read in as few calls as possible
+            if self.bytes.len() < end {
+                self.bytes.resize(end, 0);
+            }
+            return Ok(None);
+        }
+        let payload = self.bytes[HEADER_LEN..end].to_vec();
+        self.bytes.copy_within(end..self.filled, 0);
+        self.filled -= end;
+        Ok(Some((header, payload)))
+    }
+
+    /// The part of the buffer the next read goes to.
+    fn unfilled(&mut self) -> &mut [u8] {
+        if self.bytes.len() - self.filled < READ_CHUNK / 2 {
+            self.bytes.resize(self.filled + READ_CHUNK, 0);
+        }
+        &mut self.bytes[self.filled..]
+    }
+
+    /// Reads what `stream` has available. Returns 0 at the end of the stream.
+    pub fn fill(&mut self, stream: &mut impl Read) -> io::Result<usize> {
+        let read = stream.read(self.unfilled())?;
+        self.filled += read;
+        Ok(read)
+    }
+
+    /// Like [`FrameBuffer::fill`], but for tokio streams. Cancel safe, so it can be raced
+    /// against other futures.
+    pub async fn fill_async(&mut self, stream: &mut (impl AsyncRead + Unpin)) -> io::Result<usize> {
+        let read = stream.read(self.unfilled()).await?;
+        self.filled += read;
+        Ok(read)
+    }
+}
+
+/// Maps errors of a connection to `rank` that mean the peer went away.
+pub(crate) fn peer_error(e: io::Error, rank: u32) -> CommError {
+    match e.kind() {
+        ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::BrokenPipe
+        | ErrorKind::UnexpectedEof => CommError::Disconnected { rank },
+        _ => e.into(),
+    }
+}
+
+/// Blocking connection-oriented socket, such as TCP or Unix stream sockets.
+pub trait StreamSocket: Read + Write + Send + Sized {
+    fn try_clone(&self) -> io::Result<Self>;
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    /// Connects the ranks of a communicator split off the one of this socket, which is the
+    /// connection of the rank to its own listener. `exchange` hands the address of the new
+    /// listener to the other ranks and returns the rank in the new communicator with the
+    /// addresses of all its ranks.
+    fn split_mesh(
+        &self,
+        exchange: impl FnOnce(&[u8]) -> Result<(u32, Vec<Vec<u8>>), CommError>,
+    ) -> Result<StreamCommunicator<Self>, CommError>;
+}
+
+impl StreamSocket for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn split_mesh(
+        &self,
+        exchange: impl FnOnce(&[u8]) -> Result<(u32, Vec<Vec<u8>>), CommError>,
+    ) -> Result<StreamCommunicator<Self>, CommError> {
+        let (rank, links) = tcp::split_tcp_mesh(self.peer_addr()?, self.nodelay()?, exchange)?;
+        StreamCommunicator::new(rank, links)
+    }
+}
+
+impl StreamSocket for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn split_mesh(
+        &self,
+        exchange: impl FnOnce(&[u8]) -> Result<(u32, Vec<Vec<u8>>), CommError>,
+    ) -> Result<StreamCommunicator<Self>, CommError> {
+        let (rank, links) = uds::split_unix_mesh(&self.peer_addr()?, exchange)?;
+        StreamCommunicator::new(rank, links)
+    }
+}
+
+/// Connection of a mesh to one rank.
+pub(crate) enum Link<S> {
+    Peer(S),
+    /// A rank talks to itself over a connection to its own listener, which has a separate end
+    /// for each direction.
+    Loopback {
+        incoming: S,
+        outgoing: S,
+    },
+}
+
+/// Connects `rank` to all `n` ranks including itself. Every rank connects to the ranks up to
+/// its own and accepts the connections of the ones above, so every pair shares one connection.
+///
+/// `connect` opens a connection to the listener of a rank. The own listener has to be set up
+/// before, and `accept` returns `WouldBlock` while no connection is pending.
+pub(crate) fn connect_mesh<S: Read + Write>(
+    n: u32,
+    rank: u32,
+    mut connect: impl FnMut(u32) -> io::Result<S>,
+    mut accept: impl FnMut() -> io::Result<S>,
+) -> Result<Vec<Link<S>>, CommError> {
+    let deadline = Instant::now() + CONNECT_TIMEOUT;
+
+    let mut outgoing = Vec::with_capacity(rank as usize + 1);
+    for peer in 0..=rank {
+        let mut stream = retry(
+            deadline,
+            || connect(peer),
+            |kind| {
+                // the other rank has not started listening yet
+                matches!(kind, ErrorKind::ConnectionRefused | ErrorKind::NotFound)
+            },
+        )?;
+        // tell the accepting side who we are
+        stream.write_all(&rank.to_le_bytes())?;
+        outgoing.push(stream);
+    }
+
+    let mut incoming: Vec<Option<S>> = (0..n).map(|_| None).collect();
+    for _ in rank..n {
+        let mut stream = retry(deadline, &mut accept, |kind| kind == ErrorKind::WouldBlock)?;
+        let mut hello = [0; 4];
+        stream.read_exact(&mut hello)?;
+        let peer = u32::from_le_bytes(hello);
+        if peer < rank || peer >= n || incoming[peer as usize].is_some() {
+            return Err(CommError::Io(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Unexpected connection from rank {}", peer),
+            )));
+        }
+        incoming[peer as usize] = Some(stream);
+    }
+
+    let mut outgoing = outgoing.into_iter();
+    Ok((0..n)
+        .map(|peer| match peer.cmp(&rank) {
+            std::cmp::Ordering::Less => Link::Peer(outgoing.next().unwrap()),
+            std::cmp::Ordering::Equal => Link::Loopback {
+                incoming: incoming[peer as usize].take().unwrap(),
+                outgoing: outgoing.next().unwrap(),
+            },
+            std::cmp::Ordering::Greater => Link::Peer(incoming[peer as usize].take().unwrap()),
+        })
+        .collect())
+}
+
+pub(crate) fn retry<S>(
+    deadline: Instant,
+    mut attempt: impl FnMut() -> io::Result<S>,
+    retryable: impl Fn(ErrorKind) -> bool,
+) -> Result<S, CommError> {
+    loop {
+        match attempt() {
+            Ok(stream) => return Ok(stream),
+            Err(e) if retryable(e.kind()) => {
+                if Instant::now() >= deadline {
+                    return Err(CommError::Timeout);
+                }
+                thread::sleep(CONNECT_RETRY_INTERVAL);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Full mesh of blocking stream connections, see [`TcpCommunicator`](super::TcpCommunicator)
+/// and [`UdsStreamCommunicator`](super::UdsStreamCommunicator). Messages are framed with the
+/// same header as the datagram communicators, which carries their length.
+pub struct StreamCommunicator<S: StreamSocket> {
+    rank: u32,
+    peers: Vec<Peer<S>>,
+    unexpected: Mailbox,
+    recv_timeout: RecvTimeout,
+    max_message_len: usize,
+}
+
+struct Peer<S> {
+    incoming: Mutex<Incoming<S>>,
+    outgoing: Mutex<S>,
+}
+
+struct Incoming<S> {
+    stream: S,
+    frames: FrameBuffer,
+}
+
+impl<S: StreamSocket> TestCommunicator for StreamCommunicator<S> {
+    fn rank(&self) -> u32 {
+        self.rank
+    }
+
+    fn size(&self) -> u32 {
+        self.peers.len() as u32
+    }
+
+    fn send(&self, buffer: &[u8], dest: u32) -> Result<(), CommError> {
+        self.send_frame(buffer, dest, DEFAULT_TAG)
+    }
+
+    fn recv(&self, buffer: &mut [u8], source: u32) -> Result<usize, CommError> {
+        let payload = self
+            .receive_matching(source, None, self.recv_timeout.wait())?
+            .ok_or(CommError::Timeout)?;
+        matching::copy_payload(&payload, buffer)
+    }
+
+    fn barrier(&self) -> Result<(), CommError> {
+        barrier::linear_barrier(
+            self.rank,
+            self.size(),
+            |dest, tag| self.send_frame(&[], dest, tag),
+            |source, tag| {
+                self.receive_matching(source, Some(tag), self.recv_timeout.wait())?
+                    .ok_or(CommError::Timeout)?;
+                Ok(())
+            },
+        )
+    }
+
+    fn isend(&self, buffer: Vec<u8>, dest: u32) -> Result<CommRequest<'_>, CommError> {
+        // the kernel buffers the stream, so the send completes eagerly unless the buffer is full
+        self.send(&buffer, dest)?;
+        Ok(CommRequest::completed(buffer))
+    }
+
+    fn irecv(&self, buffer: Vec<u8>, source: u32) -> Result<CommRequest<'_>, CommError> {
+        Ok(CommRequest::new(StreamRecvRequest {
+            comm: self,
+            buffer,
+            source,
+            payload: None,
+        }))
+    }
+
+    fn send_tagged(&self, buffer: &[u8], dest: u32, tag: u32) -> Result<(), CommError> {
+        matching::check_tag(tag)?;
+        self.send_frame(buffer, dest, tag)
+    }
+
+    fn recv_tagged(&self, buffer: &mut [u8], source: u32, tag: u32) -> Result<usize, CommError> {
+        let payload = self
+            .receive_matching(source, Some(tag), self.recv_timeout.wait())?
+            .ok_or(CommError::Timeout)?;
+        matching::copy_payload(&payload, buffer)
+    }
+
+    fn probe(&self, source: u32) -> Result<Status, CommError> {
+        let (header, payload) = self
+            .receive_message(source, None, self.recv_timeout.wait())?
+            .ok_or(CommError::Timeout)?;
+        Ok(self.unexpected.put_back(header, payload))
+    }
+
+    fn recv_vec(&self, source: u32) -> Result<Vec<u8>, CommError> {
+        self.receive_matching(source, None, self.recv_timeout.wait())?
+            .ok_or(CommError::Timeout)
+    }
+
+    fn recv_timeout(
+        &self,
+        buffer: &mut [u8],
+        source: u32,
+        timeout: Duration,
+    ) -> Result<usize, CommError> {
+        let payload = self
+            .receive_matching(source, None, Wait::For(timeout))?
+            .ok_or(CommError::Timeout)?;
+        matching::copy_payload(&payload, buffer)
+    }
+
+    fn set_recv_timeout(&self, timeout: Option<Duration>) -> Result<(), CommError> {
+        self.recv_timeout.set(timeout);
+        Ok(())
+    }
+
+    fn split(&self, color: Option<u32>, key: u32) -> Result<Option<Self>, CommError> {
+        let Some(color) = color else {
+            split::abstain(self)?;
+            return Ok(None);
+        };
+        // a clone, so that the lock is not held during the exchange
+        let loopback = self.peers[self.rank as usize]
+            .outgoing
+            .lock()
+            .unwrap()
+            .try_clone()?;
+        let comm = loopback.split_mesh(|address| {
+            let group = split::join(self, color, key, address)?;
+            Ok((group.rank, group.addresses))
+        })?;
+        Ok(Some(comm.with_max_message_len(self.max_message_len)))
+    }
+}
+
+struct StreamRecvRequest<'a, S: StreamSocket> {
+    comm: &'a StreamCommunicator<S>,
+    buffer: Vec<u8>,
+    source: u32,
+    payload: Option<Vec<u8>>,
+}
+
+impl<S: StreamSocket> PendingRequest for StreamRecvRequest<'_, S> {
+    fn test(&mut self) -> Result<bool, CommError> {
+        if self.payload.is_none() {
+            self.payload = self.comm.receive_matching(self.source, None, Wait::Poll)?;
+        }
+        Ok(self.payload.is_some())
+    }
+
+    fn wait(mut self: Box<Self>) -> Result<Vec<u8>, CommError> {
+        let payload = match self.payload.take() {
+            Some(payload) => payload,
+            None => self
+                .comm
+                .receive_matching(self.source, None, self.comm.recv_timeout.wait())?
+                .ok_or(CommError::Timeout)?,
+        };
+        matching::fill_buffer(&mut self.buffer, &payload)?;
+        Ok(self.buffer)
+    }
+}
+
+impl<S: StreamSocket> StreamCommunicator<S> {
+    pub(crate) fn new(rank: u32, links: Vec<Link<S>>) -> Result<Self, CommError> {
+        let peers = links
+            .into_iter()
+            .map(|link| {
+                let (incoming, outgoing) = match link {
+                    Link::Peer(stream) => (stream.try_clone()?, stream),
+                    Link::Loopback { incoming, outgoing } => (incoming, outgoing),
+                };
+                Ok(Peer {
+                    incoming: Mutex::new(Incoming {
+                        stream: incoming,
+                        frames: FrameBuffer::default(),
+                    }),
+                    outgoing: Mutex::new(outgoing),
+                })
+            })
+            .collect::<Result<_, CommError>>()?;
+
+        Ok(StreamCommunicator {
+            rank,
+            peers,
+            unexpected: Mailbox::default(),
+            recv_timeout: RecvTimeout::default(),
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+        })
+    }
+
+    /// Limits the messages this rank receives, so that a corrupt length in a frame header
+    /// cannot make it allocate arbitrary amounts of memory. Defaults to 1 GiB.
+    pub fn with_max_message_len(mut self, len: usize) -> Self {
+        for peer in &mut self.peers {
+            peer.incoming.get_mut().unwrap().frames = FrameBuffer::new(len);
+        }
+        self.max_message_len = len;
+        self
+    }
+
+    fn send_frame(&self, buffer: &[u8], dest: u32, tag: u32) -> Result<(), CommError> {
+        let mut outgoing = self.peers[dest as usize].outgoing.lock().unwrap();
+        outgoing
+            .write_all(&matching::frame(self.rank, tag, buffer))
+            .map_err(|e| peer_error(e, dest))
+    }
+
+    /// Returns the payload of the next message from `source` with `tag`. Other messages that
+    /// arrive in the meantime are queued as unexpected. Returns `None` if `wait` does not allow
+    /// to wait any longer.
+    fn receive_matching(
+        &self,
+        source: u32,
+        tag: Option<u32>,
+        wait: Wait,
+    ) -> Result<Option<Vec<u8>>, CommError> {
+        let message = self.receive_message(source, tag, wait)?;
+        Ok(message.map(|(_, payload)| payload))
+    }
+
+    /// Like [`Self::receive_matching`], but also returns the header of the message.
+    fn receive_message(
+        &self,
+        source: u32,
+        tag: Option<u32>,
+        wait: Wait,
+    ) -> Result<Option<(Header, Vec<u8>)>, CommError> {
+        let peer = &self.peers[source as usize];
+        let mut incoming = peer.incoming.lock().unwrap();
+        if let Some(message) = self.unexpected.take(source, tag) {
+            return Ok(Some(message));
+        }
+        let Incoming { stream, frames } = &mut *incoming;
+        let deadline = match wait {
+            Wait::For(timeout) => Some(Instant::now() + timeout),
+            _ => None,
+        };
+        // Both directions share the socket, so sends must not run into it while it is
+        // non-blocking.
+        let _outgoing = match wait {
+            Wait::Poll => {
+                let outgoing = peer.outgoing.lock().unwrap();
+                stream.set_nonblocking(true)?;
+                Some(outgoing)
+            }
+            _ => None,
+        };
+        let payload = loop {
+            match frames.next_frame() {
+                Ok(Some((header, payload))) if header.matches(source, tag) => {
+                    break Ok(Some((header, payload)))
+                }
+                Ok(Some((header, payload))) => {
+                    self.unexpected.push(header, payload);
+                    continue;
+                }
+                Ok(None) => {}
+                Err(e) => break Err(e),
+            }
+            if let Some(deadline) = deadline {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    break Ok(None);
+                }
+                if let Err(e) = stream.set_read_timeout(Some(remaining)) {
+                    break Err(e.into());
+                }
+            }
+            match frames.fill(stream) {
+                Ok(0) => break Err(CommError::Disconnected { rank: source }),
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e)
+                    if !matches!(wait, Wait::Block)
+                        && matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                {
+                    break Ok(None)
+                }
+                Err(e) => break Err(peer_error(e, source)),
+            }
+        };
+        match wait {
+            Wait::Poll => stream.set_nonblocking(false)?,
+            Wait::For(_) => stream.set_read_timeout(None)?,
+            Wait::Block => {}
+        }
+        payload
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_frames_off_the_stream() {
+        let mut bytes = matching::frame(1, 7, &[1, 2, 3]);
+        bytes.extend(matching::frame(2, 0, &[]));
+        let mut frames = FrameBuffer::default();
+        // the first frame arrives in two reads
+        frames.fill(&mut &bytes[..5]).unwrap();
+        assert!(frames.next_frame().unwrap().is_none());
+        frames.fill(&mut &bytes[5..]).unwrap();
+
+        let (header, payload) = frames.next_frame().unwrap().unwrap();
+        assert_eq!((header.source, header.tag, payload), (1, 7, vec![1, 2, 3]));
+        let (header, payload) = frames.next_frame().unwrap().unwrap();
+        assert_eq!((header.source, header.len, payload), (2, 0, vec![]));
+        assert!(frames.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_frames_longer_than_the_maximum() {
+        let mut frames = FrameBuffer::new(4);
+        frames
+            .fill(&mut &matching::frame(0, 0, &[0; 4])[..])
+            .unwrap();
+        assert!(frames.next_frame().unwrap().is_some());
+
+        // only the header has to arrive to reject a frame
+        let bytes = matching::frame(0, 0, &[0; 5]);
+        frames.fill(&mut &bytes[..HEADER_LEN]).unwrap();
+        assert!(frames.next_frame().is_err());
+    }
+}
@@ -0,0 +1,75 @@
+use super::runtime::spawn_blocking;
+use super::{AsyncTestCommunicator, CommError, TestCommunicator};
+use std::sync::Arc;
+
+/// Offers the [`AsyncTestCommunicator`] API of a blocking communicator. Every operation runs
+/// on the blocking pool of the runtime, so it does not stall other tasks, at the cost of a
+/// thread handoff and a copy of the buffer.
+pub struct SyncAdapter<C> {
+    comm: Arc<C>,
+}
+
+impl<C> SyncAdapter<C> {
+    pub fn new(comm: C) -> Self {
+        SyncAdapter {
+            comm: Arc::new(comm),
+        }
+    }
+}
+
+impl<C: TestCommunicator + Send + Sync + 'static> AsyncTestCommunicator for SyncAdapter<C> {
+    fn rank(&self) -> u32 {
+        self.comm.rank()
+    }
+
+    fn size(&self) -> u32 {
+        self.comm.size()
+    }
+
+    async fn send(&self, buffer: &[u8], dest: u32) -> Result<(), CommError> {
+        let comm = self.comm.clone();
+        let buffer = buffer.to_vec();
+        spawn_blocking(move || comm.send(&buffer, dest)).await
+    }
+
+    async fn recv(&self, buffer: &mut [u8], source: u32) -> Result<usize, CommError> {
+        let comm = self.comm.clone();
+        // a short message leaves the rest of the buffer as it was
+        let mut received = buffer.to_vec();
+        let (received, len) = spawn_blocking(move || {
+            let len = comm.recv(&mut received, source)?;
+            Ok((received, len))
+        })
+        .await?;
+        buffer.copy_from_slice(&received);
+        Ok(len)
+    }
+
+    async fn barrier(&self) -> Result<(), CommError> {
+        let comm = self.comm.clone();
+        spawn_blocking(move || comm.barrier()).await
+    }
+
+    async fn send_tagged(&self, buffer: &[u8], dest: u32, tag: u32) -> Result<(), CommError> {
+        let comm = self.comm.clone();
+        let buffer = buffer.to_vec();
+        spawn_blocking(move || comm.send_tagged(&buffer, dest, tag)).await
+    }
+
+    async fn recv_tagged(
+        &self,
+        buffer: &mut [u8],
+        source: u32,
+        tag: u32,
+    ) -> Result<usize, CommError> {
+        let comm = self.comm.clone();
+        let mut received = buffer.to_vec();
+        let (received, len) = spawn_blocking(move || {
+            let len = comm.recv_tagged(&mut received, source, tag)?;
+            Ok((received, len))
+        })
+        .await?;
+        buffer.copy_from_slice(&received);
+        Ok(len)
+    }
+}
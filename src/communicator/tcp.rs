@@ -0,0 +1,561 @@
+use super::address::AddressBook;
+use super::barrier;
+use super::matching::{self, Header, Mailbox, RecvTimeout, DEFAULT_MAX_MESSAGE_LEN, DEFAULT_TAG};
+use super::rendezvous;
+use super::runtime::{self, CommRuntime};
+use super::split;
+use super::stream::{self, peer_error, FrameBuffer, Link, StreamCommunicator};
+use super::{
+    AsyncTestCommunicator, CommError, CommRequest, PendingRequest, Status, TestCommunicator,
+};
+use clap::{ArgAction, Parser};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+/// Socket options of the TCP communicators.
+#[derive(Parser, Debug, Clone)]
+pub struct TcpOptions {
+    /// Set TCP_NODELAY, i.e. send small messages right away instead of batching them.
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    pub nodelay: bool,
+    /// SO_SNDBUF in bytes. The system default if not given.
+    #[arg(long)]
+    pub send_buffer_size: Option<usize>,
+    /// SO_RCVBUF in bytes. The system default if not given.
+    #[arg(long)]
+    pub recv_buffer_size: Option<usize>,
+    /// Longest message received. Longer ones fail the receive, so that a corrupt frame header
+    /// cannot make a rank allocate arbitrary amounts of memory.
+    #[arg(long, default_value_t = DEFAULT_MAX_MESSAGE_LEN)]
+    pub max_message_len: usize,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        TcpOptions {
+            nodelay: true,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+        }
+    }
+}
+
+impl TcpOptions {
+    /// Buffer sizes have to be set before connecting or listening to take effect on the window.
+    fn apply_buffer_sizes(&self, socket: &Socket) -> io::Result<()> {
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+/// Listens for `rank` and connects it to the other ranks of `addresses`.
+fn connect_tcp_mesh(
+    addresses: &AddressBook,
+    rank: u32,
+    options: &TcpOptions,
+) -> Result<Vec<Link<TcpStream>>, CommError> {
+    let listener = listen(addresses.bind_address(rank), options)?;
+    mesh_with_listener(listener, addresses, rank, options)
+}
+
+/// Registers with the coordinator and connects to the other ranks of the job. Returns the
+/// assigned rank.
+fn rendezvous_tcp_mesh(
+    coordinator: SocketAddr,
+    world_size: u32,
+    options: &TcpOptions,
+) -> Result<(u32, Vec<Link<TcpStream>>), CommError> {
+    let listener = listen(rendezvous::any_address(coordinator), options)?;
+    let port = listener.local_addr()?.port();
+    let (rank, addresses) = rendezvous::register(coordinator, world_size, port)?;
+    Ok((
+        rank,
+        mesh_with_listener(listener, &addresses, rank, options)?,
+    ))
+}
+
+/// Connects the ranks of a communicator split off one in which this rank listens at `own`, see
+/// [`StreamSocket::split_mesh`](super::StreamSocket::split_mesh).
+pub(crate) fn split_tcp_mesh(
+    own: SocketAddr,
+    nodelay: bool,
+    exchange: impl FnOnce(&[u8]) -> Result<(u32, Vec<Vec<u8>>), CommError>,
+) -> Result<(u32, Vec<Link<TcpStream>>), CommError> {
+    let options = TcpOptions {
+        nodelay,
+        ..TcpOptions::default()
+    };
+    // on the same interface with any free port
+    let listener = listen(SocketAddr::new(own.ip(), 0), &options)?;
+    let address = SocketAddr::new(own.ip(), listener.local_addr()?.port());
+    let (rank, addresses) = exchange(&split::encode_socket_address(address))?;
+    let addresses = (addresses.iter())
+        .map(|address| split::decode_socket_address(address))
+        .collect::<Result<_, _>>()?;
+    let links = mesh_with_listener(listener, &AddressBook::new(addresses), rank, &options)?;
+    Ok((rank, links))
+}
+
+fn mesh_with_listener(
+    listener: TcpListener,
+    addresses: &AddressBook,
+    rank: u32,
+    options: &TcpOptions,
+) -> Result<Vec<Link<TcpStream>>, CommError> {
+    stream::connect_mesh(
+        addresses.len(),
+        rank,
+        |peer| connect(addresses.address(peer), options),
+        || {
+            let (stream, _) = listener.accept()?;
+            stream.set_nonblocking(false)?;
+            stream.set_nodelay(options.nodelay)?;
+            Ok(stream)
+        },
+    )
+}
+
+fn socket(address: SocketAddr, options: &TcpOptions) -> io::Result<Socket> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    options.apply_buffer_sizes(&socket)?;
+    Ok(socket)
+}
+
+fn listen(address: SocketAddr, options: &TcpOptions) -> io::Result<TcpListener> {
+    // accepted connections inherit the buffer sizes
+    let socket = socket(address, options)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(128)?;
+    let listener: TcpListener = socket.into();
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+fn connect(address: SocketAddr, options: &TcpOptions) -> io::Result<TcpStream> {
+    let socket = socket(address, options)?;
+    socket.connect(&address.into())?;
+    let stream: TcpStream = socket.into();
+    stream.set_nodelay(options.nodelay)?;
+    Ok(stream)
+}
+
+pub type TcpCommunicator = StreamCommunicator<TcpStream>;
+
+impl TcpCommunicator {
+    pub fn create_n_2_n(
+        n: u32,
+        rank: u32,
+        options: &TcpOptions,
+    ) -> Result<TcpCommunicator, CommError> {
+        Self::create_with_addresses(&AddressBook::localhost(n)?, rank, options)
+    }
+
+    /// Like `create_n_2_n`, with the ranks listening at the addresses of `addresses`.
+    pub fn create_with_addresses(
+        addresses: &AddressBook,
+        rank: u32,
+        options: &TcpOptions,
+    ) -> Result<TcpCommunicator, CommError> {
+        let links = connect_tcp_mesh(addresses, rank, options)?;
+        Ok(StreamCommunicator::new(rank, links)?.with_max_message_len(options.max_message_len))
+    }
+
+    /// Registers with the [`Coordinator`](super::Coordinator) at `coordinator` and connects to
+    /// the other ranks of a job of `world_size` ranks. The rank is assigned by the coordinator.
+    pub fn connect(
+        coordinator: SocketAddr,
+        world_size: u32,
+        options: &TcpOptions,
+    ) -> Result<TcpCommunicator, CommError> {
+        let (rank, links) = rendezvous_tcp_mesh(coordinator, world_size, options)?;
+        Ok(StreamCommunicator::new(rank, links)?.with_max_message_len(options.max_message_len))
+    }
+}
+
+/// Full mesh of TCP connections driven by a tokio runtime, like
+/// [`TokioCommunicator`](super::TokioCommunicator) for UDP.
+pub struct TokioTcpCommunicator {
+    rank: u32,
+    peers: Vec<TokioPeer>,
+    unexpected: Mailbox,
+    recv_timeout: RecvTimeout,
+    max_message_len: usize,
+    // dropped after the streams registered with it
+    runtime: CommRuntime,
+}
+
+struct TokioPeer {
+    incoming: tokio::sync::Mutex<TokioIncoming>,
+    outgoing: tokio::sync::Mutex<OwnedWriteHalf>,
+}
+
+struct TokioIncoming {
+    stream: OwnedReadHalf,
+    frames: FrameBuffer,
+}
+
+impl TestCommunicator for TokioTcpCommunicator {
+    fn rank(&self) -> u32 {
+        self.rank
+    }
+
+    fn size(&self) -> u32 {
+        self.peers.len() as u32
+    }
+
+    fn send(&self, buffer: &[u8], dest: u32) -> Result<(), CommError> {
+        self.runtime.block_on(self.send(buffer, dest, DEFAULT_TAG))
+    }
+
+    fn recv(&self, buffer: &mut [u8], source: u32) -> Result<usize, CommError> {
+        self.runtime.block_on(self.recv(buffer, source, None))
+    }
+
+    fn barrier(&self) -> Result<(), CommError> {
+        self.runtime.block_on(self.barrier())
+    }
+
+    fn isend(&self, buffer: Vec<u8>, dest: u32) -> Result<CommRequest<'_>, CommError> {
+        // the kernel buffers the stream, so the send completes eagerly unless the buffer is full
+        TestCommunicator::send(self, &buffer, dest)?;
+        Ok(CommRequest::completed(buffer))
+    }
+
+    fn irecv(&self, buffer: Vec<u8>, source: u32) -> Result<CommRequest<'_>, CommError> {
+        Ok(CommRequest::new(TokioTcpRecvRequest {
+            comm: self,
+            buffer,
+            source,
+            payload: None,
+        }))
+    }
+
+    fn send_tagged(&self, buffer: &[u8], dest: u32, tag: u32) -> Result<(), CommError> {
+        matching::check_tag(tag)?;
+        self.runtime.block_on(self.send(buffer, dest, tag))
+    }
+
+    fn recv_tagged(&self, buffer: &mut [u8], source: u32, tag: u32) -> Result<usize, CommError> {
+        self.runtime.block_on(self.recv(buffer, source, Some(tag)))
+    }
+
+    fn probe(&self, source: u32) -> Result<Status, CommError> {
+        self.runtime.block_on(async {
+            let receive = self.receive_message(source, None);
+            let (header, payload) = runtime::with_timeout(self.recv_timeout.get(), receive).await?;
+            Ok(self.unexpected.put_back(header, payload))
+        })
+    }
+
+    fn recv_vec(&self, source: u32) -> Result<Vec<u8>, CommError> {
+        let receive = self.receive_matching(source, None);
+        (self.runtime).block_on(runtime::with_timeout(self.recv_timeout.get(), receive))
+    }
+
+    fn recv_timeout(
+        &self,
+        buffer: &mut [u8],
+        source: u32,
+        timeout: Duration,
+    ) -> Result<usize, CommError> {
+        self.runtime.block_on(async {
+            let receive = self.receive_matching(source, None);
+            let payload = runtime::with_timeout(Some(timeout), receive).await?;
+            matching::copy_payload(&payload, buffer)
+        })
+    }
+
+    fn set_recv_timeout(&self, timeout: Option<Duration>) -> Result<(), CommError> {
+        self.recv_timeout.set(timeout);
+        Ok(())
+    }
+
+    fn split(&self, color: Option<u32>, key: u32) -> Result<Option<Self>, CommError> {
+        let Some(color) = color else {
+            split::abstain(self)?;
+            return Ok(None);
+        };
+        let (own, nodelay) = self.runtime.block_on(async {
+            let loopback = self.peers[self.rank as usize].outgoing.lock().await;
+            Ok((loopback.peer_addr()?, loopback.as_ref().nodelay()?))
+        })?;
+        let (rank, links) = split_tcp_mesh(own, nodelay, |address| {
+            let group = split::join(self, color, key, address)?;
+            Ok((group.rank, group.addresses))
+        })?;
+        let comm = Self::from_links(CommRuntime::current_thread()?, rank, links)?;
+        Ok(Some(comm.with_max_message_len(self.max_message_len)))
+    }
+}
+
+impl AsyncTestCommunicator for TokioTcpCommunicator {
+    fn rank(&self) -> u32 {
+        self.rank
+    }
+
+    fn size(&self) -> u32 {
+        self.peers.len() as u32
+    }
+
+    async fn send(&self, buffer: &[u8], dest: u32) -> Result<(), CommError> {
+        self.runtime.check_ambient()?;
+        self.send(buffer, dest, DEFAULT_TAG).await
+    }
+
+    async fn recv(&self, buffer: &mut [u8], source: u32) -> Result<usize, CommError> {
+        self.runtime.check_ambient()?;
+        self.recv(buffer, source, None).await
+    }
+
+    async fn barrier(&self) -> Result<(), CommError> {
+        self.runtime.check_ambient()?;
+        self.barrier().await
+    }
+
+    async fn send_tagged(&self, buffer: &[u8], dest: u32, tag: u32) -> Result<(), CommError> {
+        self.runtime.check_ambient()?;
+        matching::check_tag(tag)?;
+        self.send(buffer, dest, tag).await
+    }
+
+    async fn recv_tagged(
+        &self,
+        buffer: &mut [u8],
+        source: u32,
+        tag: u32,
+    ) -> Result<usize, CommError> {
+        self.runtime.check_ambient()?;
+        self.recv(buffer, source, Some(tag)).await
+    }
+}
+
+struct TokioTcpRecvRequest<'a> {
+    comm: &'a TokioTcpCommunicator,
+    buffer: Vec<u8>,
+    source: u32,
+    payload: Option<Vec<u8>>,
+}
+
+impl PendingRequest for TokioTcpRecvRequest<'_> {
+    fn test(&mut self) -> Result<bool, CommError> {
+        if self.payload.is_some() {
+            return Ok(true);
+        }
+        // same as for the UDP variant, the receive is cancel safe
+        let comm = self.comm;
+        let source = self.source;
+        self.payload = comm.runtime.block_on(async {
+            tokio::select! {
+                biased;
+                payload = comm.receive_matching(source, None) => payload.map(Some),
+                _ = tokio::task::yield_now() => Ok(None),
+            }
+        })?;
+        Ok(self.payload.is_some())
+    }
+
+    fn wait(mut self: Box<Self>) -> Result<Vec<u8>, CommError> {
+        let payload = match self.payload.take() {
+            Some(payload) => payload,
+            None => {
+                let receive = self.comm.receive_matching(self.source, None);
+                let timeout = self.comm.recv_timeout.get();
+                (self.comm.runtime).block_on(runtime::with_timeout(timeout, receive))?
+            }
+        };
+        matching::fill_buffer(&mut self.buffer, &payload)?;
+        Ok(self.buffer)
+    }
+}
+
+impl TokioTcpCommunicator {
+    pub fn create_n_2_n(
+        n: u32,
+        rank: u32,
+        options: &TcpOptions,
+    ) -> Result<TokioTcpCommunicator, CommError> {
+        Self::create_with_addresses(&AddressBook::localhost(n)?, rank, options)
+    }
+
+    /// Like `create_n_2_n`, with the ranks listening at the addresses of `addresses`.
+    pub fn create_with_addresses(
+        addresses: &AddressBook,
+        rank: u32,
+        options: &TcpOptions,
+    ) -> Result<TokioTcpCommunicator, CommError> {
+        let links = connect_tcp_mesh(addresses, rank, options)?;
+        let comm = Self::from_links(CommRuntime::current_thread()?, rank, links)?;
+        Ok(comm.with_max_message_len(options.max_message_len))
+    }
+
+    /// Like `create_n_2_n`, for the [`AsyncTestCommunicator`] API in the runtime of the caller.
+    pub async fn create_n_2_n_async(
+        n: u32,
+        rank: u32,
+        options: &TcpOptions,
+    ) -> Result<TokioTcpCommunicator, CommError> {
+        Self::create_with_addresses_async(&AddressBook::localhost(n)?, rank, options).await
+    }
+
+    /// Like `create_with_addresses`, for the [`AsyncTestCommunicator`] API in the runtime of the
+    /// caller.
+    pub async fn create_with_addresses_async(
+        addresses: &AddressBook,
+        rank: u32,
+        options: &TcpOptions,
+    ) -> Result<TokioTcpCommunicator, CommError> {
+        let addresses = addresses.clone();
+        let max_message_len = options.max_message_len;
+        let options = options.clone();
+        // the mesh is set up with blocking sockets
+        let links =
+            runtime::spawn_blocking(move || connect_tcp_mesh(&addresses, rank, &options)).await?;
+        let comm = Self::from_links(CommRuntime::ambient(), rank, links)?;
+        Ok(comm.with_max_message_len(max_message_len))
+    }
+
+    /// Like [`TcpCommunicator::connect`].
+    pub fn connect(
+        coordinator: SocketAddr,
+        world_size: u32,
+        options: &TcpOptions,
+    ) -> Result<TokioTcpCommunicator, CommError> {
+        let (rank, links) = rendezvous_tcp_mesh(coordinator, world_size, options)?;
+        let comm = Self::from_links(CommRuntime::current_thread()?, rank, links)?;
+        Ok(comm.with_max_message_len(options.max_message_len))
+    }
+
+    /// Hands the streams of a mesh, which is set up with blocking sockets, to `runtime`.
+    fn from_links(
+        runtime: CommRuntime,
+        rank: u32,
+        links: Vec<Link<TcpStream>>,
+    ) -> Result<TokioTcpCommunicator, CommError> {
+        let context = runtime.enter();
+        let into_tokio = |stream: TcpStream| {
+            stream.set_nonblocking(true)?;
+            tokio::net::TcpStream::from_std(stream)
+        };
+        let peers = links
+            .into_iter()
+            .map(|link| {
+                let (incoming, outgoing) = match link {
+                    Link::Peer(stream) => into_tokio(stream)?.into_split(),
+                    Link::Loopback { incoming, outgoing } => (
+                        into_tokio(incoming)?.into_split().0,
+                        into_tokio(outgoing)?.into_split().1,
+                    ),
+                };
+                Ok(TokioPeer {
+                    incoming: tokio::sync::Mutex::new(TokioIncoming {
+                        stream: incoming,
+                        frames: FrameBuffer::default(),
+                    }),
+                    outgoing: tokio::sync::Mutex::new(outgoing),
+                })
+            })
+            .collect::<Result<_, CommError>>()?;
+        drop(context);
+
+        Ok(TokioTcpCommunicator {
+            rank,
+            peers,
+            unexpected: Mailbox::default(),
+            recv_timeout: RecvTimeout::default(),
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+            runtime,
+        })
+    }
+
+    /// Limits the messages this rank receives, like [`StreamCommunicator::with_max_message_len`].
+    pub fn with_max_message_len(mut self, len: usize) -> Self {
+        for peer in &mut self.peers {
+            peer.incoming.get_mut().frames = FrameBuffer::new(len);
+        }
+        self.max_message_len = len;
+        self
+    }
+
+    async fn recv(
+        &self,
+        buffer: &mut [u8],
+        source: u32,
+        tag: Option<u32>,
+    ) -> Result<usize, CommError> {
+        let receive = self.receive_matching(source, tag);
+        let payload = runtime::with_timeout(self.recv_timeout.get(), receive).await?;
+        matching::copy_payload(&payload, buffer)
+    }
+
+    async fn send(&self, buffer: &[u8], dest: u32, tag: u32) -> Result<(), CommError> {
+        let mut outgoing = self.peers[dest as usize].outgoing.lock().await;
+        outgoing
+            .write_all(&matching::frame(self.rank, tag, buffer))
+            .await
+            .map_err(|e| peer_error(e, dest))
+    }
+
+    async fn barrier(&self) -> Result<(), CommError> {
+        barrier::linear_barrier_async(
+            self.rank,
+            self.peers.len() as u32,
+            |dest, tag| self.send(&[], dest, tag),
+            |source, tag| async move {
+                let receive = self.receive_matching(source, Some(tag));
+                runtime::with_timeout(self.recv_timeout.get(), receive).await?;
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    /// Returns the payload of the next message from `source` with `tag`. Other messages that
+    /// arrive in the meantime are queued as unexpected.
+    async fn receive_matching(&self, source: u32, tag: Option<u32>) -> Result<Vec<u8>, CommError> {
+        let (_, payload) = self.receive_message(source, tag).await?;
+        Ok(payload)
+    }
+
+    /// Like [`Self::receive_matching`], but also returns the header of the message.
+    async fn receive_message(
+        &self,
+        source: u32,
+        tag: Option<u32>,
+    ) -> Result<(Header, Vec<u8>), CommError> {
+        let mut incoming = self.peers[source as usize].incoming.lock().await;
+        if let Some(message) = self.unexpected.take(source, tag) {
+            return Ok(message);
+        }
+        let TokioIncoming { stream, frames } = &mut *incoming;
+        loop {
+            while let Some((header, payload)) = frames.next_frame()? {
+                if header.matches(source, tag) {
+                    return Ok((header, payload));
+                }
+                self.unexpected.push(header, payload);
+            }
+            let read = frames
+                .fill_async(stream)
+                .await
+                .map_err(|e| peer_error(e, source))?;
+            if read == 0 {
+                return Err(CommError::Disconnected { rank: source });
+            }
+        }
+    }
+}
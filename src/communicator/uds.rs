@@ -0,0 +1,151 @@
+use super::datagram::DatagramCommunicator;
+use super::matching::DEFAULT_MAX_MESSAGE_LEN;
+use super::split;
+use super::stream::{self, Link, StreamCommunicator};
+use super::CommError;
+use clap::{Parser, ValueEnum};
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram, UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UdsFlavour {
+    /// One datagram socket per rank, like the UDP communicators.
+    #[default]
+    Datagram,
+    /// A full mesh of stream connections, like the TCP communicators.
+    Stream,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct UdsArguments {
+    #[arg(long, value_enum, default_value_t = UdsFlavour::Datagram)]
+    pub flavour: UdsFlavour,
+    /// Directory the socket files of all ranks are created in.
+    #[arg(long, default_value_os_t = default_socket_dir())]
+    pub socket_dir: PathBuf,
+    /// Longest message received. Longer ones fail the receive, so that a corrupt header cannot
+    /// make a rank allocate arbitrary amounts of memory.
+    #[arg(long, default_value_t = DEFAULT_MAX_MESSAGE_LEN)]
+    pub max_message_len: usize,
+}
+
+impl Default for UdsArguments {
+    fn default() -> Self {
+        UdsArguments {
+            flavour: UdsFlavour::default(),
+            socket_dir: default_socket_dir(),
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+        }
+    }
+}
+
+/// Directory used by `create_n_2_n` if none is given.
+pub fn default_socket_dir() -> PathBuf {
+    std::env::temp_dir().join("rust-hpc-communication-test")
+}
+
+fn datagram_path(dir: &Path, rank: u32) -> PathBuf {
+    dir.join(format!("rank-{}.dgram", rank))
+}
+
+fn stream_path(dir: &Path, rank: u32) -> PathBuf {
+    dir.join(format!("rank-{}.stream", rank))
+}
+
+/// Removes the socket file a previous run left behind, as binding to an existing path fails.
+fn remove_stale(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Connects the ranks of a communicator split off one in which this rank listens at `own`, see
+/// [`StreamSocket::split_mesh`](super::StreamSocket::split_mesh).
+pub(crate) fn split_unix_mesh(
+    own: &SocketAddr,
+    exchange: impl FnOnce(&[u8]) -> Result<(u32, Vec<Vec<u8>>), CommError>,
+) -> Result<(u32, Vec<Link<UnixStream>>), CommError> {
+    let own = own
+        .as_pathname()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "listener without a socket file"))?;
+    let path = split::socket_path(own);
+    let listener = UnixListener::bind(&path)?;
+    listener.set_nonblocking(true)?;
+    let mesh = exchange(path.as_os_str().as_bytes()).and_then(|(rank, addresses)| {
+        let links = stream::connect_mesh(
+            addresses.len() as u32,
+            rank,
+            |peer| UnixStream::connect(OsStr::from_bytes(&addresses[peer as usize])),
+            || {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(false)?;
+                Ok(stream)
+            },
+        )?;
+        Ok((rank, links))
+    });
+    // nobody connects anymore once the mesh is complete, or failed
+    drop(listener);
+    remove_stale(&path)?;
+    mesh
+}
+
+pub type UdsCommunicator = DatagramCommunicator<UnixDatagram>;
+
+impl UdsCommunicator {
+    pub fn create_n_2_n(n: u32, rank: u32) -> Result<UdsCommunicator, CommError> {
+        Self::create_n_2_n_in(&default_socket_dir(), n, rank)
+    }
+
+    /// Like `create_n_2_n`, with the socket files in `dir`, which is created if needed.
+    pub fn create_n_2_n_in(dir: &Path, n: u32, rank: u32) -> Result<UdsCommunicator, CommError> {
+        fs::create_dir_all(dir)?;
+        let path = datagram_path(dir, rank);
+        remove_stale(&path)?;
+        let socket = UnixDatagram::bind(&path)?;
+        let receiver = (0..n).map(|i| datagram_path(dir, i)).collect();
+
+        Ok(DatagramCommunicator::new(rank, socket, receiver))
+    }
+}
+
+pub type UdsStreamCommunicator = StreamCommunicator<UnixStream>;
+
+impl UdsStreamCommunicator {
+    pub fn create_n_2_n(n: u32, rank: u32) -> Result<UdsStreamCommunicator, CommError> {
+        Self::create_n_2_n_in(&default_socket_dir(), n, rank)
+    }
+
+    /// Like `create_n_2_n`, with the socket files in `dir`, which is created if needed.
+    pub fn create_n_2_n_in(
+        dir: &Path,
+        n: u32,
+        rank: u32,
+    ) -> Result<UdsStreamCommunicator, CommError> {
+        fs::create_dir_all(dir)?;
+        let path = stream_path(dir, rank);
+        remove_stale(&path)?;
+        let listener = UnixListener::bind(&path)?;
+        listener.set_nonblocking(true)?;
+        let links = stream::connect_mesh(
+            n,
+            rank,
+            |peer| UnixStream::connect(stream_path(dir, peer)),
+            || {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(false)?;
+                Ok(stream)
+            },
+        )?;
+        // nobody connects anymore once the mesh is complete
+        drop(listener);
+        remove_stale(&path)?;
+
+        StreamCommunicator::new(rank, links)
+    }
+}
@@ -1,4 +1,5 @@
 mod communicator;
+mod proto;
 mod test_execution;
 
 fn main() {
@@ -1,10 +1,39 @@
-use crate::communicator::TestCommunicator;
-use clap::Parser;
+use crate::communicator::{
+    AsyncTestCommunicator, CommError, CommRequest, Epoch, MpiCommunicator, TestCommunicator,
+};
+use clap::{Parser, ValueEnum};
 use derive_builder::Builder;
 use rand::{Rng, SeedableRng};
+use std::future::Future;
+use std::io;
+use std::time::{Duration, Instant};
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Benchmark {
+    /// Blocking send followed by a blocking receive.
+    #[default]
+    PingPong,
+    /// Non-blocking send and receive overlapped with `compute_micros` of busy work.
+    Overlap,
+    /// Bursts of `burst` messages from the client, acknowledged by the server after each burst.
+    Bandwidth,
+}
+
+/// Synchronization of the one-sided benchmarks on an MPI window.
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RmaSync {
+    /// `MPI_Win_fence` on both ranks around every epoch.
+    #[default]
+    Fence,
+    /// `MPI_Win_start`/`MPI_Win_complete` on the origin, `MPI_Win_post`/`MPI_Win_wait` on the
+    /// target.
+    Pscw,
+}
 
 #[derive(Parser, Debug, Clone, Default)]
 pub struct BasicArguments {
+    #[arg(short, long, value_enum, default_value_t = Benchmark::PingPong)]
+    pub benchmark: Benchmark,
     #[arg(short, long, default_value_t = 90_000)]
     pub iterations: u32,
     #[arg(short, long, default_value_t = 5_000)]
@@ -13,6 +42,22 @@ pub struct BasicArguments {
     pub message_len: u32,
     #[arg(short, long)]
     pub reporting_file: Option<String>,
+    /// Busy work per iteration of the overlap benchmark.
+    #[arg(long, default_value_t = 0)]
+    pub compute_micros: u64,
+    /// Messages per iteration of the bandwidth benchmark.
+    #[arg(long, default_value_t = 64)]
+    pub burst: u32,
+    /// Seconds a receive waits for the other rank before the run fails, instead of waiting
+    /// forever for a rank that is gone.
+    #[arg(long)]
+    pub recv_timeout_secs: Option<u64>,
+}
+
+impl BasicArguments {
+    pub fn recv_timeout(&self) -> Option<Duration> {
+        self.recv_timeout_secs.map(Duration::from_secs)
+    }
 }
 
 #[derive(Default, Builder, Debug)]
@@ -30,27 +75,139 @@ impl<C: TestCommunicator> TestExecution<C> {
         }
     }
 
-    pub fn ping_pong_client(&self) {
-        self.check_ping_pong();
+    pub fn communicator(&self) -> &C {
+        &self.communicator
+    }
+
+    pub fn run_client(&self) -> Result<(), CommError> {
+        self.set_recv_timeout()?;
+        match self.arguments.benchmark {
+            Benchmark::PingPong => self.ping_pong_client(),
+            Benchmark::Overlap => self.overlap_client(),
+            Benchmark::Bandwidth => self.bandwidth_client(),
+        }
+    }
+
+    pub fn run_server(&self) -> Result<(), CommError> {
+        self.set_recv_timeout()?;
+        match self.arguments.benchmark {
+            // the server only echoes, these benchmarks differ on the client side
+            Benchmark::PingPong | Benchmark::Overlap => self.ping_pong_server(),
+            Benchmark::Bandwidth => self.bandwidth_server(),
+        }
+    }
+
+    pub fn ping_pong_client(&self) -> Result<(), CommError> {
+        check_ping_pong(self.communicator.size())?;
+        let other = 1;
+
+        let message = create_message(&self.arguments);
+
+        self.measure(|| {
+            self.communicator.send(&message, other)?;
+            let in_buffer = &mut vec![0; message.len()];
+            self.communicator.recv(in_buffer, other)?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    pub fn overlap_client(&self) -> Result<(), CommError> {
+        check_ping_pong(self.communicator.size())?;
         let other = 1;
 
-        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
-        let message: Vec<u8> = (0..self.arguments.message_len)
-            .map(|_| rng.random::<u8>())
-            .collect();
+        let message = create_message(&self.arguments);
+        let compute = Duration::from_micros(self.arguments.compute_micros);
+
+        self.measure(|| {
+            let recv = self.communicator.irecv(vec![0; message.len()], other)?;
+            let send = self.communicator.isend(message.clone(), other)?;
+            busy_wait(compute);
+            CommRequest::wait_all(vec![send, recv])?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    pub fn ping_pong_server(&self) -> Result<(), CommError> {
+        check_ping_pong(self.communicator.size())?;
+        let other = 0;
+
+        self.serve(|| {
+            let in_buffer = &mut vec![0; self.arguments.message_len as usize];
+            self.communicator.recv(in_buffer, other)?;
+            self.communicator.send(in_buffer, other)
+        })
+    }
+
+    pub fn bandwidth_client(&self) -> Result<(), CommError> {
+        check_ping_pong(self.communicator.size())?;
+        let other = 1;
+
+        let message = create_message(&self.arguments);
+
+        let elapsed = self.measure(|| {
+            let sends = (0..self.arguments.burst)
+                .map(|_| self.communicator.isend(message.clone(), other))
+                .collect::<Result<Vec<_>, CommError>>()?;
+            CommRequest::wait_all(sends)?;
+            self.communicator.recv(&mut [0], other)?;
+            Ok(())
+        })?;
+        print_bandwidth(&self.arguments, elapsed);
+        Ok(())
+    }
+
+    pub fn bandwidth_server(&self) -> Result<(), CommError> {
+        check_ping_pong(self.communicator.size())?;
+        let other = 0;
+
+        self.serve(|| {
+            let recvs = (0..self.arguments.burst)
+                .map(|_| {
+                    let in_buffer = vec![0; self.arguments.message_len as usize];
+                    self.communicator.irecv(in_buffer, other)
+                })
+                .collect::<Result<Vec<_>, CommError>>()?;
+            CommRequest::wait_all(recvs)?;
+            self.communicator.send(&[0], other)
+        })
+    }
+
+    pub fn barrier(&self) -> Result<(), CommError> {
+        self.set_recv_timeout()?;
+        self.communicator.barrier()
+    }
+
+    /// Hands the deadline of the arguments, if any, to the communicator.
+    fn set_recv_timeout(&self) -> Result<(), CommError> {
+        match self.arguments.recv_timeout() {
+            Some(timeout) => self.communicator.set_recv_timeout(Some(timeout)),
+            None => Ok(()),
+        }
+    }
 
+    /// Runs the iterations of a client and reports how long each took. A failed iteration ends
+    /// the run, after the times of the ones before it have been written.
+    fn measure(
+        &self,
+        mut iteration: impl FnMut() -> Result<(), CommError>,
+    ) -> Result<Duration, CommError> {
         let mut reporting = Vec::with_capacity(self.arguments.iterations as usize);
 
         //Measure elapsed time
-        let start = std::time::Instant::now();
+        let start = Instant::now();
         for i in 0..self.arguments.iterations {
             if i % self.arguments.log_interval == 0 {
                 println!("=== Client in iteration {} ===", i);
             }
-            let start_i = std::time::Instant::now();
-            self.communicator.send(&message, other);
-            let in_buffer = &mut vec![0; message.len()];
-            self.communicator.recv(in_buffer, other);
+            let start_i = Instant::now();
+            if let Err(e) = iteration() {
+                if let Some(ref _reporting_file) = self.arguments.reporting_file {
+                    write_reporting_csv(&self.arguments, &reporting)?;
+                }
+                return Err(report_failure("Client", i, e));
+            }
             let elapsed_i = start_i.elapsed();
 
             if let Some(ref _reporting_file) = self.arguments.reporting_file {
@@ -62,42 +219,393 @@ impl<C: TestCommunicator> TestExecution<C> {
         println!("Elapsed time: {:?}", elapsed);
 
         if let Some(ref _reporting_file) = self.arguments.reporting_file {
-            self.write_reporting_csv(&mut reporting);
+            write_reporting_csv(&self.arguments, &reporting)?;
         }
+        Ok(elapsed)
     }
 
-    pub fn ping_pong_server(&self) {
-        self.check_ping_pong();
-        let other = 0;
-
+    /// Runs the iterations of a server.
+    fn serve(&self, mut iteration: impl FnMut() -> Result<(), CommError>) -> Result<(), CommError> {
         for i in 0..self.arguments.iterations {
             if i % self.arguments.log_interval == 0 {
                 println!("=== Server in iteration {} ===", i);
             }
+            iteration().map_err(|e| report_failure("Server", i, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// One-sided counterparts of the benchmarks, in which the ranks put the messages into an MPI
+/// window of the other rank instead of sending them.
+impl TestExecution<MpiCommunicator> {
+    pub fn run_rma_client(&self, sync: RmaSync) -> Result<(), CommError> {
+        check_no_recv_timeout(&self.arguments)?;
+        match self.arguments.benchmark {
+            Benchmark::PingPong => self.rma_ping_pong_client(sync),
+            Benchmark::Bandwidth => self.rma_bandwidth_client(sync),
+            Benchmark::Overlap => Err(CommError::Unsupported(
+                "the overlap benchmark with one-sided communication",
+            )),
+        }
+    }
+
+    pub fn run_rma_server(&self, sync: RmaSync) -> Result<(), CommError> {
+        check_no_recv_timeout(&self.arguments)?;
+        match self.arguments.benchmark {
+            Benchmark::PingPong => self.rma_ping_pong_server(sync),
+            Benchmark::Bandwidth => self.rma_bandwidth_server(sync),
+            Benchmark::Overlap => Err(CommError::Unsupported(
+                "the overlap benchmark with one-sided communication",
+            )),
+        }
+    }
+
+    /// Puts the message into the window of the server, which puts it back.
+    pub fn rma_ping_pong_client(&self, sync: RmaSync) -> Result<(), CommError> {
+        check_ping_pong(self.communicator.size())?;
+        let other = 1;
+
+        let message = create_message(&self.arguments);
+        let window = self.communicator.create_window(message.len())?;
+
+        self.measure(|| {
+            match sync {
+                RmaSync::Fence => {
+                    window.fence(|epoch| epoch.put(&message, other, 0))?;
+                    // the server puts it back
+                    window.fence(|_| Ok(()))?;
+                }
+                RmaSync::Pscw => {
+                    window.start(&[other], |epoch| epoch.put(&message, other, 0))?;
+                    window.post(&[other], || Ok(()))?;
+                }
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    pub fn rma_ping_pong_server(&self, sync: RmaSync) -> Result<(), CommError> {
+        check_ping_pong(self.communicator.size())?;
+        let other = 0;
+
+        let len = self.arguments.message_len as usize;
+        let mut window = self.communicator.create_window(len)?;
+        let mut echo = vec![0; len];
+
+        self.serve(|| {
+            match sync {
+                RmaSync::Fence => {
+                    window.fence(|_| Ok(()))?;
+                    echo.copy_from_slice(window.memory());
+                    window.fence(|epoch| epoch.put(&echo, other, 0))?;
+                }
+                RmaSync::Pscw => {
+                    window.post(&[other], || Ok(()))?;
+                    echo.copy_from_slice(window.memory());
+                    window.start(&[other], |epoch| epoch.put(&echo, other, 0))?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Puts `burst` messages side by side into the window of the server per epoch.
+    pub fn rma_bandwidth_client(&self, sync: RmaSync) -> Result<(), CommError> {
+        check_ping_pong(self.communicator.size())?;
+        let other = 1;
+
+        let message = create_message(&self.arguments);
+        let window = self
+            .communicator
+            .create_window(message.len() * self.arguments.burst as usize)?;
+
+        let burst = self.arguments.burst as usize;
+        let elapsed = self.measure(|| match sync {
+            RmaSync::Fence => window.fence(|epoch| put_burst(epoch, &message, other, burst)),
+            RmaSync::Pscw => {
+                window.start(&[other], |epoch| put_burst(epoch, &message, other, burst))
+            }
+        })?;
+        print_bandwidth(&self.arguments, elapsed);
+        Ok(())
+    }
+
+    pub fn rma_bandwidth_server(&self, sync: RmaSync) -> Result<(), CommError> {
+        check_ping_pong(self.communicator.size())?;
+        let other = 0;
+
+        let window = self
+            .communicator
+            .create_window(self.arguments.message_len as usize * self.arguments.burst as usize)?;
+
+        self.serve(|| match sync {
+            RmaSync::Fence => window.fence(|_| Ok(())),
+            RmaSync::Pscw => window.post(&[other], || Ok(())),
+        })
+    }
+}
+
+/// Async counterpart of [`TestExecution`], which runs the benchmarks on an
+/// [`AsyncTestCommunicator`] inside a tokio runtime.
+pub struct AsyncTestExecution<C> {
+    communicator: C,
+    arguments: BasicArguments,
+}
+
+impl<C: AsyncTestCommunicator> AsyncTestExecution<C> {
+    pub fn new(communicator: C, arguments: BasicArguments) -> Self {
+        AsyncTestExecution {
+            communicator,
+            arguments,
+        }
+    }
+
+    pub async fn run_client(&self) -> Result<(), CommError> {
+        match self.arguments.benchmark {
+            Benchmark::PingPong => self.ping_pong_client().await,
+            Benchmark::Overlap => self.overlap_client().await,
+            Benchmark::Bandwidth => self.bandwidth_client().await,
+        }
+    }
+
+    pub async fn run_server(&self) -> Result<(), CommError> {
+        match self.arguments.benchmark {
+            Benchmark::PingPong | Benchmark::Overlap => self.ping_pong_server().await,
+            Benchmark::Bandwidth => self.bandwidth_server().await,
+        }
+    }
+
+    pub async fn ping_pong_client(&self) -> Result<(), CommError> {
+        check_ping_pong(self.communicator.size())?;
+        let other = 1;
+
+        let message = &create_message(&self.arguments);
+
+        self.measure(|| async move {
+            self.communicator.send(message, other).await?;
+            let in_buffer = &mut vec![0; message.len()];
+            self.recv(in_buffer, other).await?;
+            Ok(())
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Runs the receive, the send and the busy work concurrently. The busy work goes to the
+    /// blocking pool, so that it does not hold up the communication on this task.
+    pub async fn overlap_client(&self) -> Result<(), CommError> {
+        check_ping_pong(self.communicator.size())?;
+        let other = 1;
+
+        let message = &create_message(&self.arguments);
+        let compute = Duration::from_micros(self.arguments.compute_micros);
+
+        self.measure(|| async move {
+            let in_buffer = &mut vec![0; message.len()];
+            tokio::try_join!(
+                self.recv(in_buffer, other),
+                self.communicator.send(message, other),
+                async {
+                    tokio::task::spawn_blocking(move || busy_wait(compute))
+                        .await
+                        .map_err(|e| CommError::Io(io::Error::other(e)))
+                },
+            )?;
+            Ok(())
+        })
+        .await?;
+        Ok(())
+    }
+
+    pub async fn ping_pong_server(&self) -> Result<(), CommError> {
+        check_ping_pong(self.communicator.size())?;
+        let other = 0;
+
+        self.serve(|| async move {
             let in_buffer = &mut vec![0; self.arguments.message_len as usize];
-            self.communicator.recv(in_buffer, other);
-            self.communicator.send(in_buffer, other);
+            self.recv(in_buffer, other).await?;
+            self.communicator.send(in_buffer, other).await
+        })
+        .await
+    }
+
+    pub async fn bandwidth_client(&self) -> Result<(), CommError> {
+        check_ping_pong(self.communicator.size())?;
+        let other = 1;
+
+        let message = &create_message(&self.arguments);
+
+        let elapsed = self
+            .measure(|| async move {
+                for _ in 0..self.arguments.burst {
+                    self.communicator.send(message, other).await?;
+                }
+                self.recv(&mut [0], other).await?;
+                Ok(())
+            })
+            .await?;
+        print_bandwidth(&self.arguments, elapsed);
+        Ok(())
+    }
+
+    pub async fn bandwidth_server(&self) -> Result<(), CommError> {
+        check_ping_pong(self.communicator.size())?;
+        let other = 0;
+
+        self.serve(|| async move {
+            let in_buffer = &mut vec![0; self.arguments.message_len as usize];
+            for _ in 0..self.arguments.burst {
+                self.recv(in_buffer, other).await?;
+            }
+            self.communicator.send(&[0], other).await
+        })
+        .await
+    }
+
+    /// Waits for the other ranks with the deadline of the arguments, if any.
+    pub async fn barrier(&self) -> Result<(), CommError> {
+        let barrier = self.communicator.barrier();
+        match self.arguments.recv_timeout() {
+            Some(timeout) => (tokio::time::timeout(timeout, barrier).await)
+                .map_err(|_elapsed| CommError::Timeout)?,
+            None => barrier.await,
         }
     }
 
-    pub fn barrier(&self) {
-        self.communicator.barrier();
+    /// Receives with the deadline of the arguments, if any.
+    async fn recv(&self, buffer: &mut [u8], source: u32) -> Result<usize, CommError> {
+        let receive = self.communicator.recv(buffer, source);
+        match self.arguments.recv_timeout() {
+            Some(timeout) => (tokio::time::timeout(timeout, receive).await)
+                .map_err(|_elapsed| CommError::Timeout)?,
+            None => receive.await,
+        }
     }
 
-    fn check_ping_pong(&self) {
-        if self.communicator.size() != 2 {
-            panic!("For ping pong, the communicator should have exactly 2 ranks");
+    /// Like [`TestExecution::measure`].
+    async fn measure<F>(&self, mut iteration: impl FnMut() -> F) -> Result<Duration, CommError>
+    where
+        F: Future<Output = Result<(), CommError>>,
+    {
+        let mut reporting = Vec::with_capacity(self.arguments.iterations as usize);
+
+        let start = Instant::now();
+        for i in 0..self.arguments.iterations {
+            if i % self.arguments.log_interval == 0 {
+                println!("=== Client in iteration {} ===", i);
+            }
+            let start_i = Instant::now();
+            if let Err(e) = iteration().await {
+                if let Some(ref _reporting_file) = self.arguments.reporting_file {
+                    write_reporting_csv(&self.arguments, &reporting)?;
+                }
+                return Err(report_failure("Client", i, e));
+            }
+            let elapsed_i = start_i.elapsed();
+
+            if let Some(ref _reporting_file) = self.arguments.reporting_file {
+                reporting.push(elapsed_i.as_nanos());
+            }
         }
+
+        let elapsed = start.elapsed();
+        println!("Elapsed time: {:?}", elapsed);
+
+        if let Some(ref _reporting_file) = self.arguments.reporting_file {
+            write_reporting_csv(&self.arguments, &reporting)?;
+        }
+        Ok(elapsed)
     }
 
-    //save reporting as csv with header: index, elapsed time
-    fn write_reporting_csv(&self, reporting: &mut Vec<u128>) {
-        let mut wtr =
-            csv::Writer::from_path(self.arguments.reporting_file.as_ref().unwrap()).unwrap();
-        wtr.write_record(&["index", "elapsed time"]).unwrap();
+    /// Like [`TestExecution::serve`].
+    async fn serve<F>(&self, mut iteration: impl FnMut() -> F) -> Result<(), CommError>
+    where
+        F: Future<Output = Result<(), CommError>>,
+    {
+        for i in 0..self.arguments.iterations {
+            if i % self.arguments.log_interval == 0 {
+                println!("=== Server in iteration {} ===", i);
+            }
+            iteration()
+                .await
+                .map_err(|e| report_failure("Server", i, e))?;
+        }
+        Ok(())
+    }
+}
+
+fn create_message(arguments: &BasicArguments) -> Vec<u8> {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+    (0..arguments.message_len)
+        .map(|_| rng.random::<u8>())
+        .collect()
+}
+
+fn print_bandwidth(arguments: &BasicArguments, elapsed: Duration) {
+    let bytes = arguments.iterations as f64 * arguments.burst as f64 * arguments.message_len as f64;
+    println!("Bandwidth: {:.2} MB/s", bytes / elapsed.as_secs_f64() / 1e6);
+}
+
+/// Tells which iteration a run failed in, so that its output tells a timed out run apart from
+/// a finished one.
+fn report_failure(role: &str, iteration: u32, e: CommError) -> CommError {
+    println!("=== {} failed in iteration {}: {} ===", role, iteration, e);
+    e
+}
+
+/// The synchronization of MPI windows blocks without a way to give up, so a receive timeout
+/// would silently not apply to the one-sided benchmarks.
+fn check_no_recv_timeout(arguments: &BasicArguments) -> Result<(), CommError> {
+    if arguments.recv_timeout_secs.is_some() {
+        return Err(CommError::Unsupported(
+            "a receive timeout for the one-sided benchmarks",
+        ));
+    }
+    Ok(())
+}
+
+fn check_ping_pong(size: u32) -> Result<(), CommError> {
+    if size != 2 {
+        return Err(CommError::Unsupported(
+            "ping pong on a communicator without exactly 2 ranks",
+        ));
+    }
+    Ok(())
+}
+
+/// Puts `burst` copies of `message` side by side into the window of `target`.
+fn put_burst<'b>(
+    epoch: &mut Epoch<'_, 'b>,
+    message: &'b [u8],
+    target: u32,
+    burst: usize,
+) -> Result<(), CommError> {
+    for j in 0..burst {
+        epoch.put(message, target, j * message.len())?;
+    }
+    Ok(())
+}
+
+//save reporting as csv with header: index, elapsed time
+fn write_reporting_csv(arguments: &BasicArguments, reporting: &[u128]) -> Result<(), CommError> {
+    let write = || -> Result<(), csv::Error> {
+        let mut wtr = csv::Writer::from_path(arguments.reporting_file.as_ref().unwrap())?;
+        wtr.write_record(&["index", "elapsed time"])?;
         for (i, elapsed_i) in reporting.iter().enumerate() {
-            wtr.write_record(&[i.to_string(), elapsed_i.to_string()])
-                .unwrap();
+            wtr.write_record(&[i.to_string(), elapsed_i.to_string()])?;
         }
+        wtr.flush()?;
+        Ok(())
+    };
+    write().map_err(|e| CommError::Io(e.into()))
+}
+
+/// Spins instead of sleeping, so the measured overlap is not hidden by the scheduler.
+fn busy_wait(duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        std::hint::spin_loop();
     }
 }